@@ -1,14 +1,25 @@
-use std::{collections::HashMap, fs};
+use std::{collections::HashMap, fs::{self, File, OpenOptions, TryLockError}, io::{self, ErrorKind, Write}, path::Path, time::{SystemTime, SystemTimeError, UNIX_EPOCH}};
 
+use camino::{Utf8Path, Utf8PathBuf};
 use serde::{Deserialize, Serialize};
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Map, Value};
 
-use crate::{models::registry_models::Platform, paths::get_app_state_path, utils::UnwrapOrPanicJson};
+use crate::{models::{input_models::ImageArg, registry_models::Platform}, paths::{get_app_state_path, get_layers_compressed_path, get_state_lock_path}, utils::layer_media_type};
 
-#[derive(Deserialize, Serialize, Debug)]
+/// A layer archive stored in the compressed layers folder
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Layer {
     pub digest: String,
-    pub size: u64
+    /// Size of the archive as stored
+    pub size: u64,
+    pub media_type: String,
+    /// Unix time the layer was stored
+    pub downloaded_at: u64,
+    /// Unix time an image made up of the layer was last built, exported or imported
+    pub last_used_at: u64,
+    /// Digests of the images made up of the layer
+    pub images: Vec<String>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -17,81 +28,552 @@ pub struct Image {
     pub name: String,
     pub tag: String,
     pub layers: Vec<String>,
+    /// Size of the drive image, zero if it hasn't been built
     pub size: u64,
+    /// Digest of the manifest stored in the blobs folder
+    #[serde(default)]
+    pub manifest_digest: Option<String>,
+    /// Digest of what the reference resolved to in the registry, the index for
+    /// multi-platform images and otherwise the manifest itself
+    #[serde(default)]
+    pub index_digest: Option<String>,
+    /// Unix time the image was last built, exported or imported
+    pub last_used_at: u64,
 }
 
 /// The json file that can be read from and written to
 /// that stores the current images and layers
 #[derive(Deserialize, Serialize, Debug)]
 pub struct ApplicationState {
+    /// Version of the layout of this file, see `STATE_MIGRATIONS`
+    pub schema_version: u32,
     /// Mapping of image:tag-os:arch to digest
     pub tagged_images: HashMap<String, String>,
     /// All the image files that are stored locally
     pub images: HashMap<String, Image>,
-    /// All the layers stored locally
-    pub layers: Vec<String>
+    /// All the layers stored locally, keyed by digest
+    pub layers: HashMap<String, Layer>
+}
+
+impl Default for ApplicationState {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ApplicationState {
     pub fn new() -> ApplicationState {
         ApplicationState {
+            schema_version: STATE_SCHEMA_VERSION,
             tagged_images: HashMap::new(),
             images: HashMap::new(),
-            layers: Vec::new()
+            layers: HashMap::new()
         }
     }
 
-    /// Gets the digest for the stored image with the provided
-    /// name, tag and platform
-    pub fn get_stored_image_digest(&self, name: &str, tag: &str, platform: &Platform) -> Option<String> {
-        let key = format!("{name}:{tag}-{}:{}", platform.os, platform.architecture);
-        self.tagged_images.get(&key).and_then(|m|{Some(m.clone())})
+    /// The key of an image in the tagged images, image:tag-os:arch or image@digest-os:arch
+    pub fn tag_key(image: &ImageArg, platform: &Platform) -> String {
+        format!("{}-{}:{}", image.tagged_reference(), platform.os, platform.architecture)
     }
 
     /// Gets the digest for the stored image with the provided
     /// name, tag and platform
-    pub fn set_stored_image_digest(&mut self, name: &str, tag: &str, platform: &Platform, digest: String) {
-        let key = format!("{name}:{tag}-{}:{}", platform.os, platform.architecture);
-        self.tagged_images.insert(key, digest);
+    pub fn get_stored_image_digest(&self, image: &ImageArg, platform: &Platform) -> Option<String> {
+        self.tagged_images.get(&Self::tag_key(image, platform)).cloned()
+    }
+
+    /// Points the name and tag for the platform at a stored image
+    pub fn set_stored_image_digest(&mut self, image: &ImageArg, platform: &Platform, digest: String) {
+        self.tagged_images.insert(Self::tag_key(image, platform), digest);
+    }
+
+    /// Adds an image if it isn't stored yet, points the tag at it and marks its layers as used.
+    /// A size of zero means the drive image hasn't been built
+    #[allow(clippy::too_many_arguments)]
+    pub fn record_image(&mut self, image: &ImageArg, platform: &Platform, digest: &str, manifest_digest: Option<String>, index_digest: Option<String>, layers: Vec<String>, size: u64) -> Result<()> {
+        self.record_layers(digest, &layers)?;
+        let now = unix_time(SystemTime::now())?;
+        let stored = self.images.entry(digest.to_string()).or_insert_with(|| Image {
+            name: image.name.clone(),
+            tag: image.tag.clone(),
+            platform: platform.clone(),
+            size,
+            layers,
+            manifest_digest: None,
+            index_digest: None,
+            last_used_at: now,
+        });
+        stored.last_used_at = now;
+        if size != 0 {
+            stored.size = size;
+        }
+        if manifest_digest.is_some() {
+            stored.manifest_digest = manifest_digest;
+        }
+        if index_digest.is_some() {
+            stored.index_digest = index_digest;
+        }
+        self.set_stored_image_digest(image, platform, digest.to_string());
+        Ok(())
+    }
+
+    /// Tracks the layers of an image that are in the compressed layers folder
+    fn record_layers(&mut self, image_digest: &str, layers: &[String]) -> Result<()> {
+        let layers_path = get_layers_compressed_path()?;
+        let now = unix_time(SystemTime::now())?;
+        for digest in layers {
+            let layer = match self.layers.get_mut(digest) {
+                Some(layer) => layer,
+                None => {
+                    let Some((size, media_type, _)) = stored_layer(layers_path.join(format!("{digest}.tgz")).as_std_path())? else {
+                        continue;
+                    };
+                    self.layers.entry(digest.clone()).or_insert(Layer {
+                        digest: digest.clone(),
+                        size,
+                        media_type,
+                        downloaded_at: now,
+                        last_used_at: now,
+                        images: Vec::new(),
+                    })
+                },
+            };
+            layer.last_used_at = now;
+            if !layer.images.iter().any(|image| image == image_digest) {
+                layer.images.push(image_digest.to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes an image, dropping it from the layers it was made up of
+    pub fn remove_image(&mut self, digest: &str) -> Option<Image> {
+        let image = self.images.remove(digest)?;
+        for layer in &image.layers {
+            if let Some(layer) = self.layers.get_mut(layer) {
+                layer.images.retain(|image| image != digest);
+            }
+        }
+        Some(image)
+    }
+
+    /// Digests of the layers no image is made up of
+    pub fn unreferenced_layers(&self) -> Vec<String> {
+        self.layers.values().filter(|layer| layer.images.is_empty()).map(|layer| layer.digest.clone()).collect()
+    }
+
+    /// Every name:tag that points at an image, for any platform
+    pub fn get_image_tags(&self, digest: &str) -> Vec<String> {
+        let mut tags = self.tagged_images.iter()
+            .filter(|(_, tagged)| *tagged == digest)
+            // Keys end with -os:arch, which has no dashes of its own
+            .filter_map(|(key, _)| key.rsplit_once('-').map(|(tag, _)| tag.to_string()))
+            .collect::<Vec<String>>();
+        tags.sort();
+        tags.dedup();
+        tags
     }
 
     /// Gets the digest for the stored image with the provided
     /// name, tag and platform
-    pub fn get_stored_image(&self, name: &str, tag: &str, platform: &Platform) -> Option<Image> {
-        let digest = self.get_stored_image_digest(name, tag, platform)?;
-        self.images.get(&digest).map(|i|{i.clone()})
+    pub fn get_stored_image(&self, image: &ImageArg, platform: &Platform) -> Option<Image> {
+        let digest = self.get_stored_image_digest(image, platform)?;
+        self.images.get(&digest).cloned()
     }
 }
 
+/// Holds the state together with a lock on the base path, so concurrent runs wait for each
+/// other instead of overwriting each other's changes. Commands that change the state hold the
+/// lock exclusively while the ones that only read it share it with each other.
+/// Changes are only saved by `commit`, the lock is released when the handle is dropped
 pub struct StateHandle {
-    pub state: ApplicationState
+    pub state: ApplicationState,
+    read_only: bool,
+    _lock: File
 }
 
-/// We use a state handle to automatically read and write the state file
 impl StateHandle {
+    /// Loads the state for a command that changes it
     pub fn new() -> Result<StateHandle> {
+        Self::load(false)
+    }
+
+    /// Loads the state for a command that only reads it, which doesn't wait for other readers
+    /// and can't be committed. A corrupt state file is left for the next command that changes the state
+    pub fn read_only() -> Result<StateHandle> {
+        Self::load(true)
+    }
+
+    fn load(read_only: bool) -> Result<StateHandle> {
+        let lock = lock_base_path(read_only)?;
+        let state_path = get_app_state_path()?;
+        let state = match fs::read_to_string(&state_path) {
+            Ok(contents) => match serde_json::from_str::<Value>(&contents) {
+                Ok(value) => {
+                    let version = stored_schema_version(&value);
+                    if version > STATE_SCHEMA_VERSION as u64 {
+                        bail!(
+                            "{} has schema version {} but this version of whaledrive only understands up to {}, upgrade whaledrive to use it",
+                            state_path, version, STATE_SCHEMA_VERSION
+                        );
+                    }
+                    match migrate_state(value, &get_layers_compressed_path()?) {
+                        Ok(state) => state,
+                        Err(e) if is_corruption(&e) => recover_corrupt_state(&state_path, e, read_only)?,
+                        Err(e) => return Err(e).context(format!("Failed to load {}", state_path)),
+                    }
+                },
+                Err(e) => recover_corrupt_state(&state_path, e.into(), read_only)?,
+            },
+            Err(e) if e.kind() == ErrorKind::NotFound => ApplicationState::new(),
+            Err(e) => return Err(e).context(format!("Failed to read {}", state_path)),
+        };
+        Ok(StateHandle { state, read_only, _lock: lock })
+    }
+
+    /// Saves the state by writing it next to the state file and renaming it over it,
+    /// so a crash leaves either the old or the new state behind
+    pub fn commit(&self) -> Result<()> {
+        if self.read_only {
+            bail!("The state was loaded read only and can't be saved");
+        }
         let state_path = get_app_state_path()?;
-        if state_path.exists() {
-            let state = serde_json::from_str(
-                &std::fs::read_to_string(&state_path)?
-            )?;
-            Ok(StateHandle {state})
-        } else {
-            Ok(StateHandle { state: ApplicationState::new() })
+        let temp_path = Utf8PathBuf::from(format!("{state_path}.tmp"));
+        let json = serde_json::to_string_pretty(&self.state)?;
+        let mut file = File::create(&temp_path)?;
+        file.write_all(json.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temp_path, &state_path)?;
+        if let Some(parent) = state_path.parent() {
+            File::open(parent)?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+/// Takes the lock on the base path, waiting for any other run that holds it in a way that
+/// conflicts. A shared lock only waits for a run that holds it exclusively
+fn lock_base_path(shared: bool) -> Result<File> {
+    let lock_path = get_state_lock_path()?;
+    let lock = OpenOptions::new().create(true).truncate(false).write(true).open(&lock_path)
+        .context(format!("Failed to open {}", lock_path))?;
+    let attempt = if shared { lock.try_lock_shared() } else { lock.try_lock() };
+    match attempt {
+        Ok(()) => {},
+        Err(TryLockError::WouldBlock) => {
+            eprintln!("waiting for another whaledrive process to release {}", lock_path);
+            if shared { lock.lock_shared()? } else { lock.lock()? }
+        },
+        Err(TryLockError::Error(e)) => return Err(e).context(format!("Failed to lock {}", lock_path)),
+    }
+    Ok(lock)
+}
+
+/// Whether loading the state failed on its contents rather than on reading the layers it
+/// refers to, which is worth retrying instead of throwing the state away
+fn is_corruption(error: &anyhow::Error) -> bool {
+    !error.chain().any(|cause| cause.is::<io::Error>() || cause.is::<SystemTimeError>())
+}
+
+/// Moves a state file that can't be parsed aside so it can be inspected, and starts over.
+/// The backup is named after the time, with a counter when there's one from the same second
+/// already, so an earlier one isn't overwritten. A reader only starts over in memory.
+/// The layers and images on disk are kept, they are only no longer tracked
+fn recover_corrupt_state(state_path: &Utf8Path, error: anyhow::Error, read_only: bool) -> Result<ApplicationState> {
+    if read_only {
+        eprintln!("{} is corrupt ({}), it's treated as empty until a command that changes the state moves it aside", state_path, error);
+        return Ok(ApplicationState::new());
+    }
+    let time = unix_time(SystemTime::now())?;
+    let backup_path = (0..)
+        .map(|count| match count {
+            0 => format!("{state_path}.corrupt-{time}"),
+            _ => format!("{state_path}.corrupt-{time}.{count}"),
+        })
+        .find(|path| fs::symlink_metadata(path).is_err())
+        .context("No free name for the corrupt state file")?;
+    fs::rename(state_path, &backup_path)?;
+    eprintln!("{} is corrupt ({}), it was moved to {} and a new state was started", state_path, error, backup_path);
+    Ok(ApplicationState::new())
+}
+
+/// A migration gets the state json and the compressed layers folder, for when it has to look at the layers
+type Migration = fn(&mut Map<String, Value>, &Utf8Path) -> Result<()>;
+
+/// Each migration upgrades a state file from the version of its index to the next one.
+/// They work on the json so old layouts don't need types of their own. Any change to the
+/// stored data appends a migration here instead of breaking existing files
+const STATE_MIGRATIONS: &[Migration] = &[
+    migrate_v0_to_v1,
+    migrate_v1_to_v2,
+    migrate_v2_to_v3,
+    migrate_v3_to_v4,
+];
+
+/// The version of the state files this build writes
+pub const STATE_SCHEMA_VERSION: u32 = STATE_MIGRATIONS.len() as u32;
+
+/// Files written before the schema was versioned have no version, which is version 0
+fn stored_schema_version(value: &Value) -> u64 {
+    value.get("schema_version").and_then(Value::as_u64).unwrap_or(0)
+}
+
+/// Upgrades a state file of any older version to the current one, with the layers it tracks in layers_path
+pub fn migrate_state(mut value: Value, layers_path: &Utf8Path) -> Result<ApplicationState> {
+    let version = stored_schema_version(&value);
+    if version > STATE_SCHEMA_VERSION as u64 {
+        bail!("Schema version {} is newer than {}", version, STATE_SCHEMA_VERSION);
+    }
+    let state = value.as_object_mut().context("The state isn't a json object")?;
+    for (from, migration) in STATE_MIGRATIONS.iter().enumerate().skip(version as usize) {
+        migration(state, layers_path).context(format!("Failed to migrate the state from schema version {}", from))?;
+        state.insert("schema_version".to_string(), Value::from(from + 1));
+    }
+    Ok(serde_json::from_value(value)?)
+}
+
+/// Version 0 images could predate the stored manifest digest and early files had no layers list
+fn migrate_v0_to_v1(state: &mut Map<String, Value>, _layers_path: &Utf8Path) -> Result<()> {
+    state.entry("layers").or_insert_with(|| Value::Array(Vec::new()));
+    state.entry("tagged_images").or_insert_with(|| Value::Object(Map::new()));
+    let images = state.entry("images").or_insert_with(|| Value::Object(Map::new()))
+        .as_object_mut().context("images isn't a json object")?;
+    for image in images.values_mut() {
+        image.as_object_mut().context("An image isn't a json object")?
+            .entry("manifest_digest").or_insert(Value::Null);
+    }
+    Ok(())
+}
+
+/// Version 1 only listed layer digests, and never any in practice. The layers of the
+/// stored images that are on disk are tracked from their files instead
+fn migrate_v1_to_v2(state: &mut Map<String, Value>, layers_path: &Utf8Path) -> Result<()> {
+    let mut layers = Map::new();
+    let images = state.get("images").and_then(Value::as_object).context("images isn't a json object")?;
+    for (image_digest, image) in images {
+        for digest in image.get("layers").and_then(Value::as_array).context("An image has no layers list")? {
+            let digest = digest.as_str().context("A layer digest isn't a string")?;
+            if !layers.contains_key(digest) {
+                let Some((size, media_type, modified)) = stored_layer(layers_path.join(format!("{digest}.tgz")).as_std_path())? else {
+                    continue;
+                };
+                layers.insert(digest.to_string(), json!({
+                    "digest": digest,
+                    "size": size,
+                    "media_type": media_type,
+                    "downloaded_at": modified,
+                    "last_used_at": modified,
+                    "images": [],
+                }));
+            }
+            if let Some(Value::Array(referencing)) = layers.get_mut(digest).and_then(|layer| layer.get_mut("images")) {
+                referencing.push(Value::from(image_digest.as_str()));
+            }
+        }
+    }
+    state.insert("layers".to_string(), Value::Object(layers));
+    Ok(())
+}
+
+/// Version 3 images have the time they were last used. Older ones are taken to have been
+/// used when their most recently used layer was
+fn migrate_v2_to_v3(state: &mut Map<String, Value>, _layers_path: &Utf8Path) -> Result<()> {
+    let layers = state.get("layers").and_then(Value::as_object).context("layers isn't a json object")?;
+    let mut last_used = HashMap::new();
+    for layer in layers.values() {
+        for image in layer.get("images").and_then(Value::as_array).into_iter().flatten().filter_map(Value::as_str) {
+            let time = layer.get("last_used_at").and_then(Value::as_u64).unwrap_or(0);
+            let entry = last_used.entry(image.to_string()).or_insert(0);
+            *entry = time.max(*entry);
         }
     }
+    let images = state.get_mut("images").and_then(Value::as_object_mut).context("images isn't a json object")?;
+    for (digest, image) in images.iter_mut() {
+        image.as_object_mut().context("An image isn't a json object")?
+            .insert("last_used_at".to_string(), Value::from(last_used.get(digest).copied().unwrap_or(0)));
+    }
+    Ok(())
+}
+
+/// Version 4 images have the digest their reference resolved to, which older versions didn't keep
+fn migrate_v3_to_v4(state: &mut Map<String, Value>, _layers_path: &Utf8Path) -> Result<()> {
+    let images = state.get_mut("images").and_then(Value::as_object_mut).context("images isn't a json object")?;
+    for image in images.values_mut() {
+        image.as_object_mut().context("An image isn't a json object")?
+            .entry("index_digest").or_insert(Value::Null);
+    }
+    Ok(())
 }
 
-impl Drop for StateHandle { 
-    fn drop(&mut self) {
-        let res = (|| -> Result<()> {
-            let json = serde_json::to_string_pretty(&self.state)?;
-            fs::write(
-               get_app_state_path()?,
-                json.as_bytes()
-            )?;
-            Ok(())
-        })();
-        res.unwrap_or_panic_json();
+/// The size, media type and modification time of a layer archive, None if it isn't stored
+fn stored_layer(path: &Path) -> Result<Option<(u64, String, u64)>> {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(Some((metadata.len(), layer_media_type(path)?.to_string(), unix_time(metadata.modified()?)?)))
+}
+
+fn unix_time(time: SystemTime) -> Result<u64> {
+    Ok(time.duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::models::registry_models::{OCI_LAYER_GZIP_MEDIA_TYPE, OCI_LAYER_MEDIA_TYPE};
+
+    use super::*;
+
+    #[test]
+    fn only_unreadable_contents_are_corruption() {
+        let parse_error = serde_json::from_str::<Value>("{").unwrap_err();
+        assert!(is_corruption(&parse_error.into()));
+        assert!(is_corruption(&anyhow::anyhow!("images isn't a json object")));
+        let read_error = anyhow::Error::from(io::Error::from(ErrorKind::PermissionDenied)).context("Failed to read a layer");
+        assert!(!is_corruption(&read_error));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn corrupt_state_backups_are_not_overwritten() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_path = Utf8PathBuf::from_path_buf(dir.path().join("state.json")).unwrap();
+        // Both are likely to happen within the same second
+        for contents in ["first", "second"] {
+            fs::write(&state_path, contents).unwrap();
+            recover_corrupt_state(&state_path, anyhow::anyhow!("corrupt"), false).unwrap();
+            assert!(!state_path.exists());
+        }
+        let mut backups = fs::read_dir(dir.path()).unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<String>>();
+        backups.sort();
+        assert_eq!(backups.len(), 2);
+        assert!(backups.iter().all(|name| name.starts_with("state.json.corrupt-")));
+        let contents = backups.iter().map(|name| fs::read_to_string(dir.path().join(name)).unwrap()).collect::<Vec<String>>();
+        assert_eq!(contents, ["first", "second"]);
+    }
+
+    #[test]
+    fn readers_leave_a_corrupt_state_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_path = Utf8PathBuf::from_path_buf(dir.path().join("state.json")).unwrap();
+        fs::write(&state_path, "{").unwrap();
+        let state = recover_corrupt_state(&state_path, anyhow::anyhow!("corrupt"), true).unwrap();
+        assert!(state.images.is_empty());
+        assert_eq!(fs::read_to_string(&state_path).unwrap(), "{");
+    }
+
+    const IMAGE: &str = "sha256:image";
+    const OTHER_IMAGE: &str = "sha256:other";
+
+    fn image_json() -> Value {
+        json!({
+            "platform": { "os": "linux", "architecture": "amd64" },
+            "name": "alpine",
+            "tag": "latest",
+            "layers": ["sha256:stored", "sha256:missing"],
+            "size": 1024,
+        })
+    }
+
+    /// A layers folder holding a gzipped archive of the first layer of the image
+    fn layers_folder() -> (tempfile::TempDir, Utf8PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = Utf8PathBuf::from_path_buf(dir.path().to_path_buf()).unwrap();
+        fs::write(path.join("sha256:stored.tgz"), [0x1f, 0x8b, 0, 0]).unwrap();
+        (dir, path)
+    }
+
+    fn modified_time(path: &Utf8Path) -> u64 {
+        unix_time(fs::metadata(path).unwrap().modified().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn migrates_unversioned_state() {
+        let (_dir, layers_path) = layers_folder();
+        // Early files had no version, no layers and no manifest digests
+        let state = migrate_state(json!({
+            "tagged_images": { "alpine:latest-linux:amd64": IMAGE },
+            "images": { IMAGE: image_json() },
+        }), &layers_path).unwrap();
+        assert_eq!(state.schema_version, STATE_SCHEMA_VERSION);
+        assert_eq!(state.tagged_images["alpine:latest-linux:amd64"], IMAGE);
+        let image = &state.images[IMAGE];
+        assert_eq!((image.manifest_digest.as_deref(), image.index_digest.as_deref()), (None, None));
+        assert_eq!(state.layers.keys().collect::<Vec<_>>(), ["sha256:stored"]);
+    }
+
+    #[test]
+    fn migrates_v1_layers_from_the_layers_folder() {
+        let (_dir, layers_path) = layers_folder();
+        let state = migrate_state(json!({
+            "schema_version": 1,
+            "tagged_images": {},
+            "images": { IMAGE: image_json() },
+            "layers": [],
+        }), &layers_path).unwrap();
+        // Layers that aren't on disk aren't tracked
+        assert_eq!(state.layers.len(), 1);
+        let layer = &state.layers["sha256:stored"];
+        let modified = modified_time(&layers_path.join("sha256:stored.tgz"));
+        assert_eq!((layer.size, layer.media_type.as_str()), (4, OCI_LAYER_GZIP_MEDIA_TYPE));
+        assert_eq!((layer.downloaded_at, layer.last_used_at), (modified, modified));
+        assert_eq!(layer.images, [IMAGE]);
+        assert_eq!(state.images[IMAGE].last_used_at, modified);
+    }
+
+    #[test]
+    fn migrates_v2_image_times_from_their_layers() {
+        let (_dir, layers_path) = layers_folder();
+        let layer = |digest: &str, last_used_at: u64, images: &[&str]| json!({
+            "digest": digest, "size": 1, "media_type": OCI_LAYER_MEDIA_TYPE,
+            "downloaded_at": 1, "last_used_at": last_used_at, "images": images,
+        });
+        let state = migrate_state(json!({
+            "schema_version": 2,
+            "tagged_images": {},
+            "images": { IMAGE: image_json(), OTHER_IMAGE: image_json() },
+            "layers": {
+                "sha256:stored": layer("sha256:stored", 100, &[IMAGE]),
+                "sha256:missing": layer("sha256:missing", 200, &[IMAGE]),
+            },
+        }), &layers_path).unwrap();
+        // An image is taken to be used when its most recently used layer was
+        assert_eq!(state.images[IMAGE].last_used_at, 200);
+        assert_eq!(state.images[OTHER_IMAGE].last_used_at, 0);
+        assert_eq!(state.layers["sha256:stored"].last_used_at, 100);
+    }
+
+    #[test]
+    fn migrates_v3_images_without_an_index_digest() {
+        let (_dir, layers_path) = layers_folder();
+        let mut image = image_json();
+        image["last_used_at"] = json!(300);
+        image["manifest_digest"] = json!("sha256:manifest");
+        let state = migrate_state(json!({
+            "schema_version": 3,
+            "tagged_images": {},
+            "images": { IMAGE: image },
+            "layers": {},
+        }), &layers_path).unwrap();
+        let image = &state.images[IMAGE];
+        assert_eq!(image.index_digest, None);
+        assert_eq!(image.manifest_digest.as_deref(), Some("sha256:manifest"));
+        assert_eq!(image.last_used_at, 300);
+        assert_eq!(state.schema_version, STATE_SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn refuses_state_from_a_newer_version() {
+        let (_dir, layers_path) = layers_folder();
+        let value = json!({
+            "schema_version": STATE_SCHEMA_VERSION + 1,
+            "tagged_images": {},
+            "images": {},
+            "layers": {},
+        });
+        let error = migrate_state(value, &layers_path).unwrap_err();
+        assert!(error.to_string().contains("newer"), "{error}");
+    }
+}
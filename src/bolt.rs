@@ -0,0 +1,380 @@
+use std::{fs, path::Path};
+
+use anyhow::{bail, Context, Result};
+
+const MAGIC: u32 = 0xED0CDAED;
+const VERSION: u32 = 2;
+/// The meta fields before the checksum, which the checksum covers
+const META_CHECKSUMMED_SIZE: usize = 56;
+/// Page sizes to look for the second meta page at when the first one is torn and can't tell
+const COMMON_PAGE_SIZES: [usize; 4] = [4096, 8192, 16384, 65536];
+const PAGE_HEADER_SIZE: usize = 16;
+const ELEMENT_SIZE: usize = 16;
+const BUCKET_HEADER_SIZE: usize = 16;
+const BRANCH_PAGE_FLAG: u16 = 0x01;
+const LEAF_PAGE_FLAG: u16 = 0x02;
+pub(crate) const BUCKET_LEAF_FLAG: u32 = 0x01;
+
+/// A read only view of a bbolt database, which is what containerd
+/// keeps its metadata in. Only looking up keys is supported
+pub struct BoltDb {
+    data: Vec<u8>,
+    page_size: usize,
+    root: u64,
+}
+
+/// A bucket inside the database, either stored in its own pages
+/// or inline in the value of its parent
+pub struct Bucket<'a> {
+    db: &'a BoltDb,
+    root: u64,
+    inline_page: Option<&'a [u8]>,
+}
+
+impl BoltDb {
+    /// Reads the whole database file. The newest valid meta page is used so a reader racing
+    /// with a writer still gets a consistent tree, and a torn meta page falls back to the other
+    pub fn open(path: &Path) -> Result<BoltDb> {
+        let data = fs::read(path).context(format!("Failed to read {}", path.display()))?;
+        let first = read_meta(&data, 0);
+        let page_sizes = match &first {
+            Some(meta) => vec![meta.page_size],
+            None => COMMON_PAGE_SIZES.to_vec(),
+        };
+        let second = page_sizes.into_iter().find_map(|page_size| read_meta(&data, page_size).filter(|meta| meta.page_size == page_size));
+        let meta = [first, second].into_iter().flatten().max_by_key(|meta| meta.txid)
+            .context(format!("{} is not a bolt database or both of its meta pages are damaged", path.display()))?;
+        Ok(BoltDb { data, page_size: meta.page_size, root: meta.root })
+    }
+
+    pub fn root_bucket(&self) -> Bucket<'_> {
+        Bucket { db: self, root: self.root, inline_page: None }
+    }
+
+    fn page(&self, id: u64) -> Result<&[u8]> {
+        let offset = id as usize * self.page_size;
+        if offset + PAGE_HEADER_SIZE > self.data.len() {
+            bail!("Bolt page {} is out of bounds", id);
+        }
+        Ok(&self.data[offset..])
+    }
+}
+
+impl<'a> Bucket<'a> {
+    /// Gets a nested bucket by name
+    pub fn bucket(&self, name: &[u8]) -> Result<Option<Bucket<'a>>> {
+        let Some((flags, value)) = self.find(name)? else {
+            return Ok(None);
+        };
+        if flags & BUCKET_LEAF_FLAG == 0 {
+            bail!("{} is a value, not a bucket", String::from_utf8_lossy(name));
+        }
+        let root = read_u64(value, 0)?;
+        Ok(Some(Bucket {
+            db: self.db,
+            root,
+            inline_page: if root == 0 { Some(&value[BUCKET_HEADER_SIZE..]) } else { None },
+        }))
+    }
+
+    /// Follows a path of nested buckets
+    pub fn bucket_path(&self, names: &[&[u8]]) -> Result<Option<Bucket<'a>>> {
+        let mut bucket = Bucket { db: self.db, root: self.root, inline_page: self.inline_page };
+        for name in names {
+            match bucket.bucket(name)? {
+                Some(next) => bucket = next,
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(bucket))
+    }
+
+    /// Gets a value by key
+    pub fn get(&self, key: &[u8]) -> Result<Option<&'a [u8]>> {
+        Ok(match self.find(key)? {
+            Some((flags, value)) if flags & BUCKET_LEAF_FLAG == 0 => Some(value),
+            _ => None,
+        })
+    }
+
+    fn find(&self, key: &[u8]) -> Result<Option<(u32, &'a [u8])>> {
+        let mut page = match self.inline_page {
+            Some(page) => page,
+            None => self.db.page(self.root)?,
+        };
+        loop {
+            let flags = read_u16(page, 8)?;
+            let count = read_u16(page, 10)? as usize;
+            if flags & BRANCH_PAGE_FLAG != 0 {
+                // Keys in a branch are the first key of each child, so the
+                // child to follow is the last one not greater than the key
+                let mut child = None;
+                for index in 0..count {
+                    let element = PAGE_HEADER_SIZE + index * ELEMENT_SIZE;
+                    let position = read_u32(page, element)? as usize;
+                    let key_size = read_u32(page, element + 4)? as usize;
+                    let element_key = slice(page, element + position, key_size)?;
+                    if element_key > key && child.is_some() {
+                        break;
+                    }
+                    child = Some(read_u64(page, element + 8)?);
+                }
+                match child {
+                    Some(child) => page = self.db.page(child)?,
+                    None => return Ok(None),
+                }
+            } else if flags & LEAF_PAGE_FLAG != 0 {
+                for index in 0..count {
+                    let element = PAGE_HEADER_SIZE + index * ELEMENT_SIZE;
+                    let element_flags = read_u32(page, element)?;
+                    let position = read_u32(page, element + 4)? as usize;
+                    let key_size = read_u32(page, element + 8)? as usize;
+                    let value_size = read_u32(page, element + 12)? as usize;
+                    if slice(page, element + position, key_size)? == key {
+                        return Ok(Some((element_flags, slice(page, element + position + key_size, value_size)?)));
+                    }
+                }
+                return Ok(None);
+            } else {
+                bail!("Unexpected bolt page type {:#x}", flags);
+            }
+        }
+    }
+}
+
+/// The parts of a meta page needed to read the tree
+struct Meta {
+    page_size: usize,
+    root: u64,
+    txid: u64,
+}
+
+/// Reads the meta page at an offset, None unless it has the magic, version and a matching checksum
+fn read_meta(data: &[u8], offset: usize) -> Option<Meta> {
+    let meta = data.get(offset + PAGE_HEADER_SIZE..)?;
+    if read_u32(meta, 0).ok()? != MAGIC || read_u32(meta, 4).ok()? != VERSION {
+        return None;
+    }
+    if fnv64a(slice(meta, 0, META_CHECKSUMMED_SIZE).ok()?) != read_u64(meta, META_CHECKSUMMED_SIZE).ok()? {
+        return None;
+    }
+    Some(Meta {
+        page_size: read_u32(meta, 8).ok()? as usize,
+        root: read_u64(meta, 16).ok()?,
+        txid: read_u64(meta, 48).ok()?,
+    })
+}
+
+/// The 64 bit FNV-1a hash bolt checksums its meta pages with
+fn fnv64a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+}
+
+fn slice(data: &[u8], offset: usize, len: usize) -> Result<&[u8]> {
+    data.get(offset..offset + len).context("Bolt database is truncated")
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16> {
+    Ok(u16::from_le_bytes(slice(data, offset, 2)?.try_into()?))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32> {
+    Ok(u32::from_le_bytes(slice(data, offset, 4)?.try_into()?))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<u64> {
+    Ok(u64::from_le_bytes(slice(data, offset, 8)?.try_into()?))
+}
+
+/// Builders for bolt pages, which the containerd store tests use too
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::path::PathBuf;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    const PAGE_SIZE: usize = 4096;
+    const META_PAGE_FLAG: u16 = 0x04;
+    pub(crate) const FREELIST_PAGE_FLAG: u16 = 0x10;
+
+    pub(crate) fn page_header(id: u64, flags: u16, count: usize) -> Vec<u8> {
+        let mut page = Vec::new();
+        page.extend_from_slice(&id.to_le_bytes());
+        page.extend_from_slice(&flags.to_le_bytes());
+        page.extend_from_slice(&(count as u16).to_le_bytes());
+        page.extend_from_slice(&0u32.to_le_bytes());
+        page
+    }
+
+    pub(crate) fn meta_page(id: u64, root: u64, txid: u64) -> Vec<u8> {
+        let mut meta = Vec::new();
+        meta.extend_from_slice(&MAGIC.to_le_bytes());
+        meta.extend_from_slice(&VERSION.to_le_bytes());
+        meta.extend_from_slice(&(PAGE_SIZE as u32).to_le_bytes());
+        meta.extend_from_slice(&0u32.to_le_bytes());
+        meta.extend_from_slice(&root.to_le_bytes());
+        meta.extend_from_slice(&0u64.to_le_bytes());
+        meta.extend_from_slice(&2u64.to_le_bytes());
+        meta.extend_from_slice(&9u64.to_le_bytes());
+        meta.extend_from_slice(&txid.to_le_bytes());
+        let checksum = fnv64a(&meta);
+        meta.extend_from_slice(&checksum.to_le_bytes());
+        let mut page = page_header(id, META_PAGE_FLAG, 0);
+        page.extend_from_slice(&meta);
+        page
+    }
+
+    /// A leaf page with its elements, each a flag, a key and a value, in key order
+    pub(crate) fn leaf_page(id: u64, elements: &[(u32, &[u8], Vec<u8>)]) -> Vec<u8> {
+        let mut page = page_header(id, LEAF_PAGE_FLAG, elements.len());
+        let mut data = Vec::new();
+        for (index, (flags, key, value)) in elements.iter().enumerate() {
+            let position = (elements.len() - index) * ELEMENT_SIZE + data.len();
+            page.extend_from_slice(&flags.to_le_bytes());
+            page.extend_from_slice(&(position as u32).to_le_bytes());
+            page.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            page.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            data.extend_from_slice(key);
+            data.extend_from_slice(value);
+        }
+        page.extend_from_slice(&data);
+        page
+    }
+
+    /// A branch page pointing at each child page, keyed by the first key in it
+    fn branch_page(id: u64, children: &[(&[u8], u64)]) -> Vec<u8> {
+        let mut page = page_header(id, BRANCH_PAGE_FLAG, children.len());
+        let mut data = Vec::new();
+        for (index, (key, child)) in children.iter().enumerate() {
+            let position = (children.len() - index) * ELEMENT_SIZE + data.len();
+            page.extend_from_slice(&(position as u32).to_le_bytes());
+            page.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            page.extend_from_slice(&child.to_le_bytes());
+            data.extend_from_slice(key);
+        }
+        page.extend_from_slice(&data);
+        page
+    }
+
+    /// The value of a bucket stored in its own pages
+    pub(crate) fn bucket_value(root: u64) -> Vec<u8> {
+        let mut value = root.to_le_bytes().to_vec();
+        value.extend_from_slice(&0u64.to_le_bytes());
+        value
+    }
+
+    /// The value of a small bucket kept inline, a header with no root followed by its leaf page
+    pub(crate) fn inline_bucket_value(elements: &[(u32, &[u8], Vec<u8>)]) -> Vec<u8> {
+        let mut value = bucket_value(0);
+        value.extend_from_slice(&leaf_page(0, elements));
+        value
+    }
+
+    /// Two meta pages where the second is newer and has its own root, a bucket whose keys
+    /// are spread over a branch page and two leaves, and an inline bucket in one of them
+    fn fixture() -> Vec<Vec<u8>> {
+        vec![
+            meta_page(0, 3, 10),
+            meta_page(1, 7, 11),
+            page_header(2, FREELIST_PAGE_FLAG, 0),
+            leaf_page(3, &[(0, b"generation", b"old".to_vec())]),
+            branch_page(4, &[(b"a", 5), (b"m", 6)]),
+            leaf_page(5, &[(0, b"a", b"1".to_vec()), (0, b"b", b"2".to_vec())]),
+            leaf_page(6, &[
+                (0, b"m", b"3".to_vec()),
+                (BUCKET_LEAF_FLAG, b"nested", inline_bucket_value(&[(0, b"x", b"y".to_vec())])),
+            ]),
+            leaf_page(7, &[
+                (0, b"generation", b"new".to_vec()),
+                (BUCKET_LEAF_FLAG, b"v1", bucket_value(4)),
+            ]),
+        ]
+    }
+
+    /// The database file, with each page padded to the page size
+    pub(crate) fn database(pages: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for page in pages {
+            let mut page = page.clone();
+            page.resize(PAGE_SIZE, 0);
+            data.extend_from_slice(&page);
+        }
+        data
+    }
+
+    fn write_db(pages: &[Vec<u8>]) -> (TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("meta.db");
+        fs::write(&path, database(pages)).unwrap();
+        (dir, path)
+    }
+
+    /// Flips a byte in a meta page so its checksum no longer matches
+    fn tear(pages: &mut [Vec<u8>], page: usize) {
+        pages[page][PAGE_HEADER_SIZE + 20] ^= 0xff;
+    }
+
+    #[test]
+    fn follows_branch_pages_to_the_leaves() {
+        let (_dir, path) = write_db(&fixture());
+        let db = BoltDb::open(&path).unwrap();
+        let bucket = db.root_bucket().bucket(b"v1").unwrap().unwrap();
+        assert_eq!(bucket.get(b"a").unwrap(), Some(&b"1"[..]));
+        assert_eq!(bucket.get(b"b").unwrap(), Some(&b"2"[..]));
+        assert_eq!(bucket.get(b"m").unwrap(), Some(&b"3"[..]));
+        assert_eq!(bucket.get(b"c").unwrap(), None);
+        assert_eq!(bucket.get(b"z").unwrap(), None);
+        assert!(db.root_bucket().bucket(b"missing").unwrap().is_none());
+    }
+
+    #[test]
+    fn reads_nested_and_inline_buckets() {
+        let (_dir, path) = write_db(&fixture());
+        let db = BoltDb::open(&path).unwrap();
+        let nested = db.root_bucket().bucket_path(&[b"v1", b"nested"]).unwrap().unwrap();
+        assert_eq!(nested.get(b"x").unwrap(), Some(&b"y"[..]));
+        assert_eq!(nested.get(b"a").unwrap(), None);
+        assert!(db.root_bucket().bucket_path(&[b"v1", b"missing", b"x"]).unwrap().is_none());
+        // Buckets aren't values and values aren't buckets
+        let bucket = db.root_bucket().bucket(b"v1").unwrap().unwrap();
+        assert_eq!(bucket.get(b"nested").unwrap(), None);
+        assert!(bucket.bucket(b"a").is_err());
+    }
+
+    #[test]
+    fn uses_the_newest_meta_page() {
+        let (_dir, path) = write_db(&fixture());
+        let db = BoltDb::open(&path).unwrap();
+        assert_eq!(db.root_bucket().get(b"generation").unwrap(), Some(&b"new"[..]));
+    }
+
+    #[test]
+    fn falls_back_when_the_newest_meta_page_is_torn() {
+        let mut pages = fixture();
+        tear(&mut pages, 1);
+        let (_dir, path) = write_db(&pages);
+        let db = BoltDb::open(&path).unwrap();
+        assert_eq!(db.root_bucket().get(b"generation").unwrap(), Some(&b"old"[..]));
+    }
+
+    #[test]
+    fn finds_the_second_meta_page_when_the_first_is_torn() {
+        let mut pages = fixture();
+        pages[1] = meta_page(1, 3, 9);
+        tear(&mut pages, 0);
+        let (_dir, path) = write_db(&pages);
+        let db = BoltDb::open(&path).unwrap();
+        assert_eq!(db.root_bucket().get(b"generation").unwrap(), Some(&b"old"[..]));
+    }
+
+    #[test]
+    fn refuses_a_database_with_both_meta_pages_torn() {
+        let mut pages = fixture();
+        tear(&mut pages, 0);
+        tear(&mut pages, 1);
+        let (_dir, path) = write_db(&pages);
+        assert!(BoltDb::open(&path).is_err());
+    }
+}
@@ -0,0 +1,210 @@
+use std::{collections::HashSet, fs, io::ErrorKind};
+
+use anyhow::Result;
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::{application_state::{ApplicationState, Image}, paths::{get_blobs_path, get_images_path, get_layers_compressed_path, get_temp_path}, utils::get_allocated_size};
+
+/// What pruning removes, worked out before anything is deleted so a dry run can report it
+#[derive(Debug, Default)]
+pub struct PrunePlan {
+    /// Digests of the images no tag points at
+    pub images: Vec<String>,
+    /// Digests of the layers no remaining image is made up of
+    pub layers: Vec<String>,
+    /// Every file that is deleted, drive images, blobs, layers, partial downloads and temporary folders
+    pub files: Vec<Utf8PathBuf>,
+    /// Bytes the files take up on disk
+    pub size: u64,
+}
+
+/// Finds the untagged images, the drive images and layers nothing uses anymore
+/// and the partial downloads and temporary folders a run that was interrupted left behind
+pub fn plan_prune(state: &ApplicationState) -> Result<PrunePlan> {
+    let tagged = state.tagged_images.values().map(String::as_str).collect::<HashSet<&str>>();
+    let mut images = state.images.keys().filter(|digest| !tagged.contains(digest.as_str())).cloned().collect::<Vec<String>>();
+    images.sort();
+    let remaining = state.images.iter().filter(|(digest, _)| tagged.contains(digest.as_str())).map(|(_, image)| image).collect::<Vec<&Image>>();
+
+    let mut files = Vec::new();
+    // Drive images are named after the image digest followed by their variant
+    for path in list_files(&get_images_path()?)? {
+        if matches!(path.file_name().and_then(drive_image_digest), Some(digest) if !tagged.contains(digest)) {
+            files.push(path);
+        }
+    }
+    let blobs_path = get_blobs_path()?;
+    // The index of a multi-platform image is shared with the images of its other platforms
+    let used_blobs = remaining.iter()
+        .flat_map(|image| image.manifest_digest.as_deref().into_iter().chain(image.index_digest.as_deref()))
+        .chain(tagged.iter().copied())
+        .collect::<HashSet<&str>>();
+    for digest in &images {
+        let image = &state.images[digest];
+        for blob in std::iter::once(digest.as_str()).chain(image.manifest_digest.as_deref()).chain(image.index_digest.as_deref()) {
+            let path = blobs_path.join(blob);
+            // A single platform image's index is its manifest
+            if !used_blobs.contains(blob) && path.exists() && !files.contains(&path) {
+                files.push(path);
+            }
+        }
+    }
+
+    let used_layers = remaining.iter().flat_map(|image| image.layers.iter().map(String::as_str)).collect::<HashSet<&str>>();
+    let mut layers = state.layers.keys().filter(|digest| !used_layers.contains(digest.as_str())).cloned().collect::<Vec<String>>();
+    for path in list_files(&get_layers_compressed_path()?)? {
+        let Some(name) = path.file_name() else { continue };
+        if name.ends_with(".partial") {
+            files.push(path);
+        } else if let Some(digest) = name.strip_suffix(".tgz") {
+            if !used_layers.contains(digest) {
+                if !layers.iter().any(|layer| layer == digest) {
+                    layers.push(digest.to_string());
+                }
+                files.push(path);
+            }
+        }
+    }
+    layers.sort();
+    // Every command holds the state lock, so nothing else is using the temporary folder
+    files.extend(list_entries(&get_temp_path()?)?);
+
+    let mut size = 0;
+    for file in &files {
+        size += path_size(file)?;
+    }
+    Ok(PrunePlan { images, layers, files, size })
+}
+
+/// Deletes the files of a plan and stops tracking its images and layers
+pub fn apply_prune(state: &mut ApplicationState, plan: &PrunePlan) -> Result<()> {
+    for file in &plan.files {
+        if file.is_dir() {
+            fs::remove_dir_all(file)?;
+        } else {
+            remove_file_if_exists(file)?;
+        }
+    }
+    for digest in &plan.images {
+        state.remove_image(digest);
+    }
+    for digest in &plan.layers {
+        state.layers.remove(digest);
+    }
+    Ok(())
+}
+
+/// Removes the least recently used drive images and layers until the layers and drive images
+/// fit in max_size. The image that was just built and its layers are kept. Returns the removed files
+pub fn evict_least_recently_used(state: &mut ApplicationState, max_size: u64, keep: &str) -> Result<Vec<Utf8PathBuf>> {
+    let layers_path = get_layers_compressed_path()?;
+    let mut size = dir_size(&layers_path)? + dir_size(&get_images_path()?)?;
+    if size <= max_size {
+        return Ok(Vec::new());
+    }
+    let kept_layers = state.images.get(keep).map(|image| image.layers.clone()).unwrap_or_default();
+    // Each candidate is when it was last used, its files and the layer it is if it's one
+    let mut candidates = Vec::new();
+    for (digest, image) in &state.images {
+        let files = drive_image_files(digest)?;
+        if digest != keep && !files.is_empty() {
+            candidates.push((image.last_used_at, files, None));
+        }
+    }
+    for layer in state.layers.values() {
+        let path = layers_path.join(format!("{}.tgz", layer.digest));
+        if !kept_layers.contains(&layer.digest) && path.exists() {
+            candidates.push((layer.last_used_at, vec![path], Some(layer.digest.clone())));
+        }
+    }
+    candidates.sort_by_key(|(last_used_at, _, _)| *last_used_at);
+
+    let mut evicted = Vec::new();
+    for (_, files, layer) in candidates {
+        if size <= max_size {
+            break;
+        }
+        for file in files {
+            size = size.saturating_sub(get_allocated_size(file.as_std_path())?);
+            remove_file_if_exists(&file)?;
+            evicted.push(file);
+        }
+        // Images stay tracked, their layers are downloaded again when they are next built
+        if let Some(layer) = layer {
+            state.layers.remove(&layer);
+        }
+    }
+    Ok(evicted)
+}
+
+/// The disk space a folder and everything in it takes up, zero if it doesn't exist
+pub fn dir_size(path: &Utf8Path) -> Result<u64> {
+    let entries = match path.read_dir_utf8() {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+    let mut size = 0;
+    for entry in entries {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            size += dir_size(entry.path())?;
+        } else if !file_type.is_symlink() {
+            size += get_allocated_size(entry.path().as_std_path())?;
+        }
+    }
+    Ok(size)
+}
+
+/// The disk space a file or folder takes up
+pub fn path_size(path: &Utf8Path) -> Result<u64> {
+    if fs::symlink_metadata(path)?.is_dir() {
+        dir_size(path)
+    } else {
+        get_allocated_size(path.as_std_path())
+    }
+}
+
+/// The drive images built from an image in the images folder, along with the details kept next to them
+pub fn drive_image_files(digest: &str) -> Result<Vec<Utf8PathBuf>> {
+    Ok(list_files(&get_images_path()?)?
+        .into_iter()
+        .filter(|path| path.file_name().and_then(drive_image_digest) == Some(digest))
+        .collect())
+}
+
+/// The image digest a file in the images folder belongs to
+fn drive_image_digest(file_name: &str) -> Option<&str> {
+    if !file_name.starts_with("sha256:") {
+        return None;
+    }
+    file_name.split('.').next()
+}
+
+/// The files in a folder, none if it hasn't been created yet
+pub fn list_files(path: &Utf8Path) -> Result<Vec<Utf8PathBuf>> {
+    Ok(list_entries(path)?.into_iter().filter(|path| path.is_file()).collect())
+}
+
+/// Everything in a folder, nothing if it hasn't been created yet
+pub fn list_entries(path: &Utf8Path) -> Result<Vec<Utf8PathBuf>> {
+    let entries = match path.read_dir_utf8() {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut paths = Vec::new();
+    for entry in entries {
+        paths.push(entry?.into_path());
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+pub fn remove_file_if_exists(path: &Utf8Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
@@ -1,15 +1,17 @@
 use core::str;
-use std::{io::Write, path::Path, process::{Command, Output, Stdio}};
-use anyhow::{bail, Result};
+use std::{collections::BTreeSet, fs, os::unix::fs::{FileTypeExt, MetadataExt}, path::Path, process::{Command, Output}};
+use anyhow::{bail, Context, Result};
 use camino::Utf8PathBuf;
-use clap::builder::Str;
 
+use crate::{models::input_models::Filesystem, utils::{create_temp_dir, Reproducibility}};
+
+/// Checks the commands every drive image needs, the ones that create each partition's
+/// filesystem are checked once the layout is known by `check_filesystem_command_exists`
 pub fn check_required_commands_exist() -> Result<()> {
     which::which("dd")?;
+    which::which("fallocate")?;
     which::which("losetup")?;
-    which::which("mkfs.ext4")?;
     which::which("mount")?;
-    which::which("sfdisk")?;
     Ok(())
 }
 
@@ -39,39 +41,299 @@ pub fn copy_recursive(source: &Path, target: &Path) -> Result<()> {
     Ok(())
 }
 
-pub fn create_disk_image(image_path: &Utf8PathBuf, blocks: u64) -> Result<()>{
+/// Deallocates every block of the file that only holds zeros so it takes up
+/// no more disk space than the data written to it
+pub fn dig_holes(image_path: &Utf8PathBuf) -> Result<()> {
     output_error_if_failed(
-    Command::new("dd")
-        .args(&[
-            "if=/dev/zero",
-            format!("of={}", image_path.as_str()).as_str(),
-            "bs=4k",
-            format!("count={blocks}").as_str()
-        ])
-        .output()?
+        Command::new("fallocate")
+            .args(["--dig-holes", image_path.as_str()])
+            .output()?
     )?;
     Ok(())
 }
 
+/// Checks the command that creates a filesystem is installed
+pub fn check_filesystem_command_exists(filesystem: Filesystem) -> Result<()> {
+    which::which(filesystem.command()).context(format!("{} is needed to create {} filesystems", filesystem.command(), filesystem.name()))?;
+    Ok(())
+}
+
 /// Formats a image or device file as ext4
-pub fn format_ext4_file(path: &String) -> Result<()> {
-    println!("Formatting {} to ext4", path);
+pub fn format_ext4_file(path: &str, label: Option<&str>) -> Result<()> {
+    eprintln!("Formatting {} to ext4", path);
+    let mut command = Command::new("mkfs.ext4");
+    if let Some(label) = label {
+        command.args(["-L", label]);
+    }
+    output_error_if_failed(command.arg(path).output()?)?;
+    Ok(())
+}
+
+/// Formats a image or device file as ext4 populated from the source directory,
+/// pinning the uuid, hash seed and timestamps so the output is reproducible.
+/// mkfs.ext4 adds the entries of each directory sorted by name, so the order they were created
+/// in doesn't matter, but it copies the inode change times from the source, where they can't be
+/// set, so those are reset to the epoch afterwards
+pub fn format_ext4_file_reproducible(path: &str, source: &Path, reproducibility: &Reproducibility, label: Option<&str>) -> Result<()> {
+    eprintln!("Formatting {} to ext4 reproducibly", path);
+    which::which("debugfs").context("debugfs is needed to create reproducible ext4 filesystems")?;
+    // e2fsprogs reads a fake time of zero as unset, so the filesystem's own timestamps are
+    // a second later at the unix epoch
+    let fake_time = reproducibility.epoch.max(1).to_string();
+    let mut command = Command::new("mkfs.ext4");
+    // Older e2fsprogs only know about the fake time variable, newer ones
+    // also clamp the inode timestamps to SOURCE_DATE_EPOCH. The names are sorted
+    // with the locale's collation, so it's pinned too
+    command
+        .env("E2FSPROGS_FAKE_TIME", &fake_time)
+        .env("SOURCE_DATE_EPOCH", reproducibility.epoch.to_string())
+        .env("LC_ALL", "C")
+        .args([
+            "-U", reproducibility.fs_uuid.as_str(),
+            "-E", format!("hash_seed={}", reproducibility.hash_seed).as_str(),
+            "-d", &source.display().to_string(),
+        ]);
+    if let Some(label) = label {
+        command.args(["-L", label]);
+    }
+    output_error_if_failed(command.arg(path).output()?)?;
+    reset_ext4_change_times(path, reproducibility.epoch, &fake_time)
+}
+
+/// Sets the change and creation time of every inode in an ext4 filesystem to the epoch.
+/// The inodes are found by listing the directories with debugfs a level at a time,
+/// an entry with a newline in its name can't be read from the listing and is left as it is
+fn reset_ext4_change_times(path: &str, epoch: u64, fake_time: &str) -> Result<()> {
+    const ROOT_INODE: u32 = 2;
+    let temp_dir = create_temp_dir()?;
+    let script_path = temp_dir.path().join("commands");
+    let mut inodes = BTreeSet::from([ROOT_INODE]);
+    let mut directories = vec![ROOT_INODE];
+    while !directories.is_empty() {
+        fs::write(&script_path, directories.iter().map(|inode| format!("ls -p <{inode}>\n")).collect::<String>())?;
+        let listing = output_error_if_failed(Command::new("debugfs").arg("-f").arg(&script_path).arg(path).output()?)?;
+        directories.clear();
+        // Entries are listed as /inode/mode/uid/gid/name/size/
+        for line in listing.lines() {
+            let [_, inode, mode, _, _, name, _, _] = line.split('/').collect::<Vec<&str>>()[..] else {
+                continue;
+            };
+            let Ok(inode) = inode.parse::<u32>() else {
+                continue;
+            };
+            if name != "." && name != ".." && inodes.insert(inode) && mode.starts_with("04") {
+                directories.push(inode);
+            }
+        }
+    }
+    let script = inodes.iter()
+        .map(|inode| format!("sif <{inode}> ctime @{epoch}\nsif <{inode}> crtime @{epoch}\n"))
+        .collect::<String>();
+    fs::write(&script_path, script)?;
+    output_error_if_failed(
+        Command::new("debugfs")
+            .env("E2FSPROGS_FAKE_TIME", fake_time)
+            .args(["-w", "-f"])
+            .arg(&script_path)
+            .arg(path)
+            .output()?
+    )?;
+    Ok(())
+}
+
+/// A directory tree described for `mkfs.xfs -p`, with the modes of the entries that have a
+/// sticky bit, which the protofile has no way to give them
+pub struct XfsProtofile {
+    pub contents: String,
+    pub sticky: Vec<(String, u32)>,
+}
+
+/// Describes a directory tree as an xfs protofile. Returns None when a name or symlink target
+/// has whitespace or isn't utf8, since the protofile is split on whitespace.
+/// Sockets are left out and hardlinks become copies, as the format has neither
+pub fn xfs_protofile(source: &Path) -> Result<Option<XfsProtofile>> {
+    let mut protofile = XfsProtofile { contents: String::from("/dev/null\n0 0\n"), sticky: Vec::new() };
+    let metadata = fs::symlink_metadata(source)?;
+    protofile.contents.push_str(&format!("{} {} {}\n", xfs_proto_mode('d', metadata.mode()), metadata.uid(), metadata.gid()));
+    if metadata.mode() & 0o1000 != 0 {
+        protofile.sticky.push(("/".to_string(), metadata.mode()));
+    }
+    match write_xfs_proto_dir(source, "", &mut protofile)? {
+        true => Ok(Some(protofile)),
+        false => Ok(None),
+    }
+}
+
+/// Adds the entries of a directory to the protofile, false if one of them can't be described
+fn write_xfs_proto_dir(dir: &Path, path: &str, protofile: &mut XfsProtofile) -> Result<bool> {
+    let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<fs::DirEntry>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let Some(name) = entry.file_name().to_str().map(str::to_string) else { return Ok(false) };
+        if name.contains(char::is_whitespace) || name == "$" {
+            return Ok(false);
+        }
+        let entry_path = format!("{path}/{name}");
+        let metadata = entry.metadata()?;
+        let mode = metadata.mode();
+        let file_type = metadata.file_type();
+        let (type_char, extra) = if file_type.is_dir() {
+            ('d', None)
+        } else if file_type.is_symlink() {
+            let target = fs::read_link(entry.path())?;
+            match target.to_str() {
+                Some(target) if !target.is_empty() && !target.contains(char::is_whitespace) => ('l', Some(target.to_string())),
+                _ => return Ok(false),
+            }
+        } else if file_type.is_block_device() || file_type.is_char_device() {
+            let device = metadata.rdev();
+            let type_char = if file_type.is_block_device() { 'b' } else { 'c' };
+            let major = ((device >> 8) & 0xfff) | ((device >> 32) & 0xffff_f000);
+            let minor = (device & 0xff) | ((device >> 12) & 0xffff_ff00);
+            (type_char, Some(format!("{major} {minor}")))
+        } else if file_type.is_fifo() {
+            ('p', None)
+        } else if file_type.is_file() {
+            match entry.path().to_str() {
+                Some(source) if !source.contains(char::is_whitespace) => ('-', Some(source.to_string())),
+                _ => return Ok(false),
+            }
+        } else {
+            continue;
+        };
+        protofile.contents.push_str(&format!("{} {} {} {}", name, xfs_proto_mode(type_char, mode), metadata.uid(), metadata.gid()));
+        if let Some(extra) = extra {
+            protofile.contents.push_str(&format!(" {extra}"));
+        }
+        protofile.contents.push('\n');
+        if mode & 0o1000 != 0 && type_char != 'l' {
+            protofile.sticky.push((entry_path.clone(), mode));
+        }
+        if type_char == 'd' && !write_xfs_proto_dir(&entry.path(), &entry_path, protofile)? {
+            return Ok(false);
+        }
+    }
+    protofile.contents.push_str("$\n");
+    Ok(true)
+}
+
+/// The mode field of a protofile entry: its type, setuid and setgid, then the permissions in octal
+fn xfs_proto_mode(type_char: char, mode: u32) -> String {
+    let setuid = if mode & 0o4000 != 0 { 'u' } else { '-' };
+    let setgid = if mode & 0o2000 != 0 { 'g' } else { '-' };
+    format!("{type_char}{setuid}{setgid}{:03o}", mode & 0o777)
+}
+
+/// Formats a image or device file as xfs, populated from a protofile when one is given
+pub fn format_xfs_file(path: &str, protofile: Option<&XfsProtofile>, label: Option<&str>) -> Result<()> {
+    eprintln!("Formatting {} to xfs", path);
+    let mut command = Command::new("mkfs.xfs");
+    command.arg("-f");
+    if let Some(label) = label {
+        command.args(["-L", label]);
+    }
+    let Some(protofile) = protofile else {
+        output_error_if_failed(command.arg(path).output()?)?;
+        return Ok(());
+    };
+    let temp_dir = create_temp_dir()?;
+    let protofile_path = temp_dir.path().join("protofile");
+    fs::write(&protofile_path, &protofile.contents)?;
+    command.arg("-p").arg(&protofile_path);
+    output_error_if_failed(command.arg(path).output()?)?;
+    // The sticky bits are set on the unmounted filesystem afterwards, the mode having the file type too
+    if !protofile.sticky.is_empty() {
+        which::which("xfs_db").context("xfs_db is needed to set sticky bits on xfs filesystems")?;
+    }
+    for (entry_path, mode) in &protofile.sticky {
+        let mut command = Command::new("xfs_db");
+        command.args(["-x", "-c", &format!("path {entry_path}"), "-c", &format!("write core.mode 0{:o}", mode), path]);
+        output_error_if_failed(command.output()?)?;
+    }
+    Ok(())
+}
+
+/// Formats a image or device file as btrfs populated from the source directory
+pub fn format_btrfs_file(path: &str, source: &Path, label: Option<&str>) -> Result<()> {
+    eprintln!("Formatting {} to btrfs", path);
+    let mut command = Command::new("mkfs.btrfs");
+    command.args(["-f", "--rootdir", &source.display().to_string()]);
+    if let Some(label) = label {
+        command.args(["-L", label]);
+    }
+    output_error_if_failed(command.arg(path).output()?)?;
+    Ok(())
+}
+
+/// Formats a image or device file as swap, with a fixed uuid when one is given
+pub fn format_swap_file(path: &str, label: Option<&str>, uuid: Option<&str>) -> Result<()> {
+    eprintln!("Formatting {} as swap", path);
+    let mut command = Command::new("mkswap");
+    if let Some(label) = label {
+        command.args(["-L", label]);
+    }
+    if let Some(uuid) = uuid {
+        command.args(["-U", uuid]);
+    }
+    output_error_if_failed(command.arg(path).output()?)?;
+    Ok(())
+}
+
+/// Creates a squashfs image from the source directory. mksquashfs takes its
+/// timestamps from SOURCE_DATE_EPOCH when set, so reproducible builds only need the epoch
+pub fn create_squashfs_image(source: &Path, image_path: &Path, reproducibility: Option<&Reproducibility>) -> Result<()> {
+    eprintln!("Creating squashfs image {}", image_path.display());
+    let mut command = Command::new("mksquashfs");
+    command.arg(source).arg(image_path).args(["-noappend", "-quiet"]);
+    if let Some(reproducibility) = reproducibility {
+        command.env("SOURCE_DATE_EPOCH", reproducibility.epoch.to_string());
+    }
+    output_error_if_failed(command.output()?)?;
+    Ok(())
+}
+
+/// Creates an lz4 compressed erofs image from the source directory,
+/// pinning the uuid and timestamps when the build is reproducible
+pub fn create_erofs_image(source: &Path, image_path: &Path, reproducibility: Option<&Reproducibility>, label: Option<&str>) -> Result<()> {
+    eprintln!("Creating erofs image {}", image_path.display());
+    let mut command = Command::new("mkfs.erofs");
+    command.arg("-zlz4hc");
+    if let Some(label) = label {
+        command.args(["-L", label]);
+    }
+    if let Some(reproducibility) = reproducibility {
+        command
+            .env("SOURCE_DATE_EPOCH", reproducibility.epoch.to_string())
+            .args(["-T", &reproducibility.epoch.to_string(), "-U", &reproducibility.fs_uuid]);
+    }
+    output_error_if_failed(command.arg(image_path).arg(source).output()?)?;
+    Ok(())
+}
+
+/// Sets the access and modification time of every file in the directory
+/// that is newer than the epoch to the epoch
+pub fn clamp_file_times(path: &Path, epoch: u64) -> Result<()> {
+    let date = format!("@{epoch}");
     output_error_if_failed(
-        Command::new("mkfs.ext4")
-            .args(&[path.as_str()])
+        Command::new("find")
+            .arg(path)
+            .args([
+                "-newermt", date.as_str(),
+                "-exec", "touch", "--no-dereference", format!("--date={date}").as_str(), "{}", "+"
+            ])
             .output()?
     )?;
     Ok(())
 }
 
 /// Mounts an image or device file to a mount path
-pub fn mount_file(image_path: &String, mount_path: &String) -> Result<()> {
+pub fn mount_file(image_path: &str, mount_path: &str) -> Result<()> {
     output_error_if_failed(
         Command::new("mount")
-            .args(&[
+            .args([
                 "-t", "auto",
-                image_path.as_str(),
-                mount_path.as_str()
+                image_path,
+                mount_path
             ])
             .output()?
     )?;
@@ -81,7 +343,7 @@ pub fn mount_file(image_path: &String, mount_path: &String) -> Result<()> {
 pub fn unmount_file(mount_path: &Utf8PathBuf) -> Result<()> {
     output_error_if_failed(
         Command::new("umount")
-            .args(&[mount_path.as_str()])
+            .args([mount_path.as_str()])
             .output()?
     )?;
     Ok(())
@@ -93,7 +355,7 @@ pub fn burn_bootloader(image_path: &Utf8PathBuf, bootloader_path: &Utf8PathBuf)
     // That only the number of bytes that are allowed to be written to the mbr are written
     output_error_if_failed(
         Command::new("dd")
-            .args(&[
+            .args([
                 format!("if={}", bootloader_path.as_str()).as_str(),
                 format!("of={}", image_path.as_str()).as_str(),
                 "bs=440",
@@ -105,24 +367,49 @@ pub fn burn_bootloader(image_path: &Utf8PathBuf, bootloader_path: &Utf8PathBuf)
     Ok(())
 }
 
-/// Uses sfdisk to create a partition table on the provided image
-/// with a single ext4 partition
-pub fn create_partition_table(image_path: &Utf8PathBuf) -> Result<()> {
-
-    let mut sfdisk = Command::new("sfdisk")
-        .arg(&image_path)
-        .stdin(Stdio::piped())  // Enable piping to stdin
-        .stdout(Stdio::piped())
-        .spawn()?;
-
-    // Write the partition data to sfdisk's stdin
-    if let Some(mut stdin) = sfdisk.stdin.take() {
-        // Type 83 is ext4. We also mark the partition as bootable
-        stdin.write_all(b"type=83,bootable\n")?;
-    }
+/// Formats the device as LUKS2 with the key file in its first key slot
+pub fn luks_format(device: &str, key_file: &Utf8PathBuf, uuid: &str) -> Result<()> {
+    eprintln!("Encrypting {} with LUKS2", device);
+    output_error_if_failed(
+        Command::new("cryptsetup")
+            .args([
+                "luksFormat",
+                "--type", "luks2",
+                "--batch-mode",
+                "--uuid", uuid,
+                "--key-file", key_file.as_str(),
+                device
+            ])
+            .output()?
+    )?;
+    Ok(())
+}
 
-    output_error_if_failed(sfdisk.wait_with_output()?)?;
+/// Unlocks a LUKS device, returning the path of the device it's mapped to
+pub fn luks_open(device: &str, key_file: &Utf8PathBuf, name: &str) -> Result<Utf8PathBuf> {
+    output_error_if_failed(
+        Command::new("cryptsetup")
+            .args([
+                "open",
+                "--type", "luks2",
+                "--key-file", key_file.as_str(),
+                device,
+                name
+            ])
+            .output()?
+    )?;
+    Ok(Utf8PathBuf::from(format!("/dev/mapper/{name}")))
+}
 
+pub fn luks_close(name: &str) -> Result<()> {
+    output_error_if_failed(
+        Command::new("cryptsetup")
+            .args([
+                "close",
+                name
+            ])
+            .output()?
+    )?;
     Ok(())
 }
 
@@ -130,7 +417,7 @@ pub fn create_partition_table(image_path: &Utf8PathBuf) -> Result<()> {
 pub fn create_loop_device()-> Result<Utf8PathBuf> {
     let path = output_error_if_failed(
         Command::new("losetup")
-            .args(&[
+            .args([
                 "-f",
             ])
             .output()?
@@ -139,24 +426,25 @@ pub fn create_loop_device()-> Result<Utf8PathBuf> {
 }
 
 /// Uses losetup to detach a specific loop device
-pub fn detach_loop_device(device_name: &String)-> Result<()> {
+pub fn detach_loop_device(device_name: &str)-> Result<()> {
     output_error_if_failed(
         Command::new("losetup")
-            .args(&[
+            .args([
                 "-d",
-                device_name.as_str()
+                device_name
             ])
             .output()?
     )?;
     Ok(())
 }
 
-/// Uses losetup to attach a specific loop device with a provided offset in bytes
-pub fn mount_with_offset(image_path: &Utf8PathBuf, mount_path: &Utf8PathBuf, offset: u64) -> Result<()> {
+/// Uses losetup to attach a specific loop device with a provided offset and size in bytes
+pub fn mount_with_offset(image_path: &Utf8PathBuf, mount_path: &Utf8PathBuf, offset: u64, size: u64) -> Result<()> {
     output_error_if_failed(
         Command::new("losetup")
-            .args(&[
+            .args([
                 "-o", &format!("{offset}"),
+                "--sizelimit", &format!("{size}"),
                 mount_path.as_str(),
                 image_path.as_str()
             ])
@@ -1,33 +1,34 @@
-use std::fs;
-use anyhow::{Context, Result};
+use std::{collections::HashMap, fs, io::{self, IsTerminal, Write}, path::Path};
+use anyhow::{bail, Context, Result};
 use camino::Utf8PathBuf;
+use serde::{de::DeserializeOwned, Serialize};
 
 use crate::{
-    application_state::{ApplicationState, Image, StateHandle}, docker_client::DockerClient, models::{
+    application_state::{ApplicationState, StateHandle}, cache::{apply_prune, dir_size, drive_image_files, evict_least_recently_used, list_entries, plan_prune, remove_file_if_exists}, disk_formats::{convert_raw_image, ImageIdentity}, docker_client::DockerClient, docker_daemon_client::DockerDaemonClient, initramfs::{create_initramfs, InitramfsOptions}, local_images::{export_oci_layout, import_docker_archive, import_oci_layout, import_oci_layout_images}, local_stores::{import_containerd_image, import_podman_image, DEFAULT_CONTAINERD_ROOT, DEFAULT_STORAGE_ROOT}, models::{
         input_models::*,
-        output_models::{ImageInfoResult, ListImagesResult, MakeImageResult, PruneResult, RemoveImageResult},
-        registry_models::Platform,
-    }, paths::{get_images_path, get_layers_compressed_path}, utils::create_drive_image
+        output_models::{DiskUsageResult, ExportImageResult, FolderUsage, ImageEntry, ImageUsage, ExportRootfsResult, ImageInfoResult, ImportImagesResult, ListImagesResult, MakeImageResult, PruneResult, PurgeResult, RemoveImageResult, TagImageResult, UntagImageResult},
+        registry_models::{ImageConfig, Manifests, OCIManifest, Platform, PulledImage},
+    }, paths::{get_app_state_path, get_base_path, get_blobs_path, get_images_path, get_layers_compressed_path, get_layers_path, get_temp_path}, utils::{copy_sparse, create_drive_image, export_rootfs_dir, export_rootfs_tar, get_allocated_size, glob_match, parse_rfc3339, read_stored_blob, sha256_bytes, store_blob, DriveImage, DriveOptions, Reproducibility}
 };
 
 /// Get the info about an image that will be downloaded
 pub async fn image_info(args: ImageInfoArgs) -> Result<String> {
-    let handle = StateHandle::new()?;
+    let handle = StateHandle::read_only()?;
     let state = &handle.state;
     let platform = Platform {
         architecture: args.architecture,
         os: args.os
     };
 
-    let client = DockerClient::new_with_auth(&args.image.name).await?;
-    let manifests = client.get_manifests().await?;
-    let manifest = manifests.get_manifest_for_platform(&platform).context("Manifest not found for platform")?;
-    let oci_manifest = client.get_oci_manifest( &manifest.digest.as_str()).await?;
-    let stored_digest = state.get_stored_image_digest(&args.image.name, &args.image.tag, &platform);
+    let client = DockerClient::new_with_auth(&args.image.name, args.image.manifest_reference()).await?;
+    let remote = resolve_remote_manifest(&client, &args.image, &platform).await?;
+    let stored_digest = state.get_stored_image_digest(&args.image, &platform);
     let downloaded = stored_digest.is_some();
-    let is_latest = matches!(stored_digest, Some(v) if v == oci_manifest.config.digest);
+    let is_latest = matches!(stored_digest, Some(v) if v == remote.manifest.config.digest);
     Ok(serde_json::to_string_pretty(&ImageInfoResult {
-        digest: oci_manifest.config.digest,
+        digest: remote.manifest.config.digest,
+        manifest_digest: remote.manifest_digest,
+        index_digest: remote.index_digest,
         downloaded,
         is_latest,
     })?)
@@ -37,149 +38,852 @@ pub async fn image_info(args: ImageInfoArgs) -> Result<String> {
 pub async fn build_image(args: BuildImageArgs) -> Result<String> {
     let mut handle = StateHandle::new()?;
     let state = &mut handle.state;
-    let result = build_image_remote(args, state).await?;
+    let cache_max = match args.cache_max {
+        Some(cache_max) => Some(cache_max),
+        None => Config::load()?.cache_max,
+    };
+    let mut result = match (&args.image.transport, args.source) {
+        (Some(_), _) | (None, ImageSourceKind::Containerd | ImageSourceKind::Podman) => build_image_local(args, state)?,
+        (None, ImageSourceKind::Registry) => build_image_remote(args, state).await?,
+        (None, ImageSourceKind::DockerDaemon) => build_image_docker_daemon(args, state).await?,
+    };
+    if let Some(cache_max) = cache_max {
+        result.evicted = evict_least_recently_used(state, cache_max, &result.digest)?
+            .iter()
+            .map(|file| file.to_string())
+            .collect();
+    }
+    handle.commit()?;
     Ok(serde_json::to_string_pretty(&result)?)
 }
 
 async fn build_image_remote(args: BuildImageArgs, state: &mut ApplicationState) -> Result<MakeImageResult> {    
-    let layers_folder = get_layers_compressed_path()?;
-    let images_folder = get_images_path()?;
     let platform = Platform {
-        architecture: args.architecture,
-        os: args.os
+        architecture: args.architecture.clone(),
+        os: args.os.clone()
     };
 
-    println!("building remote image for {}:{}", args.image.name, args.image.tag);
+    let cached = match args.pull_policy() {
+        PullPolicy::Always => None,
+        PullPolicy::Missing => match load_cached_image(&args, state, &platform) {
+            Ok(cached) => Some(cached),
+            Err(e) => {
+                eprintln!("{}, pulling it", e);
+                None
+            },
+        },
+        PullPolicy::Never => match load_cached_image(&args, state, &platform) {
+            Ok(cached) => Some(cached),
+            Err(e) => bail!("{}, so it can't be built without pulling it", e),
+        },
+    };
+    if let Some(cached) = cached {
+        eprintln!("building cached image for {}", args.image.tagged_reference());
+        return build_pulled_image(args, state, platform, cached);
+    }
+
+    eprintln!("building remote image for {}", args.image.tagged_reference());
 
-    let client = DockerClient::new_with_auth(&args.image.name).await?;
-    let manifests = client.get_manifests().await?;
-    let manifest = manifests.get_manifest_for_platform(&platform).context("Manifest not found for platform")?;
-    let oci_manifest = client.get_oci_manifest(&manifest.digest.as_str()).await?;
-    let stored_digest = state.get_stored_image_digest(&args.image.name, &args.image.tag, &platform);
-    let mut file_path = images_folder.join(format!("{}.img", oci_manifest.config.digest)).to_string();
+    let client = DockerClient::new_with_auth(&args.image.name, args.image.manifest_reference()).await?;
+    let RemoteManifest { index_digest, manifest_digest, manifest: oci_manifest } = resolve_remote_manifest(&client, &args.image, &platform).await?;
+    let digest = oci_manifest.config.digest.clone();
+    let stored_digest = state.get_stored_image_digest(&args.image, &platform);
     let downloaded = stored_digest.is_some();
-    let is_latest = !args.outfile.is_some() && matches!(&stored_digest, Some(v) if v == &oci_manifest.config.digest);
+    // Get layer digests
+    let layers = oci_manifest.layers
+        .iter()
+        .map(|l|{l.digest.clone()})
+        .collect::<Vec<String>>();
     // If the digest doesn't match, it means that we have to download new layers
-    let size = if !is_latest {
-        // Get layer digests
-        let layers = oci_manifest.layers
-            .iter()
-            .map(|l|{l.digest.clone()})
-            .collect::<Vec<String>>();
-        // Download each layer
-        client.download_layers_compressed(&layers).await?;
-        let image_config = client.get_image_config(&oci_manifest.config.digest.as_str()).await?;
-        let bootloader_path = image_config.config.labels.get("whaledrive.bootloader.path").context("Bootloader not found in image config")?;
-        
-        let image_directory = get_images_path()?;
-        if let Some(outfile) = &args.outfile {
-            file_path = outfile.to_string();
-        }
-        fs::create_dir_all(&image_directory)?;
-        
-        create_drive_image(
-            &layers,
-            layers_folder.as_std_path(),
-            &bootloader_path,
-            &Utf8PathBuf::from(&file_path)
-        )?
-    } else {
-        let digest = stored_digest.context("Expected digest to exist")?;
-        state.images.get(&digest).context(format!("Expected image {} to exist", digest))?.size
-    };
-    // Update state with info about the new image
-    if !state.images.contains_key(&oci_manifest.config.digest) {
-        state.images.insert(oci_manifest.config.digest.clone(), Image {
-            name: args.image.name.clone(),
-            tag: args.image.tag.clone(),
-            platform: platform.clone(),
-            size,
-            layers: oci_manifest.layers.iter().map(|l|{l.digest.clone()}).collect::<Vec<String>>()
-        });
+    let (size, file_path) = match get_latest_stored_image(&args, state, &stored_digest, &digest)? {
+        Some(stored) => stored,
+        None => {
+            // Download each layer
+            client.download_layers_compressed(&layers).await?;
+            let config_bytes = client.get_image_config_bytes(digest.as_str()).await?;
+            let image_config: ImageConfig = serde_json::from_slice(&config_bytes)?;
+            store_blob(&config_bytes)?;
+            create_drive_for_image(&args, &digest, &layers, &image_config)?
+        }
+    };
+    state.record_image(&args.image, &platform, &digest, Some(manifest_digest), Some(index_digest), layers, size)?;
+    Ok(MakeImageResult {
+        digest,
+        size,
+        allocated_size: get_allocated_size(Path::new(&file_path))?,
+        downloaded,
+        verity: read_sidecar(&file_path, VERITY_SIDECAR, args.verity)?,
+        encryption: read_sidecar(&file_path, ENCRYPTION_SIDECAR, args.encrypt)?,
+        partitions: read_sidecar(&file_path, PARTITIONS_SIDECAR, args.has_layout())?,
+        evicted: Vec::new(),
+        file_path
+    })
+}
+
+async fn build_image_docker_daemon(args: BuildImageArgs, state: &mut ApplicationState) -> Result<MakeImageResult> {
+    let platform = Platform {
+        architecture: args.architecture.clone(),
+        os: args.os.clone()
+    };
+    let image = args.image.to_string();
+
+    eprintln!("building image {} from the docker daemon", image);
+
+    let client = DockerDaemonClient::from_env()?;
+    let inspect = client.inspect_image(&image).await?;
+    if inspect.os != platform.os || inspect.architecture != platform.architecture {
+        bail!(
+            "Local image {} is for {}/{} but {}/{} was requested",
+            image, inspect.os, inspect.architecture, platform.os, platform.architecture
+        );
     }
-    state.set_stored_image_digest(&args.image.name, &args.image.tag, &platform, oci_manifest.config.digest.clone());
+    let stored_digest = state.get_stored_image_digest(&args.image, &platform);
+    let downloaded = stored_digest.is_some();
+    // With the classic image store the id is the config digest drive images are stored under, so a stored
+    // one is reused without exporting. The containerd image store's id is the manifest digest instead,
+    // and the config digest is only known once the image is exported
+    let (digest, size, file_path, manifest_digest, layers) = match get_latest_stored_image(&args, state, &stored_digest, &inspect.id)? {
+        Some((size, file_path)) => {
+            let layers = state.images.get(&inspect.id).map(|i| i.layers.clone()).unwrap_or_default();
+            (inspect.id, size, file_path, None, layers)
+        },
+        None => {
+            let pulled = client.export_image(&image).await?;
+            let (size, file_path) = match get_latest_stored_image(&args, state, &stored_digest, &pulled.digest)? {
+                Some(stored) => stored,
+                None => create_drive_for_image(&args, &pulled.digest, &pulled.layers, &pulled.config)?,
+            };
+            (pulled.digest, size, file_path, Some(pulled.manifest_digest), pulled.layers)
+        }
+    };
+    state.record_image(&args.image, &platform, &digest, manifest_digest, None, layers, size)?;
+    Ok(MakeImageResult {
+        digest,
+        size,
+        allocated_size: get_allocated_size(Path::new(&file_path))?,
+        downloaded,
+        verity: read_sidecar(&file_path, VERITY_SIDECAR, args.verity)?,
+        encryption: read_sidecar(&file_path, ENCRYPTION_SIDECAR, args.encrypt)?,
+        partitions: read_sidecar(&file_path, PARTITIONS_SIDECAR, args.has_layout())?,
+        evicted: Vec::new(),
+        file_path
+    })
+}
+
+/// Builds from a `docker save` archive, OCI layout, containerd or Podman store
+/// without any network access
+fn build_image_local(args: BuildImageArgs, state: &mut ApplicationState) -> Result<MakeImageResult> {
+    let platform = Platform {
+        architecture: args.architecture.clone(),
+        os: args.os.clone()
+    };
+
+    eprintln!("building local image {}", args.image);
+
+    let pulled = import_local_image(&args.image, args.source, args.store_root.as_ref(), &args.containerd_namespace, &platform)?;
+    build_pulled_image(args, state, platform, pulled)
+}
+
+/// Builds the drive for an image whose layers, manifest and config are in the cache, reusing an up to date stored drive image
+fn build_pulled_image(args: BuildImageArgs, state: &mut ApplicationState, platform: Platform, pulled: PulledImage) -> Result<MakeImageResult> {
+    let stored_digest = state.get_stored_image_digest(&args.image, &platform);
+    let downloaded = stored_digest.is_some();
+    let (size, file_path) = match get_latest_stored_image(&args, state, &stored_digest, &pulled.digest)? {
+        Some(stored) => stored,
+        None => create_drive_for_image(&args, &pulled.digest, &pulled.layers, &pulled.config)?,
+    };
+    state.record_image(&args.image, &platform, &pulled.digest, Some(pulled.manifest_digest), pulled.index_digest, pulled.layers, size)?;
     Ok(MakeImageResult {
-        digest: oci_manifest.config.digest,
+        digest: pulled.digest,
         size,
+        allocated_size: get_allocated_size(Path::new(&file_path))?,
         downloaded,
+        verity: read_sidecar(&file_path, VERITY_SIDECAR, args.verity)?,
+        encryption: read_sidecar(&file_path, ENCRYPTION_SIDECAR, args.encrypt)?,
+        partitions: read_sidecar(&file_path, PARTITIONS_SIDECAR, args.has_layout())?,
+        evicted: Vec::new(),
         file_path
     })
 }
 
-/// Clean all layers not associated with an existing image
-pub fn prune() -> Result<String> {
+/// Copies an image from a local archive, layout or store into the layer cache
+fn import_local_image(image: &ImageArg, source: ImageSourceKind, store_root: Option<&Utf8PathBuf>, containerd_namespace: &str, platform: &Platform) -> Result<PulledImage> {
+    let pulled = match (&image.transport, source) {
+        (Some(ImageTransport::DockerArchive(path)), _) => import_docker_archive(path.as_std_path())?,
+        (Some(ImageTransport::OciLayout { path, tag }), _) => import_oci_layout(path.as_std_path(), tag.as_deref(), platform)?,
+        (None, ImageSourceKind::Containerd) => {
+            let root = store_root.cloned().unwrap_or(Utf8PathBuf::from(DEFAULT_CONTAINERD_ROOT));
+            import_containerd_image(root.as_std_path(), containerd_namespace, &image.to_string(), platform)?
+        },
+        (None, ImageSourceKind::Podman) => {
+            let root = store_root.cloned().unwrap_or(Utf8PathBuf::from(DEFAULT_STORAGE_ROOT));
+            import_podman_image(root.as_std_path(), &image.to_string())?
+        },
+        (None, _) => bail!("{} is not a local image reference", image),
+    };
+    check_image_platform(image, &pulled.config, platform)?;
+    Ok(pulled)
+}
+
+/// Fails if a local image, which has a single platform, isn't for the requested one
+fn check_image_platform(image: &ImageArg, config: &ImageConfig, platform: &Platform) -> Result<()> {
+    if config.os != platform.os || config.architecture != platform.architecture {
+        bail!(
+            "Local image {} is for {}/{} but {}/{} was requested",
+            image, config.os, config.architecture, platform.os, platform.architecture
+        );
+    }
+    Ok(())
+}
+
+/// The manifest a registry reference resolves to for a platform
+struct RemoteManifest {
+    /// Digest of what the reference points at, an index or the manifest itself
+    index_digest: String,
+    manifest_digest: String,
+    manifest: OCIManifest,
+}
+
+/// Fetches what a reference points at and picks the manifest for the platform, storing both as blobs.
+/// A reference pinned by digest has to match what the registry returned, and the manifest has to
+/// match the digest the index lists for it. Nothing is stored until both are checked
+async fn resolve_remote_manifest(client: &DockerClient, image: &ImageArg, platform: &Platform) -> Result<RemoteManifest> {
+    let bytes = client.get_manifests_bytes().await?;
+    let index_digest = sha256_bytes(&bytes);
+    if matches!(&image.digest, Some(digest) if *digest != index_digest) {
+        bail!("{} resolved to {} instead of the pinned digest", image.tagged_reference(), index_digest);
+    }
+    let manifests = Manifests::parse(&bytes)?;
+    let manifest_bytes = match manifests.manifests {
+        Some(_) => {
+            let manifest = manifests.get_manifest_for_platform(platform).context("Manifest not found for platform")?;
+            let manifest_bytes = client.get_oci_manifest_bytes(manifest.digest.as_str()).await?;
+            let digest = sha256_bytes(&manifest_bytes);
+            if digest != manifest.digest {
+                bail!("The manifest of {} for {}/{} hashes to {} instead of {}", image.tagged_reference(), platform.os, platform.architecture, digest, manifest.digest);
+            }
+            manifest_bytes
+        },
+        // A digest can point straight at the manifest of a single platform
+        None => bytes.clone(),
+    };
+    let manifest: OCIManifest = serde_json::from_slice(&manifest_bytes)?;
+    store_blob(&bytes)?;
+    let manifest_digest = store_blob(&manifest_bytes)?;
+    Ok(RemoteManifest { index_digest, manifest_digest, manifest })
+}
+
+/// Downloads any layers of a registry image that aren't cached yet, storing its manifest and config
+async fn pull_remote_image(image: &ImageArg, platform: &Platform) -> Result<PulledImage> {
+    let client = DockerClient::new_with_auth(&image.name, image.manifest_reference()).await?;
+    let RemoteManifest { index_digest, manifest_digest, manifest: oci_manifest } = resolve_remote_manifest(&client, image, platform).await?;
+    let digest = oci_manifest.config.digest.clone();
+    let layers = oci_manifest.layers
+        .iter()
+        .map(|l|{l.digest.clone()})
+        .collect::<Vec<String>>();
+    client.download_layers_compressed(&layers).await?;
+    let config_bytes = client.get_image_config_bytes(digest.as_str()).await?;
+    let config: ImageConfig = serde_json::from_slice(&config_bytes)?;
+    store_blob(&config_bytes)?;
+    Ok(PulledImage { digest, manifest_digest, index_digest: Some(index_digest), config, layers })
+}
+
+/// Reads the image the reference was last built or tagged as from the cache, without any network access.
+/// Fails when its manifest or config isn't stored, or a layer isn't and there's no drive image to reuse
+fn load_cached_image(args: &BuildImageArgs, state: &ApplicationState, platform: &Platform) -> Result<PulledImage> {
+    let reference = args.image.tagged_reference();
+    let digest = state.get_stored_image_digest(&args.image, platform)
+        .context(format!("{} isn't cached for {}/{}", reference, platform.os, platform.architecture))?;
+    let image = state.images.get(&digest).context(format!("Expected image {} to exist", digest))?;
+    let manifest_digest = image.manifest_digest.clone()
+        .context(format!("{} was stored before manifests were kept", reference))?;
+    let manifest: OCIManifest = serde_json::from_slice(&read_stored_blob(&manifest_digest)?)?;
+    if manifest.config.digest != digest {
+        bail!("The stored manifest of {} is for {} instead of {}", reference, manifest.config.digest, digest);
+    }
+    let config: ImageConfig = serde_json::from_slice(&read_stored_blob(&digest)?)?;
+    // Evicted layers are only needed when the drive image has to be built again
+    let drive_image = get_images_path()?.join(get_image_file_name(args, &digest)?);
+    if args.encrypt || !drive_image.exists() {
+        let layers_path = get_layers_compressed_path()?;
+        if let Some(layer) = image.layers.iter().find(|layer| !layers_path.join(format!("{layer}.tgz")).exists()) {
+            bail!("Layer {} of {} isn't cached", layer, reference);
+        }
+    }
+    Ok(PulledImage {
+        digest,
+        manifest_digest,
+        index_digest: image.index_digest.clone(),
+        config,
+        layers: image.layers.clone(),
+    })
+}
+
+/// If the stored image for the arguments already has the digest, returns its size and
+/// path so it doesn't have to be rebuilt. It is copied when another output file was requested
+fn get_latest_stored_image(args: &BuildImageArgs, state: &ApplicationState, stored_digest: &Option<String>, digest: &str) -> Result<Option<(u64, String)>> {
+    let is_latest = matches!(stored_digest, Some(v) if v == digest);
+    // The key file may have changed since an encrypted image was stored
+    if !is_latest || args.encrypt {
+        return Ok(None);
+    }
+    let size = state.images.get(digest).context(format!("Expected image {} to exist", digest))?.size;
+    let file_path = get_images_path()?.join(get_image_file_name(args, digest)?);
+    // Imported images are tracked before their drive image is built
+    if !file_path.exists() {
+        return Ok(None);
+    }
+    // The size in the state is of the default raw ext4 image
+    let size = match (args.output_format, args.fs) {
+        (OutputFormat::Raw, Filesystem::Ext4) if args.has_default_partitions() => size,
+        _ => fs::metadata(&file_path)?.len(),
+    };
+    if let Some(outfile) = &args.outfile {
+        copy_sparse(file_path.as_std_path(), outfile.as_std_path())?;
+        for (kind, expected) in [(VERITY_SIDECAR, args.verity), (ENCRYPTION_SIDECAR, args.encrypt), (PARTITIONS_SIDECAR, args.has_layout())] {
+            if expected {
+                fs::copy(sidecar_path(file_path.as_str(), kind), sidecar_path(outfile.as_str(), kind))?;
+            }
+        }
+        return Ok(Some((size, outfile.to_string())));
+    }
+    Ok(Some((size, file_path.to_string())))
+}
+
+const VERITY_SIDECAR: &str = "verity";
+const ENCRYPTION_SIDECAR: &str = "luks";
+const PARTITIONS_SIDECAR: &str = "partitions";
+
+/// Details of a drive image's partitions are kept next to it as JSON, so a stored image can report them
+fn sidecar_path(file_path: &str, kind: &str) -> String {
+    format!("{file_path}.{kind}.json")
+}
+
+fn read_sidecar<T: DeserializeOwned>(file_path: &str, kind: &str, expected: bool) -> Result<Option<T>> {
+    if !expected {
+        return Ok(None);
+    }
+    let contents = fs::read(sidecar_path(file_path, kind)).context(format!("The {} details of the drive image weren't found", kind))?;
+    Ok(Some(serde_json::from_slice(&contents)?))
+}
+
+fn write_sidecar<T: Serialize>(file_path: &str, kind: &str, value: Option<&T>) -> Result<()> {
+    if let Some(value) = value {
+        fs::write(sidecar_path(file_path, kind), serde_json::to_string_pretty(value)?)?;
+    }
+    Ok(())
+}
+
+fn write_sidecars(args: &BuildImageArgs, file_path: &str, drive: &DriveImage) -> Result<()> {
+    write_sidecar(file_path, VERITY_SIDECAR, drive.verity.as_ref())?;
+    write_sidecar(file_path, ENCRYPTION_SIDECAR, drive.encryption.as_ref())?;
+    write_sidecar(file_path, PARTITIONS_SIDECAR, args.has_layout().then_some(&drive.partitions))
+}
+
+/// The name a drive image is stored under, which includes whether it's reproducible, the filesystem
+/// unless it's the default ext4, whether it has a verity partition or is encrypted, a hash of any custom
+/// layout and whether qcow2 clusters are compressed. An initramfs has no filesystem but is named after its compression
+fn get_image_file_name(args: &BuildImageArgs, digest: &str) -> Result<String> {
+    let mut name = digest.to_string();
+    // A reproducible image can't be served from a build that used random ids and the current
+    // time, or from one with another SOURCE_DATE_EPOCH
+    if args.reproducible {
+        name.push_str(&format!(".repro-{}", Reproducibility::from_digest(digest)?.epoch));
+    }
+    if args.output_format == OutputFormat::Initramfs {
+        return Ok(match args.compress {
+            true => format!("{}.{}.{}", name, args.output_format.extension(), args.compression.extension()),
+            false => format!("{}.{}", name, args.output_format.extension()),
+        });
+    }
+    if args.fs != Filesystem::Ext4 {
+        name.push_str(&format!(".{}", args.fs.name()));
+    }
+    if args.verity {
+        name.push_str(".verity");
+    }
+    if args.encrypt {
+        name.push_str(".luks");
+    }
+    if args.partition_table == PartitionTableType::Gpt {
+        name.push_str(".gpt");
+    }
+    if args.alignment != DEFAULT_ALIGNMENT {
+        name.push_str(&format!(".align-{}", args.alignment));
+    }
+    if args.has_layout() {
+        let layout_digest = sha256_bytes(serde_json::to_string(&args.partition_layout()?)?.as_bytes());
+        name.push_str(&format!(".layout-{}", &layout_digest.trim_start_matches("sha256:")[0..12]));
+    }
+    if !args.overlays.is_empty() {
+        let overlays_digest = sha256_bytes(args.overlays.join("\n").as_bytes());
+        name.push_str(&format!(".overlay-{}", &overlays_digest.trim_start_matches("sha256:")[0..12]));
+    }
+    // The other disk formats are either never or always compressed
+    if args.compress && args.output_format == OutputFormat::Qcow2 {
+        name.push_str(".compressed");
+    }
+    Ok(format!("{}.{}", name, args.output_format.extension()))
+}
+
+/// Creates the drive image for an image whose layers are in the compressed layers folder,
+/// returning its size and path
+fn create_drive_for_image(args: &BuildImageArgs, digest: &str, layers: &[String], image_config: &ImageConfig) -> Result<(u64, String)> {
+    let layers_folder = get_layers_compressed_path()?;
+    let image_directory = get_images_path()?;
+    let file_path = match &args.outfile {
+        Some(outfile) => outfile.to_string(),
+        None => image_directory.join(get_image_file_name(args, digest)?).to_string(),
+    };
+    fs::create_dir_all(&image_directory)?;
+    if args.output_format == OutputFormat::Initramfs {
+        if args.verity || args.encrypt || args.has_layout() {
+            bail!("An initramfs has no partitions to lay out, add a verity hash tree to or encrypt");
+        }
+        let options = InitramfsOptions {
+            compression: args.compress.then_some(args.compression),
+            epoch: if args.reproducible { Some(Reproducibility::from_digest(digest)?.epoch) } else { None },
+        };
+        let size = create_initramfs(layers, layers_folder.as_std_path(), image_config, Path::new(&file_path), &options)?;
+        return Ok((size, file_path));
+    }
+    let bootloader_path = image_config.get_label("whaledrive.bootloader.path").context("Bootloader not found in image config")?;
+    let options = DriveOptions {
+        reproducibility: if args.reproducible {
+            Some(Reproducibility::from_digest(digest)?)
+        } else {
+            None
+        },
+        filesystem: args.fs,
+        verity: args.verity,
+        encryption_key: if args.encrypt { args.key_file.clone() } else { None },
+        partitions: args.partition_layout()?,
+        partition_table: args.partition_table,
+        alignment: args.alignment,
+        overlays: args.overlays.clone(),
+    };
+    if args.output_format == OutputFormat::Raw {
+        let drive = create_drive_image(
+            layers,
+            layers_folder.as_std_path(),
+            bootloader_path,
+            &Utf8PathBuf::from(&file_path),
+            &options
+        )?;
+        write_sidecars(args, &file_path, &drive)?;
+        return Ok((drive.size, file_path));
+    }
+    // Other formats are converted from a raw image next to the output
+    let identity = ImageIdentity::new(digest, options.reproducibility.as_ref())?;
+    let raw_path = Utf8PathBuf::from(format!("{file_path}.raw"));
+    let result = create_drive_image(
+        layers,
+        layers_folder.as_std_path(),
+        bootloader_path,
+        &raw_path,
+        &options
+    ).and_then(|drive| {
+        convert_raw_image(raw_path.as_std_path(), Path::new(&file_path), args.output_format, args.compress, &identity)?;
+        Ok(drive)
+    });
+    let _ = fs::remove_file(&raw_path);
+    write_sidecars(args, &file_path, &result?)?;
+    Ok((fs::metadata(&file_path)?.len(), file_path))
+}
+
+/// Write a stored image to an OCI image layout
+pub fn export_image(args: ExportImageArgs) -> Result<String> {
+    let handle = StateHandle::read_only()?;
+    let state = &handle.state;
+    let platform = Platform {
+        architecture: args.architecture,
+        os: args.os
+    };
+    let digest = state.get_stored_image_digest(&args.image, &platform)
+        .context(format!("Image {} not found", args.image))?;
+    let image = state.images.get(&digest).context(format!("Expected image {} to exist", digest))?;
+    let manifest_digest = export_oci_layout(args.oci_dir.as_std_path(), image)?;
+    Ok(serde_json::to_string_pretty(&ExportImageResult {
+        digest,
+        manifest_digest,
+        path: args.oci_dir.to_string(),
+    })?)
+}
+
+/// Write the flattened root filesystem of an image to a tarball or directory
+pub async fn export_rootfs(args: ExportRootfsArgs) -> Result<String> {
     let mut handle = StateHandle::new()?;
     let state = &mut handle.state;
-    
-    let base_folder = std::env::current_dir()?.join("data");
-    let active_digests: Vec<String> = state.tagged_images.values().cloned().collect();
-    
-    let inactive_layers: Vec<String> = state.layers.iter().filter(|l|{!active_digests.contains(l)}).cloned().collect();
-    for layer in &inactive_layers {
-        let path = base_folder.join(format!("layers/{}.tgz", layer));
-        if path.exists() {
-            fs::remove_file(path)?;
-        }
+    let platform = Platform {
+        architecture: args.architecture.clone(),
+        os: args.os.clone()
+    };
+    let pulled = match (&args.image.transport, args.source) {
+        (Some(_), _) | (None, ImageSourceKind::Containerd | ImageSourceKind::Podman) => {
+            import_local_image(&args.image, args.source, args.store_root.as_ref(), &args.containerd_namespace, &platform)?
+        },
+        (None, ImageSourceKind::Registry) => pull_remote_image(&args.image, &platform).await?,
+        (None, ImageSourceKind::DockerDaemon) => {
+            let pulled = DockerDaemonClient::from_env()?.export_image(&args.image.to_string()).await?;
+            check_image_platform(&args.image, &pulled.config, &platform)?;
+            pulled
+        },
+    };
+    let layers_folder = get_layers_compressed_path()?;
+    let path = match (&args.tar, &args.dir) {
+        (Some(tar), _) => {
+            export_rootfs_tar(&pulled.layers, layers_folder.as_std_path(), tar.as_std_path())?;
+            tar.to_string()
+        },
+        (None, Some(dir)) => {
+            export_rootfs_dir(&pulled.layers, layers_folder.as_std_path(), dir.as_std_path())?;
+            dir.to_string()
+        },
+        (None, None) => bail!("Either --tar or --dir is required"),
+    };
+    // The image is tracked like an import since no drive image was built
+    state.record_image(&args.image, &platform, &pulled.digest, Some(pulled.manifest_digest), pulled.index_digest, pulled.layers, 0)?;
+    handle.commit()?;
+    Ok(serde_json::to_string_pretty(&ExportRootfsResult {
+        digest: pulled.digest,
+        path,
+    })?)
+}
+
+/// Seed the layer cache and state with every image in an OCI image layout
+pub fn import_images(args: ImportImagesArgs) -> Result<String> {
+    let mut handle = StateHandle::new()?;
+    let state = &mut handle.state;
+    let mut images = HashMap::new();
+    for (image, pulled) in import_oci_layout_images(args.oci_dir.as_std_path())? {
+        let platform = Platform {
+            architecture: pulled.config.architecture.clone(),
+            os: pulled.config.os.clone()
+        };
+        state.record_image(&image, &platform, &pulled.digest, Some(pulled.manifest_digest), pulled.index_digest, pulled.layers, 0)?;
+        images.insert(ApplicationState::tag_key(&image, &platform), pulled.digest);
+    }
+    handle.commit()?;
+    Ok(serde_json::to_string_pretty(&ImportImagesResult {
+        images
+    })?)
+}
+
+/// Clean all images no tag points at and all layers not associated with an image
+pub fn prune(args: PruneArgs) -> Result<String> {
+    let mut handle = StateHandle::new()?;
+    let state = &mut handle.state;
+
+    let plan = plan_prune(state)?;
+    if !args.dry_run {
+        apply_prune(state, &plan)?;
+        handle.commit()?;
     }
     Ok(serde_json::to_string_pretty(&PruneResult {
-        layers: inactive_layers
+        images: plan.images,
+        layers: plan.layers,
+        files: plan.files.iter().map(|file| file.to_string()).collect(),
+        reclaimed_bytes: plan.size,
+        dry_run: args.dry_run,
     })?)
 }
 
-/// List all drive images that currently exist 
-pub fn list_images() -> Result<String> {
-    let handle = StateHandle::new()?;
+/// List the stored images that match the filters
+pub fn list_images(args: ListImagesArgs) -> Result<String> {
+    let handle = StateHandle::read_only()?;
     let state = &handle.state;
-    
-    let result = serde_json::to_string_pretty(&ListImagesResult {
-        images: state.images.clone()
-    })?;
-    Ok(result)
+
+    let before = args.before.as_deref().map(|reference| created_time_of(state, reference)).transpose()?;
+    let since = args.since.as_deref().map(|reference| created_time_of(state, reference)).transpose()?;
+    let mut images = Vec::new();
+    for (digest, image) in &state.images {
+        let tags = state.get_image_tags(digest);
+        if args.dangling && !tags.is_empty() {
+            continue;
+        }
+        if args.os.as_ref().is_some_and(|os| *os != image.platform.os)
+            || args.architecture.as_ref().is_some_and(|architecture| *architecture != image.platform.architecture) {
+            continue;
+        }
+        // A dangling image only has the name it was stored under
+        let names = if tags.is_empty() {
+            vec![(image.name.clone(), None)]
+        } else {
+            tags.iter().map(|tag| ImageArg::from(tag.clone())).map(|image| (image.name, image.digest.is_none().then_some(image.tag))).collect()
+        };
+        let matches_reference = names.iter().any(|(name, tag)| {
+            args.name.as_ref().is_none_or(|pattern| glob_match(pattern, name))
+                && args.tag.as_ref().is_none_or(|wanted| Some(wanted) == tag.as_ref())
+        });
+        if !matches_reference {
+            continue;
+        }
+        let created = read_image_config(digest).and_then(|config| config.created);
+        let created_time = created.as_deref().and_then(parse_rfc3339);
+        if before.is_some_and(|before| created_time.is_none_or(|created| created >= before))
+            || since.is_some_and(|since| created_time.is_none_or(|created| created <= since)) {
+            continue;
+        }
+        let drive_images = drive_image_files(digest)?
+            .into_iter()
+            .filter(|path| path.extension() != Some("json"))
+            .map(|path| path.to_string())
+            .collect();
+        images.push((created_time, ImageEntry {
+            digest: digest.clone(),
+            tags,
+            name: image.name.clone(),
+            platform: image.platform.clone(),
+            created,
+            size: image.size,
+            layers: image.layers.clone(),
+            manifest_digest: image.manifest_digest.clone(),
+            last_used_at: image.last_used_at,
+            drive_images,
+        }));
+    }
+    images.sort_by(|(a_created, a), (b_created, b)| {
+        let order = match args.sort {
+            ImageSortKey::Name => a.tags.first().unwrap_or(&a.name).cmp(b.tags.first().unwrap_or(&b.name)),
+            ImageSortKey::Created => a_created.cmp(b_created),
+            ImageSortKey::Size => a.size.cmp(&b.size),
+            ImageSortKey::LastUsed => a.last_used_at.cmp(&b.last_used_at),
+        };
+        order.then_with(|| a.digest.cmp(&b.digest))
+    });
+    if args.reverse {
+        images.reverse();
+    }
+    let images = images.into_iter().map(|(_, image)| image).collect::<Vec<ImageEntry>>();
+    if args.quiet {
+        return Ok(images.iter().map(|image| image.digest.as_str()).collect::<Vec<&str>>().join("\n"));
+    }
+    Ok(serde_json::to_string_pretty(&ListImagesResult {
+        images
+    })?)
+}
+
+/// The stored config of an image, None if it wasn't kept
+fn read_image_config(digest: &str) -> Option<ImageConfig> {
+    serde_json::from_slice(&read_stored_blob(digest).ok()?).ok()
+}
+
+/// When the image a name:tag or digest refers to was created, for --before and --since
+fn created_time_of(state: &ApplicationState, reference: &str) -> Result<(i64, u32)> {
+    let digest = if state.images.contains_key(reference) {
+        reference.to_string()
+    } else {
+        let image = ImageArg::from(reference.to_string());
+        let tag = image.tagged_reference();
+        let mut digests = state.tagged_images.iter()
+            .filter(|(key, _)| key.rsplit_once('-').map(|(tagged, _)| tagged) == Some(tag.as_str()))
+            .map(|(_, digest)| digest)
+            .collect::<Vec<&String>>();
+        digests.sort();
+        digests.first().map(|digest| digest.to_string()).context(format!("Image {} not found", reference))?
+    };
+    read_image_config(&digest)
+        .and_then(|config| config.created)
+        .as_deref()
+        .and_then(parse_rfc3339)
+        .context(format!("Image {} has no creation time", reference))
+}
+
+/// Point another name:tag at a stored image
+pub fn tag_image(args: TagImageArgs) -> Result<String> {
+    let mut handle = StateHandle::new()?;
+    let state = &mut handle.state;
+
+    if args.target.transport.is_some() {
+        bail!("{} is a local archive or layout, not a name:tag", args.target);
+    }
+    let digest = if state.images.contains_key(&args.source) {
+        args.source.clone()
+    } else {
+        let source = ImageArg::from(args.source.clone());
+        let platform = Platform {
+            architecture: args.architecture,
+            os: args.os
+        };
+        state.get_stored_image_digest(&source, &platform)
+            .context(format!("Image {} not found", args.source))?
+    };
+    // The tag is for the platform of the image rather than the one given for the source
+    let platform = state.images.get(&digest).context(format!("Expected image {} to exist", digest))?.platform.clone();
+    let previous_digest = state.get_stored_image_digest(&args.target, &platform)
+        .filter(|previous| *previous != digest);
+    state.set_stored_image_digest(&args.target, &platform, digest.clone());
+    handle.commit()?;
+    Ok(serde_json::to_string_pretty(&TagImageResult {
+        digest,
+        tag: ApplicationState::tag_key(&args.target, &platform),
+        previous_digest,
+    })?)
+}
+
+/// Remove a name:tag, leaving the image it pointed at for prune when nothing else points at it
+pub fn untag_image(args: UntagImageArgs) -> Result<String> {
+    let mut handle = StateHandle::new()?;
+    let state = &mut handle.state;
+
+    let platform = Platform {
+        architecture: args.architecture,
+        os: args.os
+    };
+    let tag = ApplicationState::tag_key(&args.image, &platform);
+    let digest = state.tagged_images.remove(&tag).context(format!("Tag {} not found", tag))?;
+    handle.commit()?;
+    Ok(serde_json::to_string_pretty(&UntagImageResult {
+        digest,
+        tag,
+    })?)
 }
 
 pub fn remove_image(args: RemoveImageArgs) -> Result<String> {
     let mut handle = StateHandle::new()?;
     let state = &mut handle.state;
 
-    let base_folder = std::env::current_dir()?.join("data");
     let platform = Platform {
         architecture: args.architecture.unwrap_or(String::from("amd64")),
         os: args.os.unwrap_or(String::from("linux"))
     };
-    let digest = state.get_stored_image_digest(&args.image.name, &args.image.tag, &platform);
-    let digest = digest.context(format!("Digest not found for image {}:{}", args.image.name, args.image.tag))?;
-    let _ = fs::remove_file(base_folder.join(format!("images/{}.img", digest)));
-    let tagged_name = format!(
-        "{}:{}-{}:{}",
-        args.image.name, args.image.tag,
-        platform.os, platform.architecture
-    );
-    state.tagged_images.remove(&tagged_name);
-    state.images.remove(&digest).context("Image not found")?;
-    let active_digests: Vec<String> = state.images
-        .iter()
-        .map(|a|{a.1.layers.clone()})
-        .flatten()
-        .collect();
+    let digest = state.get_stored_image_digest(&args.image, &platform);
+    let digest = digest.context(format!("Digest not found for image {}", args.image.tagged_reference()))?;
+    state.tagged_images.remove(&ApplicationState::tag_key(&args.image, &platform));
     let mut removed_layers = Vec::<String>::new();
-    if args.prune {
-        let inactive_layers: Vec<String> = state.layers.iter().filter(|l|{!active_digests.contains(l)}).cloned().collect();
-        for layer in &inactive_layers {
-            let path = base_folder.join(format!("layers/{}.tgz", layer));
-            if path.exists() {
-                fs::remove_file(path)?;
-                removed_layers.push(layer.clone());
+    // Other tags can point at the same image, which is only removed along with the last of them
+    if !state.tagged_images.values().any(|tagged| *tagged == digest) {
+        let image = state.remove_image(&digest).context("Image not found")?;
+        for file in drive_image_files(&digest)? {
+            remove_file_if_exists(&file)?;
+        }
+        let blobs_path = get_blobs_path()?;
+        // The index of a multi-platform image is kept while an image of another platform uses it
+        for blob in std::iter::once(&digest).chain(image.manifest_digest.as_ref()).chain(image.index_digest.as_ref()) {
+            let in_use = state.images.iter().any(|(other_digest, other)| {
+                other_digest == blob || other.manifest_digest.as_ref() == Some(blob) || other.index_digest.as_ref() == Some(blob)
+            });
+            if !in_use {
+                remove_file_if_exists(&blobs_path.join(blob))?;
+            }
+        }
+        if args.prune {
+            let layers_path = get_layers_compressed_path()?;
+            for layer in image.layers {
+                if state.images.values().any(|other| other.layers.contains(&layer)) || removed_layers.contains(&layer) {
+                    continue;
+                }
+                remove_file_if_exists(&layers_path.join(format!("{layer}.tgz")))?;
+                state.layers.remove(&layer);
+                removed_layers.push(layer);
             }
         }
     }
+    handle.commit()?;
     Ok(serde_json::to_string_pretty(&RemoveImageResult {
         digest,
         removed_layers
     })?)
 }
 
-/// Completely clean all images and layers stored
-pub fn purge() {
+/// Report the disk space used by layers, drive images and temporary files, and by each image
+pub fn disk_usage() -> Result<String> {
+    let handle = StateHandle::read_only()?;
+    let state = &handle.state;
 
+    let layers = folder_usage(get_layers_compressed_path()?)?;
+    let images = folder_usage(get_images_path()?)?;
+    let blobs = folder_usage(get_blobs_path()?)?;
+    let temp = folder_usage(get_temp_path()?)?;
+    let mut image_usage = Vec::new();
+    for (digest, image) in &state.images {
+        let mut drive_images_size = 0;
+        for file in drive_image_files(digest)? {
+            drive_images_size += get_allocated_size(file.as_std_path())?;
+        }
+        let (mut shared_layers_size, mut unique_layers_size) = (0, 0);
+        // Evicted layers aren't tracked anymore, so they take up nothing
+        for layer in image.layers.iter().filter_map(|layer| state.layers.get(layer)) {
+            if layer.images.iter().any(|other| other != digest) {
+                shared_layers_size += layer.size;
+            } else {
+                unique_layers_size += layer.size;
+            }
+        }
+        image_usage.push(ImageUsage {
+            digest: digest.clone(),
+            tags: state.get_image_tags(digest),
+            drive_images_size,
+            layers_size: shared_layers_size + unique_layers_size,
+            shared_layers_size,
+            unique_layers_size,
+        });
+    }
+    image_usage.sort_by(|a, b| b.drive_images_size.cmp(&a.drive_images_size).then_with(|| a.digest.cmp(&b.digest)));
+    Ok(serde_json::to_string_pretty(&DiskUsageResult {
+        total_size: layers.size + images.size + blobs.size + temp.size,
+        reclaimable_bytes: plan_prune(state)?.size,
+        layers,
+        images,
+        blobs,
+        temp,
+        image_usage,
+    })?)
+}
+
+fn folder_usage(path: Utf8PathBuf) -> Result<FolderUsage> {
+    Ok(FolderUsage {
+        size: dir_size(&path)?,
+        entries: list_entries(&path)?.len(),
+        path: path.to_string(),
+    })
 }
 
+/// Completely clean all images and layers stored
+pub fn purge(args: PurgeArgs) -> Result<String> {
+    let mut handle = StateHandle::new()?;
+    if !args.force && !confirm(&format!("Remove all images, layers and state in {}?", get_base_path()?))? {
+        bail!("Purge was cancelled");
+    }
+    let mut removed = Vec::new();
+    let mut reclaimed_bytes = 0;
+    for path in [get_images_path()?, get_layers_compressed_path()?, get_layers_path()?, get_blobs_path()?, get_temp_path()?] {
+        if path.exists() {
+            reclaimed_bytes += dir_size(&path)?;
+            fs::remove_dir_all(&path)?;
+            removed.push(path.to_string());
+        }
+    }
+    // Corrupt state files were moved aside with the time they were found at
+    let state_path = get_app_state_path()?;
+    let corrupt_prefix = format!("{}.corrupt", state_path.file_name().context("The state path has no file name")?);
+    let state_dir = state_path.parent().context("The state path has no parent")?;
+    for entry in state_dir.read_dir_utf8()? {
+        let entry = entry?;
+        if entry.file_name().starts_with(&corrupt_prefix) && entry.file_type()?.is_file() {
+            reclaimed_bytes += get_allocated_size(entry.path().as_std_path())?;
+            remove_file_if_exists(entry.path())?;
+            removed.push(entry.path().to_string());
+        }
+    }
+    handle.state = ApplicationState::new();
+    handle.commit()?;
+    Ok(serde_json::to_string_pretty(&PurgeResult {
+        removed,
+        reclaimed_bytes,
+    })?)
+}
+
+/// Asks a yes or no question on the terminal, anything but yes is a no.
+/// Without a terminal there's no one to ask, so it fails instead of waiting
+fn confirm(question: &str) -> Result<bool> {
+    if !io::stdin().is_terminal() {
+        bail!("{} Pass --force to confirm when not running from a terminal", question);
+    }
+    eprint!("{question} [y/N] ");
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes"))
+}
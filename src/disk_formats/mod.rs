@@ -0,0 +1,114 @@
+use std::{fs::File, io::Read, path::Path, time::{SystemTime, UNIX_EPOCH}};
+
+use anyhow::{bail, Result};
+use sha2::{Digest, Sha256};
+
+use crate::{models::input_models::OutputFormat, utils::{copy_sparse, random_bytes, Reproducibility}};
+
+pub mod qcow2;
+pub mod vhd;
+pub mod vhdx;
+pub mod vmdk;
+
+/// Identifiers and timestamps written into the headers of formats that carry them
+#[derive(Debug, Clone)]
+pub struct ImageIdentity {
+    /// Unique id of the virtual disk
+    pub unique_id: [u8; 16],
+    /// Creation time as a unix timestamp
+    pub timestamp: u64,
+}
+
+impl ImageIdentity {
+    /// Reproducible builds derive the identity from the image digest and SOURCE_DATE_EPOCH, so it's
+    /// the same on every build on purpose. Otherwise the id is random and the time is the current one
+    pub fn new(digest: &str, reproducibility: Option<&Reproducibility>) -> Result<ImageIdentity> {
+        let (unique_id, timestamp) = match reproducibility {
+            Some(reproducibility) => (to_guid(&Sha256::digest(digest.as_bytes())), reproducibility.epoch),
+            None => (to_guid(&random_bytes::<16>()?), SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs()),
+        };
+        Ok(ImageIdentity { unique_id, timestamp })
+    }
+
+    /// Derives another id from the unique id for formats that need several
+    pub fn derive_id(&self, purpose: &[u8]) -> [u8; 16] {
+        let mut hasher = Sha256::new();
+        hasher.update(self.unique_id);
+        hasher.update(purpose);
+        to_guid(&hasher.finalize())
+    }
+}
+
+/// Writes a raw disk image out in another format. Raw images are copied sparsely
+pub fn convert_raw_image(raw_path: &Path, output_path: &Path, format: OutputFormat, compress: bool, identity: &ImageIdentity) -> Result<()> {
+    match format {
+        OutputFormat::Raw => copy_sparse(raw_path, output_path)?,
+        OutputFormat::Qcow2 => qcow2::write_qcow2(raw_path, output_path, compress)?,
+        OutputFormat::Vhd => vhd::write_vhd(raw_path, output_path, identity)?,
+        OutputFormat::Vhdx => vhdx::write_vhdx(raw_path, output_path, identity)?,
+        OutputFormat::Vmdk => vmdk::write_vmdk(raw_path, output_path, identity)?,
+        OutputFormat::Initramfs => bail!("An initramfs is created from the layers, not a disk image"),
+    }
+    Ok(())
+}
+
+/// Takes 16 bytes of a hash as a random (version 4) guid
+pub fn to_guid(hash: &[u8]) -> [u8; 16] {
+    let mut guid = [0u8; 16];
+    guid.copy_from_slice(&hash[0..16]);
+    guid[7] = (guid[7] & 0x0f) | 0x40;
+    guid[8] = (guid[8] & 0x3f) | 0x80;
+    guid
+}
+
+/// Converts a guid in its text form to the mixed endian layout Microsoft formats store
+pub fn guid_to_bytes(guid: &str) -> [u8; 16] {
+    let hex = guid.replace('-', "");
+    let mut bytes = [0u8; 16];
+    for (index, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[index * 2..index * 2 + 2], 16).unwrap_or_default();
+    }
+    bytes[0..4].reverse();
+    bytes[4..6].reverse();
+    bytes[6..8].reverse();
+    bytes
+}
+
+/// Reads until the buffer is full or the file ends
+fn read_full(file: &mut File, buffer: &mut [u8]) -> Result<()> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        let read = file.read(&mut buffer[filled..])?;
+        if read == 0 {
+            break;
+        }
+        filled += read;
+    }
+    Ok(())
+}
+
+/// A fixed identity so the format tests can check the ids written
+#[cfg(test)]
+pub fn test_identity() -> ImageIdentity {
+    ImageIdentity { unique_id: to_guid(&Sha256::digest(b"whaledrive")), timestamp: 1_700_000_000 }
+}
+
+/// Writes a raw image made of the given chunks at their offsets and converts it,
+/// returning the raw image and what it was converted to
+#[cfg(test)]
+pub fn convert_test_image(size: u64, chunks: &[(u64, &[u8])], convert: impl Fn(&Path, &Path) -> Result<()>) -> (Vec<u8>, Vec<u8>) {
+    use std::{fs, io::{Seek, SeekFrom, Write}};
+
+    let dir = tempfile::tempdir().unwrap();
+    let raw_path = dir.path().join("disk.img");
+    let output_path = dir.path().join("disk.out");
+    let mut raw = File::create(&raw_path).unwrap();
+    raw.set_len(size).unwrap();
+    for (offset, data) in chunks {
+        raw.seek(SeekFrom::Start(*offset)).unwrap();
+        raw.write_all(data).unwrap();
+    }
+    drop(raw);
+    convert(&raw_path, &output_path).unwrap();
+    (fs::read(raw_path).unwrap(), fs::read(output_path).unwrap())
+}
@@ -0,0 +1,386 @@
+use std::{collections::BTreeMap, fs::File, io::{BufWriter, Seek, SeekFrom, Write}, path::Path};
+
+use anyhow::Result;
+use flate2::{Compress, Compression, FlushCompress, Status};
+
+use super::read_full;
+
+const CLUSTER_BITS: u32 = 16;
+const CLUSTER_SIZE: u64 = 1 << CLUSTER_BITS;
+const HEADER_LENGTH: u32 = 104;
+/// Refcounts are 16 bits wide, which is what qemu uses by default
+const REFCOUNT_ORDER: u32 = 4;
+const REFCOUNTS_PER_BLOCK: u64 = CLUSTER_SIZE / 2;
+const L2_ENTRIES: u64 = CLUSTER_SIZE / 8;
+const OFLAG_COPIED: u64 = 1 << 63;
+const OFLAG_COMPRESSED: u64 = 1 << 62;
+/// Bit where the sector count of a compressed cluster descriptor starts
+const COMPRESSED_SECTORS_SHIFT: u32 = 62 - (CLUSTER_BITS - 8);
+/// qemu inflates compressed clusters with a 4 KiB window
+const DEFLATE_WINDOW: usize = 4096;
+
+/// Converts a raw disk image to a qcow2 (version 3) image. Clusters that are
+/// entirely zero are left unallocated and read back as zeros
+pub fn write_qcow2(raw_path: &Path, qcow2_path: &Path, compress: bool) -> Result<()> {
+    let mut raw = File::open(raw_path)?;
+    let virtual_size = raw.metadata()?.len();
+    let guest_clusters = virtual_size.div_ceil(CLUSTER_SIZE);
+    let l1_size = guest_clusters.div_ceil(L2_ENTRIES).max(1);
+    let l1_clusters = (l1_size * 8).div_ceil(CLUSTER_SIZE);
+
+    let mut output = BufWriter::new(File::create(qcow2_path)?);
+    // Refcount of every host cluster, the header and L1 table come first
+    let mut refcounts = vec![1u16; 1 + l1_clusters as usize];
+    // L2 tables by their L1 index
+    let mut l2_tables: BTreeMap<u64, Vec<u64>> = BTreeMap::new();
+    let mut host_offset = (1 + l1_clusters) * CLUSTER_SIZE;
+    output.seek(SeekFrom::Start(host_offset))?;
+
+    let mut cluster = vec![0u8; CLUSTER_SIZE as usize];
+    for guest_cluster in 0..guest_clusters {
+        cluster.fill(0);
+        read_full(&mut raw, &mut cluster)?;
+        if cluster.iter().all(|b| *b == 0) {
+            continue;
+        }
+        let compressed = if compress { deflate_cluster(&cluster)? } else { None };
+        let entry = match compressed {
+            Some(data) => {
+                // Compressed clusters are packed together but never straddle a host cluster
+                let used = host_offset % CLUSTER_SIZE;
+                if used == 0 || used + data.len() as u64 > CLUSTER_SIZE {
+                    host_offset = host_offset.div_ceil(CLUSTER_SIZE) * CLUSTER_SIZE;
+                    output.seek(SeekFrom::Start(host_offset))?;
+                    refcounts.push(0);
+                }
+                let host_cluster = (host_offset / CLUSTER_SIZE) as usize;
+                refcounts[host_cluster] += 1;
+                let extra_sectors = ((host_offset + data.len() as u64 - 1) >> 9) - (host_offset >> 9);
+                let entry = OFLAG_COMPRESSED | (extra_sectors << COMPRESSED_SECTORS_SHIFT) | host_offset;
+                output.write_all(&data)?;
+                host_offset += data.len() as u64;
+                entry
+            },
+            None => {
+                host_offset = host_offset.div_ceil(CLUSTER_SIZE) * CLUSTER_SIZE;
+                output.seek(SeekFrom::Start(host_offset))?;
+                refcounts.resize((host_offset / CLUSTER_SIZE) as usize, 0);
+                refcounts.push(1);
+                let entry = OFLAG_COPIED | host_offset;
+                output.write_all(&cluster)?;
+                host_offset += CLUSTER_SIZE;
+                entry
+            },
+        };
+        l2_tables.entry(guest_cluster / L2_ENTRIES)
+            .or_insert_with(|| vec![0; L2_ENTRIES as usize])[(guest_cluster % L2_ENTRIES) as usize] = entry;
+    }
+    host_offset = host_offset.div_ceil(CLUSTER_SIZE) * CLUSTER_SIZE;
+    refcounts.resize((host_offset / CLUSTER_SIZE) as usize, 0);
+
+    // L2 tables follow the data
+    let mut l1_table = vec![0u64; l1_size as usize];
+    output.seek(SeekFrom::Start(host_offset))?;
+    for (l1_index, table) in &l2_tables {
+        l1_table[*l1_index as usize] = OFLAG_COPIED | host_offset;
+        for entry in table {
+            output.write_all(&entry.to_be_bytes())?;
+        }
+        refcounts.push(1);
+        host_offset += CLUSTER_SIZE;
+    }
+
+    // The refcount blocks and table go last and have to count themselves
+    let mut refcount_blocks = 1;
+    let mut refcount_table_clusters = 1;
+    loop {
+        let total = refcounts.len() as u64 + refcount_blocks + refcount_table_clusters;
+        let needed_blocks = total.div_ceil(REFCOUNTS_PER_BLOCK);
+        let needed_table_clusters = (needed_blocks * 8).div_ceil(CLUSTER_SIZE);
+        if needed_blocks == refcount_blocks && needed_table_clusters == refcount_table_clusters {
+            break;
+        }
+        refcount_blocks = needed_blocks;
+        refcount_table_clusters = needed_table_clusters;
+    }
+    let refcount_blocks_offset = host_offset;
+    let refcount_table_offset = refcount_blocks_offset + refcount_blocks * CLUSTER_SIZE;
+    refcounts.resize(refcounts.len() + (refcount_blocks + refcount_table_clusters) as usize, 1);
+    refcounts.resize((refcount_blocks * REFCOUNTS_PER_BLOCK) as usize, 0);
+    for refcount in &refcounts {
+        output.write_all(&refcount.to_be_bytes())?;
+    }
+    let mut refcount_table = vec![0u8; (refcount_table_clusters * CLUSTER_SIZE) as usize];
+    for block in 0..refcount_blocks {
+        let offset = refcount_blocks_offset + block * CLUSTER_SIZE;
+        refcount_table[(block * 8) as usize..(block * 8 + 8) as usize].copy_from_slice(&offset.to_be_bytes());
+    }
+    output.write_all(&refcount_table)?;
+
+    output.seek(SeekFrom::Start(0))?;
+    let mut header = Vec::with_capacity(HEADER_LENGTH as usize);
+    header.extend_from_slice(b"QFI\xfb");
+    header.extend_from_slice(&3u32.to_be_bytes());
+    header.extend_from_slice(&0u64.to_be_bytes()); // backing file offset
+    header.extend_from_slice(&0u32.to_be_bytes()); // backing file size
+    header.extend_from_slice(&CLUSTER_BITS.to_be_bytes());
+    header.extend_from_slice(&virtual_size.to_be_bytes());
+    header.extend_from_slice(&0u32.to_be_bytes()); // no encryption
+    header.extend_from_slice(&(l1_size as u32).to_be_bytes());
+    header.extend_from_slice(&CLUSTER_SIZE.to_be_bytes()); // l1 table offset
+    header.extend_from_slice(&refcount_table_offset.to_be_bytes());
+    header.extend_from_slice(&(refcount_table_clusters as u32).to_be_bytes());
+    header.extend_from_slice(&0u32.to_be_bytes()); // snapshot count
+    header.extend_from_slice(&0u64.to_be_bytes()); // snapshots offset
+    header.extend_from_slice(&0u64.to_be_bytes()); // incompatible features
+    header.extend_from_slice(&0u64.to_be_bytes()); // compatible features
+    header.extend_from_slice(&0u64.to_be_bytes()); // autoclear features
+    header.extend_from_slice(&REFCOUNT_ORDER.to_be_bytes());
+    header.extend_from_slice(&HEADER_LENGTH.to_be_bytes());
+    // The cluster is zero past the header, which doubles as the end of the header extensions
+    output.write_all(&header)?;
+    output.seek(SeekFrom::Start(CLUSTER_SIZE))?;
+    for entry in &l1_table {
+        output.write_all(&entry.to_be_bytes())?;
+    }
+    output.flush()?;
+    Ok(())
+}
+
+/// Compresses a cluster as raw deflate, returning None if it doesn't get smaller.
+/// The dictionary is reset every 4 KiB so no back reference reaches further
+/// than the window qemu decompresses with
+fn deflate_cluster(cluster: &[u8]) -> Result<Option<Vec<u8>>> {
+    let mut compressor = Compress::new(Compression::default(), false);
+    let mut output = Vec::with_capacity(cluster.len());
+    let chunks = cluster.chunks(DEFLATE_WINDOW).collect::<Vec<&[u8]>>();
+    for (index, chunk) in chunks.iter().enumerate() {
+        let flush = if index == chunks.len() - 1 { FlushCompress::Finish } else { FlushCompress::Full };
+        let start = compressor.total_in();
+        loop {
+            let consumed = (compressor.total_in() - start) as usize;
+            output.reserve(DEFLATE_WINDOW * 2);
+            let status = compressor.compress_vec(&chunk[consumed..], &mut output, flush)?;
+            let all_consumed = (compressor.total_in() - start) as usize == chunk.len();
+            // A flush is complete once all input is in and the output had room to spare
+            if status == Status::StreamEnd || (flush == FlushCompress::Full && all_consumed && output.len() < output.capacity()) {
+                break;
+            }
+        }
+        if output.len() as u64 >= CLUSTER_SIZE {
+            return Ok(None);
+        }
+    }
+    Ok(Some(output))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use flate2::{Decompress, FlushDecompress};
+    use sha2::{Digest, Sha256};
+
+    use super::*;
+
+    fn be_u32(data: &[u8], offset: u64) -> u32 {
+        u32::from_be_bytes(data[offset as usize..offset as usize + 4].try_into().unwrap())
+    }
+
+    fn be_u64(data: &[u8], offset: u64) -> u64 {
+        u64::from_be_bytes(data[offset as usize..offset as usize + 8].try_into().unwrap())
+    }
+
+    /// Bytes that don't compress, so their cluster is stored as is
+    fn noise(len: usize, seed: u8) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(len);
+        let mut block = Sha256::digest([seed]).to_vec();
+        while bytes.len() < len {
+            bytes.extend_from_slice(&block);
+            block = Sha256::digest(&block).to_vec();
+        }
+        bytes.truncate(len);
+        bytes
+    }
+
+    /// A raw image with a cluster of noise, a zero cluster, a cluster of text and a partial last cluster
+    fn raw_image() -> Vec<u8> {
+        let cluster = CLUSTER_SIZE as usize;
+        let mut raw = noise(cluster, 1);
+        raw.resize(cluster * 2, 0);
+        raw.extend(b"whaledrive ".iter().cycle().take(cluster));
+        raw.extend(noise(1000, 2));
+        raw
+    }
+
+    fn convert(raw: &[u8], compress: bool) -> Vec<u8> {
+        let dir = tempfile::tempdir().unwrap();
+        let raw_path = dir.path().join("disk.img");
+        let qcow2_path = dir.path().join("disk.qcow2");
+        fs::write(&raw_path, raw).unwrap();
+        write_qcow2(&raw_path, &qcow2_path, compress).unwrap();
+        fs::read(qcow2_path).unwrap()
+    }
+
+    /// The L2 entry of every guest cluster
+    fn l2_entries(qcow2: &[u8]) -> Vec<u64> {
+        let virtual_size = be_u64(qcow2, 24);
+        let l1_offset = be_u64(qcow2, 40);
+        (0..virtual_size.div_ceil(CLUSTER_SIZE)).map(|guest_cluster| {
+            let l1_entry = be_u64(qcow2, l1_offset + guest_cluster / L2_ENTRIES * 8);
+            match l1_entry & !OFLAG_COPIED {
+                0 => 0,
+                l2_offset => be_u64(qcow2, l2_offset + guest_cluster % L2_ENTRIES * 8),
+            }
+        }).collect()
+    }
+
+    /// Reads back the guest data the way qemu does
+    fn read_guest(qcow2: &[u8]) -> Vec<u8> {
+        let virtual_size = be_u64(qcow2, 24);
+        let mut guest = Vec::new();
+        for entry in l2_entries(qcow2) {
+            let mut cluster = vec![0u8; CLUSTER_SIZE as usize];
+            if entry & OFLAG_COMPRESSED != 0 {
+                let offset = entry & ((1 << COMPRESSED_SECTORS_SHIFT) - 1);
+                let sectors = ((entry & !OFLAG_COMPRESSED) >> COMPRESSED_SECTORS_SHIFT) + 1;
+                // The data ends somewhere in the last sector, which is all qemu reads up to
+                let end = ((offset >> 9) + sectors) << 9;
+                let data = &qcow2[offset as usize..(end as usize).min(qcow2.len())];
+                let mut decompressor = Decompress::new(false);
+                decompressor.decompress(data, &mut cluster, FlushDecompress::Finish).unwrap();
+                assert_eq!(decompressor.total_out(), CLUSTER_SIZE);
+            } else if entry != 0 {
+                assert_ne!(entry & OFLAG_COPIED, 0);
+                let offset = (entry & !OFLAG_COPIED) as usize;
+                cluster.copy_from_slice(&qcow2[offset..offset + CLUSTER_SIZE as usize]);
+            }
+            guest.extend_from_slice(&cluster);
+        }
+        guest.truncate(virtual_size as usize);
+        guest
+    }
+
+    /// The refcount of every host cluster, as stored in the refcount blocks
+    fn stored_refcounts(qcow2: &[u8]) -> Vec<u16> {
+        let table_offset = be_u64(qcow2, 48);
+        let table_clusters = be_u32(qcow2, 56) as u64;
+        let mut refcounts = Vec::new();
+        for index in 0..table_clusters * CLUSTER_SIZE / 8 {
+            let block_offset = be_u64(qcow2, table_offset + index * 8);
+            if block_offset == 0 {
+                break;
+            }
+            refcounts.extend((0..REFCOUNTS_PER_BLOCK).map(|i| u16::from_be_bytes(qcow2[(block_offset + i * 2) as usize..(block_offset + i * 2 + 2) as usize].try_into().unwrap())));
+        }
+        refcounts
+    }
+
+    /// Counts the references to every host cluster from the header, tables and data
+    fn expected_refcounts(qcow2: &[u8]) -> Vec<u16> {
+        let mut refcounts = vec![0u16; qcow2.len().div_ceil(CLUSTER_SIZE as usize)];
+        let mut count = |offset: u64, len: u64| {
+            for cluster in offset / CLUSTER_SIZE..(offset + len).div_ceil(CLUSTER_SIZE) {
+                refcounts[cluster as usize] += 1;
+            }
+        };
+        count(0, CLUSTER_SIZE);
+        count(be_u64(qcow2, 40), be_u32(qcow2, 36) as u64 * 8);
+        let table_offset = be_u64(qcow2, 48);
+        let table_clusters = be_u32(qcow2, 56) as u64;
+        count(table_offset, table_clusters * CLUSTER_SIZE);
+        for index in 0..table_clusters * CLUSTER_SIZE / 8 {
+            match be_u64(qcow2, table_offset + index * 8) {
+                0 => break,
+                block => count(block, CLUSTER_SIZE),
+            }
+        }
+        for index in 0..be_u32(qcow2, 36) as u64 {
+            match be_u64(qcow2, be_u64(qcow2, 40) + index * 8) & !OFLAG_COPIED {
+                0 => {},
+                l2_offset => count(l2_offset, CLUSTER_SIZE),
+            }
+        }
+        for entry in l2_entries(qcow2) {
+            if entry & OFLAG_COMPRESSED != 0 {
+                // A compressed cluster counts once for each host cluster it has data in
+                count(entry & ((1 << COMPRESSED_SECTORS_SHIFT) - 1), 1);
+            } else if entry != 0 {
+                count(entry & !OFLAG_COPIED, CLUSTER_SIZE);
+            }
+        }
+        refcounts
+    }
+
+    #[test]
+    fn writes_a_version_3_header() {
+        let raw = raw_image();
+        let qcow2 = convert(&raw, false);
+        assert_eq!(&qcow2[0..4], b"QFI\xfb");
+        assert_eq!(be_u32(&qcow2, 4), 3);
+        assert_eq!(be_u32(&qcow2, 20), CLUSTER_BITS);
+        assert_eq!(be_u64(&qcow2, 24), raw.len() as u64);
+        assert_eq!(be_u32(&qcow2, 32), 0);
+        assert_eq!(be_u32(&qcow2, 36), 1);
+        assert_eq!(be_u64(&qcow2, 40), CLUSTER_SIZE);
+        assert_eq!(be_u64(&qcow2, 72), 0);
+        assert_eq!(be_u32(&qcow2, 96), REFCOUNT_ORDER);
+        assert_eq!(be_u32(&qcow2, 100), HEADER_LENGTH);
+        // No header extensions
+        assert_eq!(be_u32(&qcow2, HEADER_LENGTH as u64), 0);
+    }
+
+    #[test]
+    fn maps_clusters_through_the_l1_and_l2_tables() {
+        let raw = raw_image();
+        let qcow2 = convert(&raw, false);
+        let entries = l2_entries(&qcow2);
+        assert_eq!(entries.len(), 4);
+        // The zero cluster isn't allocated and nothing is compressed
+        assert_eq!(entries[1], 0);
+        assert!(entries.iter().all(|entry| entry & OFLAG_COMPRESSED == 0));
+        assert!(entries.iter().filter(|entry| **entry != 0).all(|entry| (entry & !OFLAG_COPIED).is_multiple_of(CLUSTER_SIZE)));
+        assert_eq!(read_guest(&qcow2), raw);
+    }
+
+    #[test]
+    fn counts_every_host_cluster() {
+        for compress in [false, true] {
+            let qcow2 = convert(&raw_image(), compress);
+            let stored = stored_refcounts(&qcow2);
+            let expected = expected_refcounts(&qcow2);
+            assert!(stored.len() >= expected.len());
+            assert_eq!(&stored[..expected.len()], &expected[..]);
+            assert!(stored[expected.len()..].iter().all(|refcount| *refcount == 0));
+        }
+    }
+
+    #[test]
+    fn compresses_clusters_that_shrink() {
+        let raw = raw_image();
+        let qcow2 = convert(&raw, true);
+        let entries = l2_entries(&qcow2);
+        // Noise doesn't shrink so it's stored as is, the text and the mostly zero last cluster do
+        assert_eq!(entries[0] & OFLAG_COMPRESSED, 0);
+        assert_ne!(entries[0] & OFLAG_COPIED, 0);
+        assert_eq!(entries[1], 0);
+        for entry in &entries[2..] {
+            assert_ne!(entry & OFLAG_COMPRESSED, 0);
+            assert_eq!(entry & OFLAG_COPIED, 0);
+        }
+        assert!(qcow2.len() < convert(&raw, false).len());
+        assert_eq!(read_guest(&qcow2), raw);
+    }
+
+    #[test]
+    fn describes_compressed_clusters_by_sector() {
+        let qcow2 = convert(&raw_image(), true);
+        for entry in l2_entries(&qcow2).into_iter().filter(|entry| entry & OFLAG_COMPRESSED != 0) {
+            let offset = entry & ((1 << COMPRESSED_SECTORS_SHIFT) - 1);
+            let extra_sectors = (entry & !OFLAG_COMPRESSED) >> COMPRESSED_SECTORS_SHIFT;
+            // Compressed data never crosses into the next host cluster
+            let last_sector = (offset >> 9) + extra_sectors;
+            assert_eq!(offset / CLUSTER_SIZE, (last_sector << 9) / CLUSTER_SIZE);
+        }
+    }
+}
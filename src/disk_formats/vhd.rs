@@ -0,0 +1,145 @@
+use std::{fs::OpenOptions, io::{Seek, SeekFrom, Write}, path::Path};
+
+use anyhow::Result;
+
+use crate::utils::copy_sparse;
+
+use super::ImageIdentity;
+
+const FOOTER_SIZE: usize = 512;
+/// Azure only accepts fixed disks whose size is a whole number of megabytes
+const SIZE_ALIGNMENT: u64 = 1024 * 1024;
+const FIXED_DISK_TYPE: u32 = 2;
+/// Seconds between the unix epoch and the VHD epoch of 2000-01-01
+const VHD_EPOCH_OFFSET: u64 = 946_684_800;
+
+/// Converts a raw disk image to a fixed VHD, which is the raw data
+/// padded to a whole megabyte followed by a footer
+pub fn write_vhd(raw_path: &Path, vhd_path: &Path, identity: &ImageIdentity) -> Result<()> {
+    copy_sparse(raw_path, vhd_path)?;
+    let mut output = OpenOptions::new().write(true).open(vhd_path)?;
+    let size = output.metadata()?.len().div_ceil(SIZE_ALIGNMENT) * SIZE_ALIGNMENT;
+    output.set_len(size)?;
+    output.seek(SeekFrom::Start(size))?;
+    output.write_all(&create_footer(size, identity))?;
+    Ok(())
+}
+
+fn create_footer(size: u64, identity: &ImageIdentity) -> [u8; FOOTER_SIZE] {
+    let (cylinders, heads, sectors_per_track) = get_geometry(size);
+    let mut footer = [0u8; FOOTER_SIZE];
+    footer[0..8].copy_from_slice(b"conectix");
+    footer[8..12].copy_from_slice(&2u32.to_be_bytes()); // features, this one is always set
+    footer[12..16].copy_from_slice(&0x0001_0000u32.to_be_bytes()); // file format version
+    footer[16..24].copy_from_slice(&u64::MAX.to_be_bytes()); // fixed disks have no dynamic header
+    footer[24..28].copy_from_slice(&(identity.timestamp.saturating_sub(VHD_EPOCH_OFFSET) as u32).to_be_bytes());
+    footer[28..32].copy_from_slice(b"wdrv");
+    footer[32..36].copy_from_slice(&0x0001_0000u32.to_be_bytes()); // creator version
+    footer[36..40].copy_from_slice(b"Wi2k");
+    footer[40..48].copy_from_slice(&size.to_be_bytes()); // original size
+    footer[48..56].copy_from_slice(&size.to_be_bytes()); // current size
+    footer[56..58].copy_from_slice(&cylinders.to_be_bytes());
+    footer[58] = heads;
+    footer[59] = sectors_per_track;
+    footer[60..64].copy_from_slice(&FIXED_DISK_TYPE.to_be_bytes());
+    footer[68..84].copy_from_slice(&identity.unique_id);
+    let checksum = !footer.iter().fold(0u32, |sum, b| sum.wrapping_add(*b as u32));
+    footer[64..68].copy_from_slice(&checksum.to_be_bytes());
+    footer
+}
+
+/// The CHS geometry calculation from the VHD specification
+fn get_geometry(size: u64) -> (u16, u8, u8) {
+    let total_sectors = (size / 512).min(65535 * 16 * 255);
+    let (sectors_per_track, heads, cylinder_times_heads) = if total_sectors >= 65535 * 16 * 63 {
+        (255, 16, total_sectors / 255)
+    } else {
+        let mut sectors_per_track = 17;
+        let mut cylinder_times_heads = total_sectors / sectors_per_track;
+        let mut heads = cylinder_times_heads.div_ceil(1024).max(4);
+        if cylinder_times_heads >= heads * 1024 || heads > 16 {
+            sectors_per_track = 31;
+            heads = 16;
+            cylinder_times_heads = total_sectors / sectors_per_track;
+        }
+        if cylinder_times_heads >= heads * 1024 {
+            sectors_per_track = 63;
+            heads = 16;
+            cylinder_times_heads = total_sectors / sectors_per_track;
+        }
+        (sectors_per_track, heads, cylinder_times_heads)
+    };
+    ((cylinder_times_heads / heads) as u16, heads as u8, sectors_per_track as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::disk_formats::{convert_test_image, test_identity};
+
+    use super::*;
+
+    fn be_u32(data: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn be_u64(data: &[u8], offset: usize) -> u64 {
+        u64::from_be_bytes(data[offset..offset + 8].try_into().unwrap())
+    }
+
+    fn convert() -> (Vec<u8>, Vec<u8>) {
+        let identity = test_identity();
+        convert_test_image(1536 * 1024, &[(0, b"boot sector"), (1024 * 1024, b"data")], |raw, vhd| write_vhd(raw, vhd, &identity))
+    }
+
+    #[test]
+    fn pads_the_data_to_a_whole_megabyte() {
+        let (raw, vhd) = convert();
+        assert_eq!(vhd.len(), 2 * 1024 * 1024 + FOOTER_SIZE);
+        assert_eq!(&vhd[..raw.len()], &raw[..]);
+        assert!(vhd[raw.len()..2 * 1024 * 1024].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn writes_a_fixed_disk_footer() {
+        let (_, vhd) = convert();
+        let footer = &vhd[vhd.len() - FOOTER_SIZE..];
+        assert_eq!(&footer[0..8], b"conectix");
+        assert_eq!(be_u32(footer, 12), 0x0001_0000);
+        assert_eq!(be_u64(footer, 16), u64::MAX);
+        assert_eq!(be_u32(footer, 24) as u64, 1_700_000_000 - VHD_EPOCH_OFFSET);
+        assert_eq!(be_u64(footer, 40), 2 * 1024 * 1024);
+        assert_eq!(be_u64(footer, 48), 2 * 1024 * 1024);
+        assert_eq!(be_u32(footer, 60), FIXED_DISK_TYPE);
+        assert_eq!(&footer[68..84], &test_identity().unique_id);
+    }
+
+    #[test]
+    fn checksums_the_footer() {
+        let (_, vhd) = convert();
+        let mut footer = vhd[vhd.len() - FOOTER_SIZE..].to_vec();
+        let stored = be_u32(&footer, 64);
+        footer[64..68].fill(0);
+        assert_eq!(stored, !footer.iter().fold(0u32, |sum, b| sum.wrapping_add(*b as u32)));
+    }
+
+    #[test]
+    fn stores_the_chs_geometry() {
+        let (_, vhd) = convert();
+        let footer = &vhd[vhd.len() - FOOTER_SIZE..];
+        let cylinders = u16::from_be_bytes([footer[56], footer[57]]);
+        assert_eq!((cylinders, footer[58], footer[59]), (60, 4, 17));
+    }
+
+    #[test]
+    fn calculates_geometry_as_the_specification_does() {
+        assert_eq!(get_geometry(2 * 1024 * 1024), (60, 4, 17));
+        assert_eq!(get_geometry(1024 * 1024 * 1024), (2080, 16, 63));
+        // Beyond what CHS can address the geometry is capped
+        assert_eq!(get_geometry(4 * 1024 * 1024 * 1024 * 1024), (65535, 16, 255));
+        for size in [10u64, 100, 1000, 30_000] {
+            let size = size * 1024 * 1024;
+            let (cylinders, heads, sectors_per_track) = get_geometry(size);
+            assert!(cylinders as u64 * heads as u64 * sectors_per_track as u64 <= size / 512);
+        }
+    }
+}
@@ -0,0 +1,300 @@
+use std::{fs::File, io::{BufWriter, Seek, SeekFrom, Write}, path::Path};
+
+use anyhow::Result;
+
+use super::{guid_to_bytes, read_full, ImageIdentity};
+
+const KIB: u64 = 1024;
+const MIB: u64 = 1024 * KIB;
+const BLOCK_SIZE: u64 = 32 * MIB;
+const LOGICAL_SECTOR_SIZE: u64 = 512;
+const PHYSICAL_SECTOR_SIZE: u32 = 4096;
+/// Number of payload blocks described by each sector bitmap block
+const CHUNK_RATIO: u64 = (1 << 23) * LOGICAL_SECTOR_SIZE / BLOCK_SIZE;
+const HEADER_SIZE: usize = 4 * KIB as usize;
+const REGION_TABLE_SIZE: usize = 64 * KIB as usize;
+const LOG_OFFSET: u64 = MIB;
+const LOG_LENGTH: u64 = MIB;
+const METADATA_OFFSET: u64 = 2 * MIB;
+const METADATA_LENGTH: u64 = MIB;
+const BAT_OFFSET: u64 = 3 * MIB;
+const PAYLOAD_BLOCK_FULLY_PRESENT: u64 = 6;
+
+const BAT_REGION_GUID: &str = "2DC27766-F623-4200-9D64-115E9BFD4A08";
+const METADATA_REGION_GUID: &str = "8B7CA206-4790-4B9A-B8FE-575F050F886E";
+const FILE_PARAMETERS_GUID: &str = "CAA16737-FA36-4D43-B3B6-33F0AA44E76B";
+const VIRTUAL_DISK_SIZE_GUID: &str = "2FA54224-CD1B-4876-B211-5DBED83BF4B8";
+const VIRTUAL_DISK_ID_GUID: &str = "BECA12AB-B2E6-4523-93EF-C309E000C746";
+const LOGICAL_SECTOR_SIZE_GUID: &str = "8141BF1D-A96F-4709-BA47-F233A8FAAB5F";
+const PHYSICAL_SECTOR_SIZE_GUID: &str = "CDA348C7-445D-4471-9CC9-E9885251C556";
+
+/// Converts a raw disk image to a dynamic VHDX. Blocks that are entirely
+/// zero are left out of the file and read back as zeros
+pub fn write_vhdx(raw_path: &Path, vhdx_path: &Path, identity: &ImageIdentity) -> Result<()> {
+    let mut raw = File::open(raw_path)?;
+    // The virtual size has to be a whole number of logical sectors
+    let virtual_size = raw.metadata()?.len().div_ceil(LOGICAL_SECTOR_SIZE) * LOGICAL_SECTOR_SIZE;
+    let payload_blocks = virtual_size.div_ceil(BLOCK_SIZE);
+    // Sector bitmap entries are interleaved with the payload entries even though
+    // they are never present in a disk without a parent
+    let bat_entries = payload_blocks + payload_blocks.saturating_sub(1) / CHUNK_RATIO;
+    let bat_length = (bat_entries * 8).div_ceil(MIB) * MIB;
+
+    let mut output = BufWriter::new(File::create(vhdx_path)?);
+    let mut bat = vec![0u64; bat_entries as usize];
+    let mut block_offset = BAT_OFFSET + bat_length;
+    let mut block = vec![0u8; BLOCK_SIZE as usize];
+    for block_index in 0..payload_blocks {
+        block.fill(0);
+        read_full(&mut raw, &mut block)?;
+        if block.iter().all(|b| *b == 0) {
+            continue;
+        }
+        // Zero pages inside a present block are skipped so the file stays sparse
+        for (index, page) in block.chunks(4 * KIB as usize).enumerate() {
+            if page.iter().any(|b| *b != 0) {
+                output.seek(SeekFrom::Start(block_offset + index as u64 * 4 * KIB))?;
+                output.write_all(page)?;
+            }
+        }
+        let entry_index = block_index + block_index / CHUNK_RATIO;
+        // Offsets are stored in megabytes above the state bits
+        bat[entry_index as usize] = PAYLOAD_BLOCK_FULLY_PRESENT | (block_offset / MIB) << 20;
+        block_offset += BLOCK_SIZE;
+    }
+
+    output.seek(SeekFrom::Start(0))?;
+    output.write_all(&create_file_identifier())?;
+    let header = create_header(identity);
+    for (offset, sequence_number) in [(64 * KIB, 0), (128 * KIB, 1)] {
+        output.seek(SeekFrom::Start(offset))?;
+        output.write_all(&with_sequence_number(&header, sequence_number))?;
+    }
+    let region_table = create_region_table(bat_length);
+    for offset in [192 * KIB, 256 * KIB] {
+        output.seek(SeekFrom::Start(offset))?;
+        output.write_all(&region_table)?;
+    }
+    // The log is left as a hole since there is nothing to replay
+    output.seek(SeekFrom::Start(METADATA_OFFSET))?;
+    output.write_all(&create_metadata(virtual_size, identity))?;
+    output.seek(SeekFrom::Start(BAT_OFFSET))?;
+    let mut bat_bytes = vec![0u8; bat_length as usize];
+    for (index, entry) in bat.iter().enumerate() {
+        bat_bytes[index * 8..index * 8 + 8].copy_from_slice(&entry.to_le_bytes());
+    }
+    output.write_all(&bat_bytes)?;
+    output.flush()?;
+    drop(output);
+    // Make sure the file covers the last block even if it was all zeros
+    let file = File::options().write(true).open(vhdx_path)?;
+    if file.metadata()?.len() < block_offset {
+        file.set_len(block_offset)?;
+    }
+    Ok(())
+}
+
+fn create_file_identifier() -> Vec<u8> {
+    let mut identifier = vec![0u8; 64 * KIB as usize];
+    identifier[0..8].copy_from_slice(b"vhdxfile");
+    for (index, unit) in "whaledrive".encode_utf16().enumerate() {
+        identifier[8 + index * 2..10 + index * 2].copy_from_slice(&unit.to_le_bytes());
+    }
+    identifier
+}
+
+fn create_header(identity: &ImageIdentity) -> Vec<u8> {
+    let mut header = vec![0u8; HEADER_SIZE];
+    header[0..4].copy_from_slice(b"head");
+    header[16..32].copy_from_slice(&identity.derive_id(b"file write"));
+    header[32..48].copy_from_slice(&identity.derive_id(b"data write"));
+    // A zero log guid means the log is empty
+    header[64..66].copy_from_slice(&0u16.to_le_bytes()); // log version
+    header[66..68].copy_from_slice(&1u16.to_le_bytes()); // version
+    header[68..72].copy_from_slice(&(LOG_LENGTH as u32).to_le_bytes());
+    header[72..80].copy_from_slice(&LOG_OFFSET.to_le_bytes());
+    header
+}
+
+/// The header with the higher sequence number is the current one
+fn with_sequence_number(header: &[u8], sequence_number: u64) -> Vec<u8> {
+    let mut header = header.to_vec();
+    header[8..16].copy_from_slice(&sequence_number.to_le_bytes());
+    let checksum = crc32c(&header);
+    header[4..8].copy_from_slice(&checksum.to_le_bytes());
+    header
+}
+
+fn create_region_table(bat_length: u64) -> Vec<u8> {
+    let mut table = vec![0u8; REGION_TABLE_SIZE];
+    table[0..4].copy_from_slice(b"regi");
+    table[8..12].copy_from_slice(&2u32.to_le_bytes());
+    let regions = [(BAT_REGION_GUID, BAT_OFFSET, bat_length), (METADATA_REGION_GUID, METADATA_OFFSET, METADATA_LENGTH)];
+    for (index, (guid, offset, length)) in regions.iter().enumerate() {
+        let entry = 16 + index * 32;
+        table[entry..entry + 16].copy_from_slice(&guid_to_bytes(guid));
+        table[entry + 16..entry + 24].copy_from_slice(&offset.to_le_bytes());
+        table[entry + 24..entry + 28].copy_from_slice(&(*length as u32).to_le_bytes());
+        table[entry + 28..entry + 32].copy_from_slice(&1u32.to_le_bytes()); // required
+    }
+    let checksum = crc32c(&table);
+    table[4..8].copy_from_slice(&checksum.to_le_bytes());
+    table
+}
+
+fn create_metadata(virtual_size: u64, identity: &ImageIdentity) -> Vec<u8> {
+    const IS_VIRTUAL_DISK: u32 = 2;
+    const IS_REQUIRED: u32 = 4;
+    let mut file_parameters = Vec::new();
+    file_parameters.extend_from_slice(&(BLOCK_SIZE as u32).to_le_bytes());
+    file_parameters.extend_from_slice(&0u32.to_le_bytes()); // blocks may be unallocated and there's no parent
+    let items: [(&str, Vec<u8>, u32); 5] = [
+        (FILE_PARAMETERS_GUID, file_parameters, IS_REQUIRED),
+        (VIRTUAL_DISK_SIZE_GUID, virtual_size.to_le_bytes().to_vec(), IS_VIRTUAL_DISK | IS_REQUIRED),
+        (VIRTUAL_DISK_ID_GUID, identity.unique_id.to_vec(), IS_VIRTUAL_DISK | IS_REQUIRED),
+        (LOGICAL_SECTOR_SIZE_GUID, (LOGICAL_SECTOR_SIZE as u32).to_le_bytes().to_vec(), IS_VIRTUAL_DISK | IS_REQUIRED),
+        (PHYSICAL_SECTOR_SIZE_GUID, PHYSICAL_SECTOR_SIZE.to_le_bytes().to_vec(), IS_VIRTUAL_DISK | IS_REQUIRED),
+    ];
+    let mut metadata = vec![0u8; METADATA_LENGTH as usize];
+    metadata[0..8].copy_from_slice(b"metadata");
+    metadata[10..12].copy_from_slice(&(items.len() as u16).to_le_bytes());
+    // Item data starts after the 64 KiB table
+    let mut data_offset = 64 * KIB as usize;
+    for (index, (guid, data, flags)) in items.iter().enumerate() {
+        let entry = 32 + index * 32;
+        metadata[entry..entry + 16].copy_from_slice(&guid_to_bytes(guid));
+        metadata[entry + 16..entry + 20].copy_from_slice(&(data_offset as u32).to_le_bytes());
+        metadata[entry + 20..entry + 24].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        metadata[entry + 24..entry + 28].copy_from_slice(&flags.to_le_bytes());
+        metadata[data_offset..data_offset + data.len()].copy_from_slice(data);
+        data_offset += data.len();
+    }
+    metadata
+}
+
+/// CRC-32C (Castagnoli), which VHDX uses for its header and table checksums
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0x82F6_3B78 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::disk_formats::{convert_test_image, test_identity};
+
+    use super::*;
+
+    fn le_u16(data: &[u8], offset: u64) -> u16 {
+        u16::from_le_bytes(data[offset as usize..offset as usize + 2].try_into().unwrap())
+    }
+
+    fn le_u32(data: &[u8], offset: u64) -> u32 {
+        u32::from_le_bytes(data[offset as usize..offset as usize + 4].try_into().unwrap())
+    }
+
+    fn le_u64(data: &[u8], offset: u64) -> u64 {
+        u64::from_le_bytes(data[offset as usize..offset as usize + 8].try_into().unwrap())
+    }
+
+    /// Three blocks where the middle one is all zeros and the last one is partial
+    fn convert() -> (Vec<u8>, Vec<u8>) {
+        let identity = test_identity();
+        convert_test_image(
+            2 * BLOCK_SIZE + 1000,
+            &[(0, b"first block"), (BLOCK_SIZE - 4, b"edge"), (2 * BLOCK_SIZE + 10, b"last block")],
+            |raw, vhdx| write_vhdx(raw, vhdx, &identity),
+        )
+    }
+
+    /// Checks the CRC-32C of a structure, which is computed with its checksum field zeroed
+    fn assert_checksum(vhdx: &[u8], offset: u64, size: usize) {
+        let mut structure = vhdx[offset as usize..offset as usize + size].to_vec();
+        let stored = le_u32(&structure, 4);
+        structure[4..8].fill(0);
+        assert_eq!(stored, crc32c(&structure));
+    }
+
+    #[test]
+    fn computes_crc32c() {
+        assert_eq!(crc32c(b"123456789"), 0xE306_9283);
+    }
+
+    #[test]
+    fn starts_with_the_file_identifier() {
+        let (_, vhdx) = convert();
+        assert_eq!(&vhdx[0..8], b"vhdxfile");
+        let creator = (0..10).map(|index| le_u16(&vhdx, 8 + index * 2)).collect::<Vec<u16>>();
+        assert_eq!(String::from_utf16(&creator).unwrap(), "whaledrive");
+    }
+
+    #[test]
+    fn writes_both_headers() {
+        let (_, vhdx) = convert();
+        for (offset, sequence_number) in [(64 * KIB, 0), (128 * KIB, 1)] {
+            assert_eq!(&vhdx[offset as usize..offset as usize + 4], b"head");
+            assert_checksum(&vhdx, offset, HEADER_SIZE);
+            assert_eq!(le_u64(&vhdx, offset + 8), sequence_number);
+            assert_eq!(le_u16(&vhdx, offset + 66), 1);
+            assert_eq!(le_u32(&vhdx, offset + 68) as u64, LOG_LENGTH);
+            assert_eq!(le_u64(&vhdx, offset + 72), LOG_OFFSET);
+            // An empty log
+            assert!(vhdx[offset as usize + 48..offset as usize + 64].iter().all(|b| *b == 0));
+        }
+    }
+
+    #[test]
+    fn writes_both_region_tables() {
+        let (_, vhdx) = convert();
+        for offset in [192 * KIB, 256 * KIB] {
+            assert_eq!(&vhdx[offset as usize..offset as usize + 4], b"regi");
+            assert_checksum(&vhdx, offset, REGION_TABLE_SIZE);
+            assert_eq!(le_u32(&vhdx, offset + 8), 2);
+            let bat = offset + 16;
+            assert_eq!(&vhdx[bat as usize..bat as usize + 16], &guid_to_bytes(BAT_REGION_GUID));
+            assert_eq!(le_u64(&vhdx, bat + 16), BAT_OFFSET);
+            assert_eq!(le_u32(&vhdx, bat + 24) as u64, MIB);
+            let metadata = offset + 48;
+            assert_eq!(&vhdx[metadata as usize..metadata as usize + 16], &guid_to_bytes(METADATA_REGION_GUID));
+            assert_eq!(le_u64(&vhdx, metadata + 16), METADATA_OFFSET);
+            assert_eq!(le_u32(&vhdx, metadata + 24) as u64, METADATA_LENGTH);
+        }
+    }
+
+    #[test]
+    fn describes_the_disk_in_the_metadata() {
+        let (raw, vhdx) = convert();
+        assert_eq!(&vhdx[METADATA_OFFSET as usize..METADATA_OFFSET as usize + 8], b"metadata");
+        let count = le_u16(&vhdx, METADATA_OFFSET + 10) as u64;
+        let item = |guid: &str| (0..count).map(|index| METADATA_OFFSET + 32 + index * 32)
+            .find(|entry| vhdx[*entry as usize..*entry as usize + 16] == guid_to_bytes(guid))
+            .map(|entry| METADATA_OFFSET + le_u32(&vhdx, entry + 16) as u64)
+            .unwrap();
+        assert_eq!(le_u32(&vhdx, item(FILE_PARAMETERS_GUID)) as u64, BLOCK_SIZE);
+        assert_eq!(le_u64(&vhdx, item(VIRTUAL_DISK_SIZE_GUID)), raw.len().div_ceil(512) as u64 * 512);
+        let disk_id = item(VIRTUAL_DISK_ID_GUID) as usize;
+        assert_eq!(&vhdx[disk_id..disk_id + 16], &test_identity().unique_id);
+        assert_eq!(le_u32(&vhdx, item(LOGICAL_SECTOR_SIZE_GUID)) as u64, LOGICAL_SECTOR_SIZE);
+        assert_eq!(le_u32(&vhdx, item(PHYSICAL_SECTOR_SIZE_GUID)), PHYSICAL_SECTOR_SIZE);
+    }
+
+    #[test]
+    fn maps_present_blocks_in_the_bat() {
+        let (raw, vhdx) = convert();
+        let entries = (0..3).map(|index| le_u64(&vhdx, BAT_OFFSET + index * 8)).collect::<Vec<u64>>();
+        // The zero block is left out
+        assert_eq!(entries[1], 0);
+        for (block, entry) in [(0, entries[0]), (2, entries[2])] {
+            assert_eq!(entry & 7, PAYLOAD_BLOCK_FULLY_PRESENT);
+            let offset = ((entry >> 20) * MIB) as usize;
+            let start = (block * BLOCK_SIZE) as usize;
+            let end = raw.len().min(start + BLOCK_SIZE as usize);
+            assert_eq!(&vhdx[offset..offset + end - start], &raw[start..end]);
+        }
+    }
+}
@@ -0,0 +1,262 @@
+use std::{fs::File, io::{BufWriter, Seek, Write}, path::Path};
+
+use anyhow::Result;
+use flate2::{write::ZlibEncoder, Compression};
+
+use super::{read_full, ImageIdentity};
+
+const SECTOR_SIZE: u64 = 512;
+/// Grains are 64 KiB, the size VMware uses for stream optimized disks
+const GRAIN_SECTORS: u64 = 128;
+const GRAIN_SIZE: u64 = GRAIN_SECTORS * SECTOR_SIZE;
+const GTES_PER_GT: u64 = 512;
+const GT_SECTORS: u64 = GTES_PER_GT * 4 / SECTOR_SIZE;
+const DESCRIPTOR_OFFSET: u64 = 1;
+const DESCRIPTOR_SECTORS: u64 = 20;
+/// Grains start after the header and descriptor, on a grain boundary
+const OVERHEAD_SECTORS: u64 = GRAIN_SECTORS;
+const GD_AT_END: u64 = u64::MAX;
+const MARKER_EOS: u32 = 0;
+const MARKER_GT: u32 = 1;
+const MARKER_GD: u32 = 2;
+const MARKER_FOOTER: u32 = 3;
+
+/// Converts a raw disk image to a stream optimized VMDK. Every grain is
+/// deflated and grains that are entirely zero are left out
+pub fn write_vmdk(raw_path: &Path, vmdk_path: &Path, identity: &ImageIdentity) -> Result<()> {
+    let mut raw = File::open(raw_path)?;
+    let capacity = raw.metadata()?.len().div_ceil(SECTOR_SIZE);
+    let grains = capacity.div_ceil(GRAIN_SECTORS);
+    let grain_tables = grains.div_ceil(GTES_PER_GT).max(1);
+    let file_name = vmdk_path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+
+    let mut output = BufWriter::new(File::create(vmdk_path)?);
+    output.write_all(&create_header(capacity, GD_AT_END))?;
+    let mut descriptor = create_descriptor(capacity, &file_name, identity).into_bytes();
+    descriptor.resize(((OVERHEAD_SECTORS - DESCRIPTOR_OFFSET) * SECTOR_SIZE) as usize, 0);
+    output.write_all(&descriptor)?;
+
+    // Sector of each grain's marker by grain index, zero if the grain was left out
+    let mut grain_table = vec![0u32; (grain_tables * GTES_PER_GT) as usize];
+    let mut sector = OVERHEAD_SECTORS;
+    let mut grain = vec![0u8; GRAIN_SIZE as usize];
+    for grain_index in 0..grains {
+        grain.fill(0);
+        read_full(&mut raw, &mut grain)?;
+        if grain.iter().all(|b| *b == 0) {
+            continue;
+        }
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&grain)?;
+        let compressed = encoder.finish()?;
+        let mut marker = Vec::with_capacity(12 + compressed.len());
+        marker.extend_from_slice(&(grain_index * GRAIN_SECTORS).to_le_bytes());
+        marker.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+        marker.extend_from_slice(&compressed);
+        grain_table[grain_index as usize] = sector as u32;
+        sector += write_padded(&mut output, &marker)?;
+    }
+
+    // Each grain table is preceded by a marker, then the directory pointing at them
+    let mut grain_directory = Vec::with_capacity(grain_tables as usize);
+    for table in grain_table.chunks(GTES_PER_GT as usize) {
+        sector += write_padded(&mut output, &create_marker(GT_SECTORS, MARKER_GT))?;
+        grain_directory.push(sector as u32);
+        sector += write_padded(&mut output, &to_le_bytes(table))?;
+    }
+    let directory_sectors = (grain_tables * 4).div_ceil(SECTOR_SIZE);
+    sector += write_padded(&mut output, &create_marker(directory_sectors, MARKER_GD))?;
+    let directory_offset = sector;
+    sector += write_padded(&mut output, &to_le_bytes(&grain_directory))?;
+
+    // The footer repeats the header with the directory location filled in
+    write_padded(&mut output, &create_marker(1, MARKER_FOOTER))?;
+    output.write_all(&create_header(capacity, directory_offset))?;
+    write_padded(&mut output, &create_marker(0, MARKER_EOS))?;
+    output.flush()?;
+    debug_assert_eq!(output.stream_position()?, (sector + 3) * SECTOR_SIZE);
+    Ok(())
+}
+
+fn create_header(capacity: u64, directory_offset: u64) -> Vec<u8> {
+    let mut header = Vec::with_capacity(SECTOR_SIZE as usize);
+    header.extend_from_slice(b"KDMV");
+    header.extend_from_slice(&3u32.to_le_bytes()); // version
+    // Valid newline detection, compressed grains and markers
+    header.extend_from_slice(&(1u32 | 1 << 16 | 1 << 17).to_le_bytes());
+    header.extend_from_slice(&capacity.to_le_bytes());
+    header.extend_from_slice(&GRAIN_SECTORS.to_le_bytes());
+    header.extend_from_slice(&DESCRIPTOR_OFFSET.to_le_bytes());
+    header.extend_from_slice(&DESCRIPTOR_SECTORS.to_le_bytes());
+    header.extend_from_slice(&(GTES_PER_GT as u32).to_le_bytes());
+    header.extend_from_slice(&0u64.to_le_bytes()); // no redundant grain directory
+    header.extend_from_slice(&directory_offset.to_le_bytes());
+    header.extend_from_slice(&OVERHEAD_SECTORS.to_le_bytes());
+    header.push(0); // clean shutdown
+    header.extend_from_slice(b"\n \r\n");
+    header.extend_from_slice(&1u16.to_le_bytes()); // deflate
+    header.resize(SECTOR_SIZE as usize, 0);
+    header
+}
+
+fn create_descriptor(capacity: u64, file_name: &str, identity: &ImageIdentity) -> String {
+    let content_id = u32::from_le_bytes(identity.unique_id[0..4].try_into().unwrap_or_default());
+    let cylinders = (capacity / (255 * 63)).clamp(1, 65535);
+    format!(
+        "# Disk DescriptorFile\n\
+        version=1\n\
+        CID={content_id:08x}\n\
+        parentCID=ffffffff\n\
+        createType=\"streamOptimized\"\n\
+        \n\
+        # Extent description\n\
+        RW {capacity} SPARSE \"{file_name}\"\n\
+        \n\
+        # The Disk Data Base\n\
+        #DDB\n\
+        \n\
+        ddb.virtualHWVersion = \"4\"\n\
+        ddb.adapterType = \"lsilogic\"\n\
+        ddb.geometry.cylinders = \"{cylinders}\"\n\
+        ddb.geometry.heads = \"255\"\n\
+        ddb.geometry.sectors = \"63\"\n"
+    )
+}
+
+fn create_marker(sectors: u64, marker_type: u32) -> Vec<u8> {
+    let mut marker = Vec::with_capacity(16);
+    marker.extend_from_slice(&sectors.to_le_bytes());
+    marker.extend_from_slice(&0u32.to_le_bytes());
+    marker.extend_from_slice(&marker_type.to_le_bytes());
+    marker
+}
+
+fn to_le_bytes(values: &[u32]) -> Vec<u8> {
+    values.iter().flat_map(|value| value.to_le_bytes()).collect()
+}
+
+/// Writes the data padded to a whole sector, returning the number of sectors written
+fn write_padded(output: &mut impl Write, data: &[u8]) -> Result<u64> {
+    let sectors = (data.len() as u64).div_ceil(SECTOR_SIZE).max(1);
+    output.write_all(data)?;
+    output.write_all(&vec![0u8; (sectors * SECTOR_SIZE) as usize - data.len()])?;
+    Ok(sectors)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::ZlibDecoder;
+
+    use crate::disk_formats::{convert_test_image, test_identity};
+
+    use super::*;
+
+    fn le_u32(data: &[u8], offset: u64) -> u32 {
+        u32::from_le_bytes(data[offset as usize..offset as usize + 4].try_into().unwrap())
+    }
+
+    fn le_u64(data: &[u8], offset: u64) -> u64 {
+        u64::from_le_bytes(data[offset as usize..offset as usize + 8].try_into().unwrap())
+    }
+
+    /// Four grains where the second is all zeros and the last one is partial
+    fn convert() -> (Vec<u8>, Vec<u8>) {
+        let identity = test_identity();
+        convert_test_image(
+            3 * GRAIN_SIZE + 1000,
+            &[(0, b"first grain"), (2 * GRAIN_SIZE + 7, b"third grain"), (3 * GRAIN_SIZE, b"last grain")],
+            |raw, vmdk| write_vmdk(raw, vmdk, &identity),
+        )
+    }
+
+    /// Checks a sparse header, with the directory offset it's expected to have
+    fn assert_header(vmdk: &[u8], offset: u64, capacity: u64, directory_offset: u64) {
+        assert_eq!(&vmdk[offset as usize..offset as usize + 4], b"KDMV");
+        assert_eq!(le_u32(vmdk, offset + 4), 3);
+        assert_eq!(le_u32(vmdk, offset + 8), 1 | 1 << 16 | 1 << 17);
+        assert_eq!(le_u64(vmdk, offset + 12), capacity);
+        assert_eq!(le_u64(vmdk, offset + 20), GRAIN_SECTORS);
+        assert_eq!(le_u64(vmdk, offset + 28), DESCRIPTOR_OFFSET);
+        assert_eq!(le_u64(vmdk, offset + 36), DESCRIPTOR_SECTORS);
+        assert_eq!(le_u32(vmdk, offset + 44) as u64, GTES_PER_GT);
+        assert_eq!(le_u64(vmdk, offset + 56), directory_offset);
+        assert_eq!(le_u64(vmdk, offset + 64), OVERHEAD_SECTORS);
+        assert_eq!(&vmdk[offset as usize + 73..offset as usize + 77], b"\n \r\n");
+        assert_eq!(u16::from_le_bytes([vmdk[offset as usize + 77], vmdk[offset as usize + 78]]), 1);
+    }
+
+    #[test]
+    fn starts_with_a_sparse_header() {
+        let (raw, vmdk) = convert();
+        assert_header(&vmdk, 0, raw.len().div_ceil(SECTOR_SIZE as usize) as u64, GD_AT_END);
+    }
+
+    #[test]
+    fn embeds_the_descriptor() {
+        let (raw, vmdk) = convert();
+        let start = (DESCRIPTOR_OFFSET * SECTOR_SIZE) as usize;
+        let end = (OVERHEAD_SECTORS * SECTOR_SIZE) as usize;
+        let descriptor = String::from_utf8(vmdk[start..end].iter().copied().take_while(|b| *b != 0).collect()).unwrap();
+        let capacity = raw.len().div_ceil(SECTOR_SIZE as usize);
+        assert!(descriptor.starts_with("# Disk DescriptorFile\n"));
+        assert!(descriptor.contains("createType=\"streamOptimized\"\n"));
+        assert!(descriptor.contains(&format!("RW {capacity} SPARSE \"disk.out\"\n")));
+        let content_id = u32::from_le_bytes(test_identity().unique_id[0..4].try_into().unwrap());
+        assert!(descriptor.contains(&format!("CID={content_id:08x}\n")));
+    }
+
+    #[test]
+    fn streams_grains_then_tables_then_the_footer() {
+        let (raw, vmdk) = convert();
+        let capacity = raw.len().div_ceil(SECTOR_SIZE as usize) as u64;
+        let mut sector = OVERHEAD_SECTORS;
+        let mut grains = Vec::new();
+        let mut grain_tables = Vec::new();
+        // Grain markers have a size, the others are a sector count, zero, and their type
+        loop {
+            let offset = sector * SECTOR_SIZE;
+            let size = le_u32(&vmdk, offset + 8) as u64;
+            if size != 0 {
+                let lba = le_u64(&vmdk, offset);
+                let mut grain = Vec::new();
+                ZlibDecoder::new(&vmdk[offset as usize + 12..(offset + 12 + size) as usize]).read_to_end(&mut grain).unwrap();
+                assert_eq!(grain.len() as u64, GRAIN_SIZE);
+                let start = (lba * SECTOR_SIZE) as usize;
+                let end = raw.len().min(start + GRAIN_SIZE as usize);
+                assert_eq!(&grain[..end - start], &raw[start..end]);
+                assert!(grain[end - start..].iter().all(|b| *b == 0));
+                grains.push((lba / GRAIN_SECTORS, sector));
+                sector += (12 + size).div_ceil(SECTOR_SIZE);
+                continue;
+            }
+            let sectors = le_u64(&vmdk, offset);
+            match le_u32(&vmdk, offset + 12) {
+                MARKER_GT => grain_tables.push(sector + 1),
+                MARKER_GD => {
+                    let directory = (0..grain_tables.len() as u64).map(|index| le_u32(&vmdk, (sector + 1) * SECTOR_SIZE + index * 4) as u64).collect::<Vec<u64>>();
+                    assert_eq!(directory, grain_tables);
+                    // The footer is the header with the directory filled in
+                    let footer_marker = sector + 1 + sectors;
+                    assert_eq!(le_u32(&vmdk, footer_marker * SECTOR_SIZE + 12), MARKER_FOOTER);
+                    assert_header(&vmdk, (footer_marker + 1) * SECTOR_SIZE, capacity, sector + 1);
+                    let end_marker = (footer_marker + 2) * SECTOR_SIZE;
+                    assert_eq!(le_u32(&vmdk, end_marker + 12), MARKER_EOS);
+                    assert_eq!(vmdk.len() as u64, end_marker + SECTOR_SIZE);
+                    break;
+                },
+                marker => panic!("Unexpected marker {marker} at sector {sector}"),
+            }
+            sector += 1 + sectors;
+        }
+        // The zero grain is left out and the grain table points at each grain's marker
+        assert_eq!(grains.iter().map(|(grain, _)| *grain).collect::<Vec<u64>>(), vec![0, 2, 3]);
+        assert_eq!(grain_tables.len(), 1);
+        for grain in 0..4 {
+            let entry = le_u32(&vmdk, grain_tables[0] * SECTOR_SIZE + grain * 4) as u64;
+            let expected = grains.iter().find(|(index, _)| *index == grain).map(|(_, sector)| *sector).unwrap_or(0);
+            assert_eq!(entry, expected);
+        }
+    }
+}
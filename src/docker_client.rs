@@ -1,11 +1,11 @@
 use std::{fs, path::Path};
 
-use hyper::header::ACCEPT;
+use hyper::{body::Bytes, header::ACCEPT};
 use reqwest::Client;
 use tokio::io::AsyncWriteExt;
 
-use crate::{models::registry_models::{AuthResponse, ImageConfig, Manifests, OCIManifest}, paths::get_layers_compressed_path};
-use anyhow::{bail, Context, Result};
+use crate::{models::registry_models::{AuthResponse, ImageConfig, Manifests, OCIManifest, OCI_INDEX_MEDIA_TYPE, OCI_MANIFEST_MEDIA_TYPE}, paths::get_layers_compressed_path};
+use anyhow::Result;
 use tokio::fs::File as TokioFile;
 
 
@@ -16,28 +16,24 @@ const SVC_URL: &str = "registry.docker.io";
 pub struct DockerClient {
     client: Client,
     image_name: String,
-    image_tag: String,
+    /// The tag or digest the manifests are fetched for
+    reference: String,
     namespace: String,
     token: String
 }
 
 impl DockerClient {
 
-    pub async fn new_with_auth(image: &String) -> Result<DockerClient> {
+    /// Authenticates for pulling an image, whose manifests are then fetched by the tag or digest in reference
+    pub async fn new_with_auth(image: &str, reference: &str) -> Result<DockerClient> {
         let mut namespace = String::from("library");
-        let mut image_name = image.clone();
-        let mut image_tag = String::from("latest");
+        let mut image_name = image.to_string();
         let client = reqwest::Client::new();
-        if image.contains("/") {
-            let parts = image.split("/").collect::<Vec<&str>>();
+        if image.contains('/') {
+            let parts = image.split('/').collect::<Vec<&str>>();
             namespace = parts[0].to_string();
             image_name = parts[1].to_string();
         }
-        let image_name_parts = image_name.split(":").map(String::from).collect::<Vec<String>>();
-        image_name = image_name_parts[0].to_string();
-        if image_name_parts.len() > 1 {
-            image_tag = image_name_parts[1].to_string();
-        }
         let url = format!("{AUTH_URL}/token?service={SVC_URL}&scope=repository:{namespace}/{image_name}:pull");
         let response = client.get(&url).send().await?;
         let auth_response = response.json::<AuthResponse>().await?;
@@ -45,7 +41,7 @@ impl DockerClient {
             Self {
                 client,
                 image_name,
-                image_tag,
+                reference: reference.to_string(),
                 namespace,
                 token: auth_response.token
             }
@@ -53,25 +49,33 @@ impl DockerClient {
     }
 
     pub async fn get_manifests(&self) -> Result<Manifests> {
+        Manifests::parse(&self.get_manifests_bytes().await?)
+    }
 
-        let url = format!("{REGISTRY_URL}/v2/{}/{}/manifests/{}", self.namespace, self.image_name, self.image_tag);
-        println!("Getting manifests for {}", url);
+    /// Gets what the reference points at exactly as the registry serves it, so its digest can be checked.
+    /// That's an index for multi-platform images, a digest can also point at a single manifest
+    pub async fn get_manifests_bytes(&self) -> Result<Bytes> {
+        let url = format!("{REGISTRY_URL}/v2/{}/{}/manifests/{}", self.namespace, self.image_name, self.reference);
+        eprintln!("Getting manifests for {}", url);
         let response = self.client
             .get(&url)
-            // .header(ACCEPT, "application/vnd.docker.distribution.manifest.list.v2+json")
             // .header(ACCEPT, "application/vnd.docker.distribution.manifest.v1+json")
             .header(ACCEPT, "application/vnd.docker.distribution.manifest.v2+json")
+            .header(ACCEPT, "application/vnd.docker.distribution.manifest.list.v2+json")
+            .header(ACCEPT, OCI_INDEX_MEDIA_TYPE)
+            .header(ACCEPT, OCI_MANIFEST_MEDIA_TYPE)
             .bearer_auth(self.token.clone())
             .send()
             .await?;
-        let manifest = response.json::<Manifests>().await?;
-        if let Some(errors) = manifest.errors {
-            bail!("Error getting manifests: {:?}", errors.get(0).context("Errors present but empty")?.message);
-        }
-        Ok(manifest)
+        Ok(response.bytes().await?)
     }
 
     pub async fn get_oci_manifest(&self,digest: &str) -> Result<OCIManifest> {
+        Ok(serde_json::from_slice(&self.get_oci_manifest_bytes(digest).await?)?)
+    }
+
+    /// Gets the manifest exactly as the registry serves it so its digest is preserved
+    pub async fn get_oci_manifest_bytes(&self, digest: &str) -> Result<Bytes> {
         let url = format!("{REGISTRY_URL}/v2/{}/{}/manifests/{}", self.namespace, self.image_name, digest);
 
         let response = self.client
@@ -80,7 +84,7 @@ impl DockerClient {
             .bearer_auth(self.token.clone())
             .send()
             .await?;
-        Ok(response.json::<OCIManifest>().await?)
+        Ok(response.bytes().await?)
     }
 
     pub async fn download_layer(&self, digest: &str, dest: &Path) -> Result<()> {
@@ -96,11 +100,18 @@ impl DockerClient {
         while let Some(chunk) = response.chunk().await? {
             file.write_all(&chunk).await?;
         }
+        // Tokio finishes writes in the background, they have to be done before the file is renamed
+        file.flush().await?;
 
         Ok(())
     }
 
     pub async fn get_image_config(&self, digest: &str) -> Result<ImageConfig> {
+        Ok(serde_json::from_slice(&self.get_image_config_bytes(digest).await?)?)
+    }
+
+    /// Gets the image config exactly as the registry serves it so its digest is preserved
+    pub async fn get_image_config_bytes(&self, digest: &str) -> Result<Bytes> {
         let url = format!("{REGISTRY_URL}/v2/{}/{}/blobs/{}", self.namespace, self.image_name, digest);
         let response = self.client
             .get(&url)
@@ -108,18 +119,21 @@ impl DockerClient {
             .send()
             .await?;
 
-        Ok(response.json::<ImageConfig>().await?)
+        Ok(response.bytes().await?)
     }
 
-    /// Downloads layers from the registry and leaves them compressed
-    pub async fn download_layers_compressed(&self, layers: &Vec<String>) -> Result<()> {
+    /// Downloads layers from the registry and leaves them compressed. Each one is only
+    /// renamed into place once it's complete, so an interrupted download isn't mistaken for a layer
+    pub async fn download_layers_compressed(&self, layers: &[String]) -> Result<()> {
         let compressed_layers_path = get_layers_compressed_path()?;
         fs::create_dir_all(&compressed_layers_path)?;
         for digest in layers {
             let dest = compressed_layers_path.join(format!("{}.tgz", &digest));
             
             if !dest.exists() {
-                self.download_layer(&digest, dest.as_path().as_std_path()).await?;
+                let partial = compressed_layers_path.join(format!("{}.partial", &digest));
+                self.download_layer(digest, partial.as_std_path()).await?;
+                fs::rename(&partial, &dest)?;
             }
         }
         Ok(())
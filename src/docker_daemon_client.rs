@@ -0,0 +1,155 @@
+use std::{fs::File, io::Write};
+
+use anyhow::{bail, Context, Result};
+use camino::Utf8PathBuf;
+use http_body_util::{BodyExt, Full};
+use hyper::{body::{Bytes, Incoming}, Response};
+use hyper_util::client::legacy::Client;
+use hyperlocal::{UnixClientExt, UnixConnector, Uri as UnixUri};
+
+use crate::{local_images::import_docker_archive, models::registry_models::{LocalImageInspect, PulledImage}, utils::create_temp_dir};
+
+const DEFAULT_SOCKET_PATH: &str = "/var/run/docker.sock";
+
+/// Talks to the local Docker Engine API over its unix socket
+pub struct DockerDaemonClient {
+    client: Client<UnixConnector, Full<Bytes>>,
+    socket_path: Utf8PathBuf,
+}
+
+impl DockerDaemonClient {
+
+    /// Connects to the socket in DOCKER_HOST if it is a unix socket, otherwise the default docker socket
+    pub fn from_env() -> Result<DockerDaemonClient> {
+        let socket_path = match std::env::var("DOCKER_HOST") {
+            Ok(host) => match host.strip_prefix("unix://") {
+                Some(path) => Utf8PathBuf::from(path),
+                None => bail!("Only unix sockets are supported for DOCKER_HOST but got {}", host),
+            },
+            Err(_) => Utf8PathBuf::from(DEFAULT_SOCKET_PATH),
+        };
+        Ok(Self::new(socket_path))
+    }
+
+    pub fn new(socket_path: Utf8PathBuf) -> DockerDaemonClient {
+        Self {
+            client: Client::unix(),
+            socket_path,
+        }
+    }
+
+    async fn get(&self, path: &str) -> Result<Response<Incoming>> {
+        let uri = UnixUri::new(&self.socket_path, path).into();
+        let response = self.client.get(uri).await
+            .with_context(|| format!("Failed to connect to the docker daemon at {}", self.socket_path))?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.into_body().collect().await?.to_bytes();
+            bail!("Docker daemon returned {} for {}: {}", status, path, String::from_utf8_lossy(&body).trim());
+        }
+        Ok(response)
+    }
+
+    /// Gets the id and platform of a local image. The id is the config digest with the
+    /// classic image store and the manifest digest with the containerd one
+    pub async fn inspect_image(&self, image: &str) -> Result<LocalImageInspect> {
+        let response = self.get(&format!("/images/{}/json", encode_reference(image))).await?;
+        let body = response.into_body().collect().await?.to_bytes();
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    /// Exports an image the same way `docker save` does and moves its layers
+    /// into the compressed layers folder
+    pub async fn export_image(&self, image: &str) -> Result<PulledImage> {
+        let temp_dir = create_temp_dir()?;
+        let archive_path = temp_dir.path().join("image.tar");
+        let mut response = self.get(&format!("/images/{}/get", encode_reference(image))).await?;
+        let mut file = File::create(&archive_path)?;
+        while let Some(frame) = response.body_mut().frame().await {
+            if let Ok(chunk) = frame?.into_data() {
+                file.write_all(&chunk)?;
+            }
+        }
+        drop(file);
+        import_docker_archive(&archive_path)
+    }
+}
+
+/// Percent-encodes an image reference for the request path. The daemon decodes the path before
+/// matching the name, so registry ports, digests and anything else reach it as they were given
+fn encode_reference(reference: &str) -> String {
+    let mut encoded = String::with_capacity(reference.len());
+    for byte in reference.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::UnixListener, task::JoinHandle};
+
+    use super::*;
+
+    /// Answers a single request on a unix socket with a canned response, returning the request line
+    fn fake_daemon(socket_path: &Utf8PathBuf, status: &'static str, body: &'static str) -> JoinHandle<String> {
+        let listener = UnixListener::bind(socket_path).unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0u8; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let read = stream.read(&mut buffer).await.unwrap();
+                request.extend_from_slice(&buffer[..read]);
+            }
+            let response = format!("HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}", body.len());
+            stream.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8(request).unwrap().lines().next().unwrap().to_string()
+        })
+    }
+
+    fn socket_path(dir: &tempfile::TempDir) -> Utf8PathBuf {
+        Utf8PathBuf::from_path_buf(dir.path().join("docker.sock")).unwrap()
+    }
+
+    #[tokio::test]
+    async fn inspects_an_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = socket_path(&dir);
+        let daemon = fake_daemon(&socket_path, "200 OK", r#"{"Id":"sha256:abc","Os":"linux","Architecture":"arm64","RepoTags":["alpine:3.19"]}"#);
+        let inspect = DockerDaemonClient::new(socket_path).inspect_image("alpine:3.19").await.unwrap();
+        assert_eq!(inspect.id, "sha256:abc");
+        assert_eq!(inspect.os, "linux");
+        assert_eq!(inspect.architecture, "arm64");
+        assert_eq!(daemon.await.unwrap(), "GET /images/alpine%3A3.19/json HTTP/1.1");
+    }
+
+    #[tokio::test]
+    async fn encodes_registry_ports_and_digests() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = socket_path(&dir);
+        let daemon = fake_daemon(&socket_path, "200 OK", r#"{"Id":"sha256:abc","Os":"linux","Architecture":"amd64"}"#);
+        DockerDaemonClient::new(socket_path).inspect_image("host:5000/ns/img@sha256:0123").await.unwrap();
+        assert_eq!(daemon.await.unwrap(), "GET /images/host%3A5000/ns/img%40sha256%3A0123/json HTTP/1.1");
+    }
+
+    #[tokio::test]
+    async fn reports_daemon_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = socket_path(&dir);
+        let daemon = fake_daemon(&socket_path, "404 Not Found", r#"{"message":"No such image: missing:latest"}"#);
+        let error = DockerDaemonClient::new(socket_path).inspect_image("missing:latest").await.unwrap_err();
+        assert!(error.to_string().contains("404"), "{error}");
+        assert!(error.to_string().contains("No such image: missing:latest"), "{error}");
+        daemon.await.unwrap();
+    }
+
+    #[test]
+    fn leaves_unreserved_characters_alone() {
+        assert_eq!(encode_reference("library/ubuntu_x-1.0~rc"), "library/ubuntu_x-1.0~rc");
+        assert_eq!(encode_reference("a b#c?d"), "a%20b%23c%3Fd");
+    }
+}
@@ -0,0 +1,158 @@
+use std::{fs::{self, File}, io::{self, Read, Write}, os::unix::{ffi::OsStrExt, fs::{symlink, MetadataExt, PermissionsExt}}, path::Path};
+
+use anyhow::{bail, Result};
+use flate2::{write::GzEncoder, Compression};
+
+use crate::{models::{input_models::CompressionAlgorithm, registry_models::ImageConfig}, utils::{create_temp_dir, decompress_layers}};
+
+const NEWC_MAGIC: &str = "070701";
+const TRAILER: &str = "TRAILER!!!";
+
+/// Options that change how an initramfs is created
+#[derive(Debug, Clone, Default)]
+pub struct InitramfsOptions {
+    /// Compresses the archive, the kernel detects which algorithm was used
+    pub compression: Option<CompressionAlgorithm>,
+    /// When set no timestamp in the archive is later than this
+    pub epoch: Option<u64>,
+}
+
+/// Flattens the layers into a newc cpio archive the kernel can unpack as its root filesystem,
+/// returning the size of the archive
+pub fn create_initramfs(layers: &[String], layers_path: &Path, image_config: &ImageConfig, output_path: &Path, options: &InitramfsOptions) -> Result<u64> {
+    let temp_combined_dir = create_temp_dir()?;
+    decompress_layers(layers, layers_path, temp_combined_dir.path())?;
+    create_init(temp_combined_dir.path(), image_config)?;
+    let file = File::create(output_path)?;
+    match options.compression {
+        None => write_cpio(temp_combined_dir.path(), file, options.epoch)?.flush()?,
+        Some(CompressionAlgorithm::Gzip) => {
+            write_cpio(temp_combined_dir.path(), GzEncoder::new(file, Compression::default()), options.epoch)?.finish()?;
+        },
+        Some(CompressionAlgorithm::Zstd) => {
+            write_cpio(temp_combined_dir.path(), zstd::Encoder::new(file, 0)?, options.epoch)?.finish()?;
+        },
+    }
+    Ok(fs::metadata(output_path)?.len())
+}
+
+/// The kernel runs /init from the initramfs. An image that has one keeps it, one with an
+/// init system gets a link to it, and anything else gets a script that runs the entrypoint
+fn create_init(root: &Path, image_config: &ImageConfig) -> Result<()> {
+    let init_path = root.join("init");
+    if fs::symlink_metadata(&init_path).is_ok() {
+        return Ok(());
+    }
+    if fs::symlink_metadata(root.join("sbin/init")).is_ok() {
+        symlink("/sbin/init", &init_path)?;
+        return Ok(());
+    }
+    let config = &image_config.config;
+    let command = config.entrypoint.iter().flatten().chain(config.cmd.iter().flatten()).cloned().collect::<Vec<String>>();
+    if command.is_empty() {
+        bail!("The image has no /init, /sbin/init or entrypoint to start");
+    }
+    if fs::symlink_metadata(root.join("bin/sh")).is_err() {
+        // Without a shell the entrypoint can only be linked to directly
+        if command.len() > 1 || !command[0].starts_with('/') {
+            bail!("The image has no shell to run its entrypoint with arguments from /init");
+        }
+        symlink(&command[0], &init_path)?;
+        return Ok(());
+    }
+    let mut script = String::from("#!/bin/sh\n");
+    for (directory, fs_type) in [("proc", "proc"), ("sys", "sysfs"), ("dev", "devtmpfs")] {
+        script.push_str(&format!("mkdir -p /{directory} && mount -t {fs_type} {fs_type} /{directory} 2>/dev/null\n"));
+    }
+    for variable in config.env.iter().flatten() {
+        if let Some((name, value)) = variable.split_once('=') {
+            script.push_str(&format!("export {}={}\n", name, shell_quote(value)));
+        }
+    }
+    if let Some(working_dir) = config.working_dir.as_deref().filter(|dir| !dir.is_empty()) {
+        script.push_str(&format!("cd {}\n", shell_quote(working_dir)));
+    }
+    script.push_str(&format!("exec {}\n", command.iter().map(|arg| shell_quote(arg)).collect::<Vec<String>>().join(" ")));
+    fs::write(&init_path, script)?;
+    fs::set_permissions(&init_path, fs::Permissions::from_mode(0o755))?;
+    Ok(())
+}
+
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Writes every file under the root as a newc cpio archive. Entries are sorted so the
+/// same tree always gives the same archive, and hard links are stored as separate files
+fn write_cpio<W: Write>(root: &Path, mut writer: W, epoch: Option<u64>) -> Result<W> {
+    let mut inode = 0;
+    let mut entries = vec![root.to_path_buf()];
+    while let Some(path) = entries.pop() {
+        let metadata = fs::symlink_metadata(&path)?;
+        if metadata.is_dir() {
+            let mut children = fs::read_dir(&path)?.map(|entry| Ok(entry?.path())).collect::<io::Result<Vec<_>>>()?;
+            // Popping from the end, so reverse order visits them alphabetically
+            children.sort_by(|a, b| b.cmp(a));
+            entries.extend(children);
+        }
+        let relative = path.strip_prefix(root)?;
+        let name = if relative.as_os_str().is_empty() { b".".as_slice() } else { relative.as_os_str().as_bytes() };
+        // Files are streamed into the archive rather than read into memory, their size is in the header already
+        let (mut data, size): (Box<dyn Read>, u64) = if metadata.file_type().is_symlink() {
+            let target = fs::read_link(&path)?.as_os_str().as_bytes().to_vec();
+            let size = target.len() as u64;
+            (Box::new(io::Cursor::new(target)), size)
+        } else if metadata.is_file() {
+            (Box::new(File::open(&path)?), metadata.len())
+        } else {
+            (Box::new(io::empty()), 0)
+        };
+        inode += 1;
+        let mtime = match epoch {
+            Some(epoch) => (metadata.mtime().max(0) as u64).min(epoch),
+            None => metadata.mtime().max(0) as u64,
+        };
+        // The root is a temporary directory, which only its owner can enter
+        let mode = if name == b"." { 0o40755 } else { metadata.mode() as u64 };
+        let rdev = metadata.rdev();
+        write_entry(&mut writer, name, &[
+            inode,
+            mode,
+            metadata.uid() as u64,
+            metadata.gid() as u64,
+            if metadata.is_dir() { 2 } else { 1 },
+            mtime,
+            size,
+            0,
+            0,
+            // The same split of the device number as glibc's major and minor
+            ((rdev >> 8) & 0xfff) | ((rdev >> 32) & 0xffff_f000),
+            (rdev & 0xff) | ((rdev >> 12) & 0xffff_ff00),
+        ], &mut data)?;
+    }
+    write_entry(&mut writer, TRAILER.as_bytes(), &[0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0], &mut io::empty())?;
+    Ok(writer)
+}
+
+/// Writes a header with its fields as 8 hex digits, then the name and data each padded to 4 bytes.
+/// The data has to be as long as the file size field says
+fn write_entry(writer: &mut impl Write, name: &[u8], fields: &[u64; 11], data: &mut impl Read) -> Result<()> {
+    let mut header = String::from(NEWC_MAGIC);
+    for field in fields {
+        header.push_str(&format!("{:08x}", field));
+    }
+    // Name size includes the terminating nul, and no checksum is used
+    header.push_str(&format!("{:08x}{:08x}", name.len() + 1, 0));
+    let mut entry = header.into_bytes();
+    entry.extend_from_slice(name);
+    entry.push(0);
+    entry.resize(entry.len().div_ceil(4) * 4, 0);
+    writer.write_all(&entry)?;
+    let size = fields[6];
+    let written = io::copy(&mut data.take(size), writer)?;
+    if written != size {
+        bail!("{} changed size while it was being archived", String::from_utf8_lossy(name));
+    }
+    writer.write_all(&vec![0u8; (size.div_ceil(4) * 4 - size) as usize])?;
+    Ok(())
+}
@@ -1,7 +1,17 @@
 pub mod application_state;
+pub mod bolt;
+pub mod cache;
 pub mod commands;
+pub mod disk_formats;
 pub mod cli_commands;
 pub mod docker_client;
+pub mod docker_daemon_client;
+pub mod initramfs;
+pub mod local_images;
+pub mod local_stores;
 pub mod models;
+pub mod partition_table;
+pub mod partitions;
 pub mod paths;
-pub mod utils;
\ No newline at end of file
+pub mod utils;
+pub mod verity;
@@ -0,0 +1,275 @@
+use std::{collections::HashMap, fs::{self, File}, path::Path};
+
+use anyhow::{bail, Context, Result};
+use tar::Archive;
+
+use crate::{application_state::Image, models::{input_models::ImageArg, registry_models::{ImageConfig, Layer, LocalImageManifest, OCIDescriptor, OCIIndex, OCIManifest, OCIManifestConfig, Platform, PulledImage, IMAGE_NAME_ANNOTATION, OCI_CONFIG_MEDIA_TYPE, OCI_INDEX_MEDIA_TYPE, OCI_MANIFEST_MEDIA_TYPE, REF_NAME_ANNOTATION}}, paths::get_layers_compressed_path, utils::{create_temp_dir, layer_media_type, read_stored_blob, store_blob, verify_digest}};
+
+/// Reads a `docker save` tarball and moves its layers into the compressed layers folder
+pub fn import_docker_archive(archive_path: &Path) -> Result<PulledImage> {
+    let temp_dir = create_temp_dir()?;
+    let archive = File::open(archive_path).context(format!("Failed to open {}", archive_path.display()))?;
+    Archive::new(archive).unpack(temp_dir.path())?;
+    import_saved_image(temp_dir.path())
+}
+
+/// Reads an unpacked `docker save` archive and moves its layers into the
+/// compressed layers folder. Layers in these archives are usually uncompressed tars
+pub fn import_saved_image(unpacked_path: &Path) -> Result<PulledImage> {
+    let manifests: Vec<LocalImageManifest> = serde_json::from_str(
+        &fs::read_to_string(unpacked_path.join("manifest.json")).context("Archive has no manifest.json")?
+    )?;
+    let manifest = manifests.first().context("Archive manifest.json is empty")?;
+    let config_path = unpacked_path.join(&manifest.config);
+    let digest = digest_from_blob_path(&manifest.config)
+        .context(format!("Could not get the config digest from {}", manifest.config))?;
+    verify_digest(&config_path, &digest)?;
+    let config_bytes = fs::read(&config_path)?;
+    let config: ImageConfig = serde_json::from_slice(&config_bytes)?;
+    store_blob(&config_bytes)?;
+    if manifest.layers.len() != config.rootfs.diff_ids.len() {
+        bail!("Archive lists {} layers but its config has {}", manifest.layers.len(), config.rootfs.diff_ids.len());
+    }
+
+    let compressed_layers_path = get_layers_compressed_path()?;
+    fs::create_dir_all(&compressed_layers_path)?;
+    let mut layers = Vec::new();
+    for (layer_path, diff_id) in manifest.layers.iter().zip(&config.rootfs.diff_ids) {
+        // Newer archives store layers as content addressed blobs, older ones
+        // store uncompressed tars which are identified by their diff id
+        let layer_digest = if layer_path.starts_with("blobs/") {
+            digest_from_blob_path(layer_path).context(format!("Could not get the layer digest from {}", layer_path))?
+        } else {
+            diff_id.clone()
+        };
+        let dest = compressed_layers_path.join(format!("{layer_digest}.tgz"));
+        if !dest.exists() {
+            let source = unpacked_path.join(layer_path);
+            verify_digest(&source, &layer_digest)?;
+            fs::copy(source, &dest)?;
+        }
+        layers.push(layer_digest);
+    }
+
+    // These archives have no registry manifest, so one is made up from the stored blobs
+    let manifest_digest = store_blob(&create_manifest(&digest, config_bytes.len() as u64, &layers)?)?;
+    Ok(PulledImage {
+        digest,
+        manifest_digest,
+        index_digest: None,
+        config,
+        layers,
+    })
+}
+
+/// Creates an OCI manifest for a config and layers that are already stored
+pub fn create_manifest(config_digest: &str, config_size: u64, layers: &[String]) -> Result<Vec<u8>> {
+    let compressed_layers_path = get_layers_compressed_path()?;
+    let layers = layers.iter().map(|digest| {
+        let path = compressed_layers_path.join(format!("{digest}.tgz"));
+        Ok(Layer {
+            media_type: layer_media_type(path.as_std_path())?.to_string(),
+            digest: digest.clone(),
+            size: fs::metadata(&path)?.len(),
+        })
+    }).collect::<Result<Vec<Layer>>>()?;
+    let manifest = OCIManifest {
+        schema_version: 2,
+        media_type: OCI_MANIFEST_MEDIA_TYPE.to_string(),
+        config: OCIManifestConfig {
+            media_type: OCI_CONFIG_MEDIA_TYPE.to_string(),
+            digest: config_digest.to_string(),
+            size: config_size,
+        },
+        layers,
+        annotations: None,
+    };
+    Ok(serde_json::to_vec(&manifest)?)
+}
+
+/// Reads an image from an OCI image layout directory and moves its layers into the
+/// compressed layers folder. Without a tag the layout must hold a single image
+/// or an index with a manifest for the platform
+pub fn import_oci_layout(layout_path: &Path, tag: Option<&str>, platform: &Platform) -> Result<PulledImage> {
+    if !layout_path.join("oci-layout").exists() {
+        bail!("{} is not an OCI image layout", layout_path.display());
+    }
+    let index: OCIIndex = serde_json::from_slice(&fs::read(layout_path.join("index.json"))?)?;
+    let descriptor = match tag {
+        Some(tag) => index.manifests.iter()
+            .find(|m| m.ref_name().is_some_and(|name| name == tag || name.ends_with(&format!(":{tag}"))))
+            .context(format!("Tag {} not found in {}", tag, layout_path.display()))?,
+        None if index.manifests.len() == 1 => &index.manifests[0],
+        None => find_platform_descriptor(&index.manifests, platform)
+            .context(format!("{} holds several images, a tag is required", layout_path.display()))?,
+    };
+    let descriptor = resolve_image_manifest(layout_path, descriptor, platform)?;
+    import_oci_manifest(layout_path, &descriptor.digest)
+}
+
+/// Copies an image manifest, its config and its layers from an OCI layout into the cache
+pub fn import_oci_manifest(layout_path: &Path, manifest_digest: &str) -> Result<PulledImage> {
+    let manifest_bytes = read_blob(layout_path, manifest_digest)?;
+    let manifest: OCIManifest = serde_json::from_slice(&manifest_bytes)?;
+    let config_bytes = read_blob(layout_path, &manifest.config.digest)?;
+    let config: ImageConfig = serde_json::from_slice(&config_bytes)?;
+    store_blob(&manifest_bytes)?;
+    store_blob(&config_bytes)?;
+
+    let compressed_layers_path = get_layers_compressed_path()?;
+    fs::create_dir_all(&compressed_layers_path)?;
+    let mut layers = Vec::new();
+    for layer in &manifest.layers {
+        let dest = compressed_layers_path.join(format!("{}.tgz", layer.digest));
+        if !dest.exists() {
+            let source = blob_path(layout_path, &layer.digest)?;
+            verify_digest(&source, &layer.digest)?;
+            fs::copy(source, &dest)?;
+        }
+        layers.push(layer.digest.clone());
+    }
+
+    Ok(PulledImage {
+        digest: manifest.config.digest,
+        manifest_digest: manifest_digest.to_string(),
+        index_digest: None,
+        config,
+        layers,
+    })
+}
+
+/// Follows image indexes until reaching the image manifest for the platform
+pub fn resolve_image_manifest(layout_path: &Path, descriptor: &OCIDescriptor, platform: &Platform) -> Result<OCIDescriptor> {
+    let mut descriptor = descriptor.clone();
+    while descriptor.is_index() {
+        let index: OCIIndex = serde_json::from_slice(&read_blob(layout_path, &descriptor.digest)?)?;
+        descriptor = find_platform_descriptor(&index.manifests, platform)
+            .context(format!("No manifest for {}/{} in index {}", platform.os, platform.architecture, descriptor.digest))?
+            .clone();
+    }
+    Ok(descriptor)
+}
+
+fn find_platform_descriptor<'a>(descriptors: &'a [OCIDescriptor], platform: &Platform) -> Option<&'a OCIDescriptor> {
+    descriptors.iter().find(|d| d.platform.as_ref() == Some(platform))
+}
+
+/// Gets the path of a blob in an OCI layout
+pub fn blob_path(layout_path: &Path, digest: &str) -> Result<std::path::PathBuf> {
+    let (algorithm, hex) = digest.split_once(':').context(format!("Invalid digest {}", digest))?;
+    Ok(layout_path.join("blobs").join(algorithm).join(hex))
+}
+
+/// Reads a blob from an OCI layout after checking it matches its digest
+pub fn read_blob(layout_path: &Path, digest: &str) -> Result<Vec<u8>> {
+    let path = blob_path(layout_path, digest)?;
+    verify_digest(&path, digest)?;
+    Ok(fs::read(path)?)
+}
+
+/// Turns `blobs/sha256/<hex>` or `<hex>.json` into `sha256:<hex>`
+fn digest_from_blob_path(path: &str) -> Option<String> {
+    let path = Path::new(path);
+    let hex = path.file_stem()?.to_str()?;
+    let algorithm = match path.parent()?.file_name() {
+        Some(name) if path.starts_with("blobs") => name.to_str()?,
+        _ => "sha256",
+    };
+    if hex.is_empty() || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some(format!("{algorithm}:{hex}"))
+}
+
+/// Writes a stored image into an OCI image layout, adding it to the layout's index
+/// under its tag. Returns the manifest digest
+pub fn export_oci_layout(layout_path: &Path, image: &Image) -> Result<String> {
+    let manifest_digest = image.manifest_digest.as_ref()
+        .context(format!("{}:{} was stored before manifests were kept, build it again to export it", image.name, image.tag))?;
+    let manifest_bytes = read_stored_blob(manifest_digest)?;
+    let manifest: OCIManifest = serde_json::from_slice(&manifest_bytes)?;
+    let config_bytes = read_stored_blob(&manifest.config.digest)?;
+
+    fs::create_dir_all(layout_path.join("blobs"))?;
+    fs::write(layout_path.join("oci-layout"), r#"{"imageLayoutVersion":"1.0.0"}"#)?;
+    write_blob(layout_path, manifest_digest, &manifest_bytes)?;
+    write_blob(layout_path, &manifest.config.digest, &config_bytes)?;
+    let compressed_layers_path = get_layers_compressed_path()?;
+    for layer in &manifest.layers {
+        let dest = blob_path(layout_path, &layer.digest)?;
+        if !dest.exists() {
+            let source = compressed_layers_path.join(format!("{}.tgz", layer.digest));
+            verify_digest(source.as_std_path(), &layer.digest)?;
+            fs::create_dir_all(dest.parent().context("Blob path has no parent")?)?;
+            fs::copy(source, &dest)?;
+        }
+    }
+
+    let index_path = layout_path.join("index.json");
+    let mut index = if index_path.exists() {
+        serde_json::from_slice(&fs::read(&index_path)?)?
+    } else {
+        OCIIndex {
+            schema_version: 2,
+            media_type: Some(OCI_INDEX_MEDIA_TYPE.to_string()),
+            manifests: Vec::new(),
+        }
+    };
+    let mut annotations = HashMap::from([(REF_NAME_ANNOTATION.to_string(), image.tag.clone())]);
+    // Images read from local archives are named after their path which isn't worth keeping
+    let reference = format!("{}:{}", image.name, image.tag);
+    if ImageArg::from(reference.clone()).transport.is_none() {
+        annotations.insert(IMAGE_NAME_ANNOTATION.to_string(), reference);
+    }
+    // A reference can only point at one manifest per platform. Other images in the layout can
+    // share the tag, so the image name has to match as well
+    let image_name = annotations.get(IMAGE_NAME_ANNOTATION).cloned();
+    index.manifests.retain(|m| !(m.ref_name() == Some(&image.tag) && m.image_name() == image_name.as_ref() && m.platform.as_ref() == Some(&image.platform)));
+    index.manifests.push(OCIDescriptor {
+        media_type: if manifest.media_type.is_empty() { OCI_MANIFEST_MEDIA_TYPE.to_string() } else { manifest.media_type.clone() },
+        digest: manifest_digest.clone(),
+        size: manifest_bytes.len() as u64,
+        platform: Some(image.platform.clone()),
+        annotations: Some(annotations),
+    });
+    fs::write(index_path, serde_json::to_vec_pretty(&index)?)?;
+    Ok(manifest_digest.clone())
+}
+
+/// Copies every image in an OCI layout into the cache, returning the
+/// name and tag each one should be stored under
+pub fn import_oci_layout_images(layout_path: &Path) -> Result<Vec<(ImageArg, PulledImage)>> {
+    if !layout_path.join("oci-layout").exists() {
+        bail!("{} is not an OCI image layout", layout_path.display());
+    }
+    let index: OCIIndex = serde_json::from_slice(&fs::read(layout_path.join("index.json"))?)?;
+    let mut images = Vec::new();
+    for descriptor in index.manifests.iter().filter(|m| !m.is_attestation()) {
+        let image = match descriptor.image_name() {
+            Some(name) => ImageArg::from(name.clone()),
+            None => ImageArg::from(format!(
+                "oci:{}:{}",
+                layout_path.display(),
+                descriptor.ref_name().map(String::as_str).unwrap_or("latest")
+            )),
+        };
+        // Indexes are imported for every platform they contain, leaving out the attestations buildkit adds
+        let manifests = if descriptor.is_index() {
+            let nested: OCIIndex = serde_json::from_slice(&read_blob(layout_path, &descriptor.digest)?)?;
+            nested.manifests.into_iter().filter(|m| !m.is_index() && !m.is_attestation()).collect()
+        } else {
+            vec![descriptor.clone()]
+        };
+        for manifest in manifests {
+            images.push((image.clone(), import_oci_manifest(layout_path, &manifest.digest)?));
+        }
+    }
+    Ok(images)
+}
+
+fn write_blob(layout_path: &Path, digest: &str, bytes: &[u8]) -> Result<()> {
+    let path = blob_path(layout_path, digest)?;
+    fs::create_dir_all(path.parent().context("Blob path has no parent")?)?;
+    fs::write(path, bytes)?;
+    Ok(())
+}
@@ -0,0 +1,404 @@
+use std::{collections::HashMap, fs::{self, File}, io::{self, BufRead, BufReader, Read, Write}, path::Path};
+
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use flate2::read::GzDecoder;
+
+use crate::{bolt::BoltDb, local_images::{create_manifest, import_oci_manifest, resolve_image_manifest}, models::registry_models::{ImageConfig, OCIDescriptor, Platform, PulledImage, StorageImage, StorageLayer, TarSplitEntry}, paths::get_layers_compressed_path, utils::{store_blob, verify_digest}};
+
+pub const DEFAULT_CONTAINERD_ROOT: &str = "/var/lib/containerd";
+pub const DEFAULT_STORAGE_ROOT: &str = "/var/lib/containers/storage";
+
+/// Reads an image straight out of containerd's content store, resolving the
+/// name through its metadata database. The compressed blobs are reused as they are
+pub fn import_containerd_image(root: &Path, namespace: &str, image: &str, platform: &Platform) -> Result<PulledImage> {
+    let db = BoltDb::open(&root.join("io.containerd.metadata.v1.bolt/meta.db"))?;
+    let root_bucket = db.root_bucket();
+    let images = root_bucket.bucket_path(&[b"v1", namespace.as_bytes(), b"images"])?
+        .context(format!("containerd namespace {} has no images", namespace))?;
+    let mut target = None;
+    for name in candidate_names(image) {
+        if let Some(bucket) = images.bucket_path(&[name.as_bytes(), b"target"])? {
+            target = Some(bucket);
+            break;
+        }
+    }
+    let target = target.context(format!("Image {} not found in containerd namespace {}", image, namespace))?;
+    let descriptor = OCIDescriptor {
+        media_type: String::from_utf8(target.get(b"mediatype")?.context("Image target has no media type")?.to_vec())?,
+        digest: String::from_utf8(target.get(b"digest")?.context("Image target has no digest")?.to_vec())?,
+        size: 0,
+        platform: None,
+        annotations: None,
+    };
+    // The content store lays blobs out the same way an OCI layout does
+    let content_path = root.join("io.containerd.content.v1.content");
+    let descriptor = resolve_image_manifest(&content_path, &descriptor, platform)?;
+    import_oci_manifest(&content_path, &descriptor.digest)
+}
+
+/// Reads an image out of containers/storage, which Podman and Buildah use.
+/// Compressed blobs aren't kept there, so the uncompressed layer tars are
+/// reassembled from the tar-split data and stored under their diff ids
+pub fn import_podman_image(root: &Path, image: &str) -> Result<PulledImage> {
+    let images: Vec<StorageImage> = serde_json::from_slice(
+        &fs::read(root.join("overlay-images/images.json")).context("Only the overlay storage driver is supported")?
+    )?;
+    let candidates = candidate_names(image);
+    let stored = images.iter()
+        .find(|i| i.names.as_ref().is_some_and(|names| names.iter().any(|n| candidates.contains(n))))
+        .context(format!("Image {} not found in {}", image, root.display()))?;
+
+    let digest = format!("sha256:{}", stored.id);
+    // Big data items are stored under their key, base64 encoded when it isn't a valid file name
+    let config_path = root.join("overlay-images").join(&stored.id).join(format!("={}", STANDARD.encode(&digest)));
+    verify_digest(&config_path, &digest)?;
+    let config_bytes = fs::read(&config_path)?;
+    let config: ImageConfig = serde_json::from_slice(&config_bytes)?;
+    store_blob(&config_bytes)?;
+
+    let storage_layers: Vec<StorageLayer> = serde_json::from_slice(&fs::read(root.join("overlay-layers/layers.json"))?)?;
+    let storage_layers: HashMap<&str, &StorageLayer> = storage_layers.iter().map(|l| (l.id.as_str(), l)).collect();
+    // Walk from the top layer down through the parents
+    let mut chain = Vec::new();
+    let mut next = stored.layer.as_deref();
+    while let Some(id) = next {
+        let layer = storage_layers.get(id).context(format!("Layer {} not found", id))?;
+        chain.push(*layer);
+        next = layer.parent.as_deref();
+    }
+    chain.reverse();
+
+    let compressed_layers_path = get_layers_compressed_path()?;
+    fs::create_dir_all(&compressed_layers_path)?;
+    let mut layers = Vec::new();
+    for layer in chain {
+        let diff_digest = layer.diff_digest.clone().context(format!("Layer {} has no diff digest", layer.id))?;
+        let dest = compressed_layers_path.join(format!("{diff_digest}.tgz"));
+        if !dest.exists() {
+            let partial = compressed_layers_path.join(format!("{diff_digest}.partial"));
+            assemble_layer(root, &layer.id, partial.as_std_path())?;
+            verify_digest(partial.as_std_path(), &diff_digest)?;
+            fs::rename(&partial, &dest)?;
+        }
+        layers.push(diff_digest);
+    }
+
+    let manifest_digest = store_blob(&create_manifest(&digest, config_bytes.len() as u64, &layers)?)?;
+    Ok(PulledImage {
+        digest,
+        manifest_digest,
+        index_digest: None,
+        config,
+        layers,
+    })
+}
+
+/// Rebuilds the original uncompressed layer tar from its tar-split file and diff directory
+fn assemble_layer(root: &Path, layer_id: &str, dest: &Path) -> Result<()> {
+    let tar_split_path = root.join("overlay-layers").join(format!("{layer_id}.tar-split.gz"));
+    let tar_split = BufReader::new(GzDecoder::new(File::open(&tar_split_path)
+        .context(format!("Failed to open {}", tar_split_path.display()))?));
+    let diff_path = root.join("overlay").join(layer_id).join("diff");
+    let mut output = File::create(dest)?;
+    for line in tar_split.lines() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let entry: TarSplitEntry = serde_json::from_str(&line)?;
+        match entry.entry_type {
+            1 => {
+                let size = entry.size.unwrap_or(0);
+                if size == 0 {
+                    continue;
+                }
+                let name = match (entry.name, entry.name_raw) {
+                    (_, Some(raw)) => String::from_utf8(STANDARD.decode(raw)?)?,
+                    (Some(name), None) => name,
+                    (None, None) => bail!("tar-split file entry has no name"),
+                };
+                let mut file = File::open(diff_path.join(&name)).context(format!("Failed to open {} in layer {}", name, layer_id))?.take(size);
+                if io::copy(&mut file, &mut output)? != size {
+                    bail!("{} in layer {} is shorter than its tar entry", name, layer_id);
+                }
+            },
+            2 => output.write_all(&STANDARD.decode(entry.payload.unwrap_or_default())?)?,
+            other => bail!("Unknown tar-split entry type {}", other),
+        }
+    }
+    Ok(())
+}
+
+/// Names an image could be stored under, since both stores keep fully qualified
+/// names while users usually type the short docker style ones
+fn candidate_names(image: &str) -> Vec<String> {
+    let mut names = vec![image.to_string()];
+    let first = image.split('/').next().unwrap_or_default();
+    let has_domain = image.contains('/') && (first.contains('.') || first.contains(':') || first == "localhost");
+    if !has_domain {
+        if image.contains('/') {
+            names.push(format!("docker.io/{image}"));
+        } else {
+            names.push(format!("docker.io/library/{image}"));
+        }
+        // Images built by podman itself live under localhost
+        names.push(format!("localhost/{image}"));
+    }
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Mutex};
+
+    use camino::Utf8PathBuf;
+    use flate2::{write::GzEncoder, Compression};
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    use crate::{bolt::{tests::{bucket_value, database, inline_bucket_value, leaf_page, meta_page, page_header, FREELIST_PAGE_FLAG}, BUCKET_LEAF_FLAG}, models::registry_models::{OCI_CONFIG_MEDIA_TYPE, OCI_INDEX_MEDIA_TYPE, OCI_LAYER_GZIP_MEDIA_TYPE, OCI_MANIFEST_MEDIA_TYPE}, paths::{get_blobs_path, BASE_PATH}, utils::sha256_bytes};
+
+    use super::*;
+
+    /// The base path is global, so the tests that import into it take turns
+    static BASE_PATH_GUARD: Mutex<()> = Mutex::new(());
+
+    /// Points the base path at a new temporary directory for as long as the guard is held
+    fn with_base_path(test: impl FnOnce(&Path)) {
+        let _guard = BASE_PATH_GUARD.lock().unwrap_or_else(|e| e.into_inner());
+        let base = tempfile::tempdir().unwrap();
+        *BASE_PATH.write().unwrap() = Utf8PathBuf::from_path_buf(base.path().to_path_buf()).unwrap();
+        test(base.path());
+    }
+
+    fn platform() -> Platform {
+        Platform { architecture: "amd64".to_string(), os: "linux".to_string() }
+    }
+
+    fn config_bytes(diff_ids: &[String]) -> Vec<u8> {
+        serde_json::to_vec(&json!({
+            "architecture": "amd64",
+            "os": "linux",
+            "config": { "Cmd": ["/bin/sh"] },
+            "rootfs": { "type": "layers", "diff_ids": diff_ids },
+        })).unwrap()
+    }
+
+    /// Writes a blob into a content store laid out like an OCI layout, returning its digest
+    fn write_blob(content_path: &Path, bytes: &[u8]) -> String {
+        let digest = sha256_bytes(bytes);
+        let path = content_path.join("blobs/sha256").join(digest.trim_start_matches("sha256:"));
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, bytes).unwrap();
+        digest
+    }
+
+    /// A containerd root with an index for the platform in its content store, and a
+    /// metadata database naming it `docker.io/library/alpine:latest` in the default namespace.
+    /// Returns the root, the config digest and the layer digest
+    fn containerd_root() -> (TempDir, String, String) {
+        let root = tempfile::tempdir().unwrap();
+        let content_path = root.path().join("io.containerd.content.v1.content");
+        let layer_digest = write_blob(&content_path, b"\x1f\x8bnot really a layer");
+        let config = config_bytes(std::slice::from_ref(&layer_digest));
+        let config_digest = write_blob(&content_path, &config);
+        let manifest = serde_json::to_vec(&json!({
+            "schemaVersion": 2,
+            "mediaType": OCI_MANIFEST_MEDIA_TYPE,
+            "config": { "mediaType": OCI_CONFIG_MEDIA_TYPE, "digest": config_digest, "size": config.len() },
+            "layers": [{ "mediaType": OCI_LAYER_GZIP_MEDIA_TYPE, "digest": layer_digest, "size": 20 }],
+        })).unwrap();
+        let manifest_digest = write_blob(&content_path, &manifest);
+        let index = serde_json::to_vec(&json!({
+            "schemaVersion": 2,
+            "mediaType": OCI_INDEX_MEDIA_TYPE,
+            "manifests": [
+                { "mediaType": OCI_MANIFEST_MEDIA_TYPE, "digest": sha256_bytes(b"other"), "size": 5, "platform": { "architecture": "arm64", "os": "linux" } },
+                { "mediaType": OCI_MANIFEST_MEDIA_TYPE, "digest": manifest_digest, "size": manifest.len(), "platform": { "architecture": "amd64", "os": "linux" } },
+            ],
+        })).unwrap();
+        let index_digest = write_blob(&content_path, &index);
+
+        let target = inline_bucket_value(&[
+            (0, b"digest", index_digest.into_bytes()),
+            (0, b"mediatype", OCI_INDEX_MEDIA_TYPE.as_bytes().to_vec()),
+        ]);
+        let pages = vec![
+            meta_page(0, 3, 1),
+            meta_page(1, 3, 0),
+            page_header(2, FREELIST_PAGE_FLAG, 0),
+            leaf_page(3, &[(BUCKET_LEAF_FLAG, b"v1", bucket_value(4))]),
+            leaf_page(4, &[(BUCKET_LEAF_FLAG, b"default", bucket_value(5))]),
+            leaf_page(5, &[(BUCKET_LEAF_FLAG, b"images", bucket_value(6))]),
+            leaf_page(6, &[(BUCKET_LEAF_FLAG, b"docker.io/library/alpine:latest", bucket_value(7))]),
+            leaf_page(7, &[(BUCKET_LEAF_FLAG, b"target", target)]),
+        ];
+        let db_path = root.path().join("io.containerd.metadata.v1.bolt/meta.db");
+        fs::create_dir_all(db_path.parent().unwrap()).unwrap();
+        fs::write(db_path, database(&pages)).unwrap();
+        (root, config_digest, layer_digest)
+    }
+
+    /// Builds a layer tar out of files and directories, writing the files into the layer's diff
+    /// directory and everything else into its tar-split the way containers/storage records it.
+    /// A name given as `raw:<name>` is recorded base64 encoded. Returns the tar's digest
+    fn write_layer(root: &Path, layer_id: &str, entries: &[(&str, Option<&[u8]>)]) -> String {
+        let diff_path = root.join("overlay").join(layer_id).join("diff");
+        fs::create_dir_all(&diff_path).unwrap();
+        let mut layer = Vec::new();
+        let mut tar_split = Vec::new();
+        let segment = |layer: &mut Vec<u8>, tar_split: &mut Vec<serde_json::Value>, bytes: &[u8]| {
+            layer.extend_from_slice(bytes);
+            tar_split.push(json!({ "type": 2, "payload": STANDARD.encode(bytes) }));
+        };
+        for (name, contents) in entries {
+            let (name, raw) = match name.strip_prefix("raw:") {
+                Some(name) => (name, true),
+                None => (*name, false),
+            };
+            let mut header = tar::Header::new_gnu();
+            header.set_path(name).unwrap();
+            header.set_size(contents.map_or(0, |contents| contents.len() as u64));
+            header.set_mode(if contents.is_some() { 0o644 } else { 0o755 });
+            header.set_entry_type(if contents.is_some() { tar::EntryType::Regular } else { tar::EntryType::Directory });
+            header.set_cksum();
+            segment(&mut layer, &mut tar_split, header.as_bytes());
+            let size = contents.map_or(0, |contents| contents.len());
+            let mut entry = json!({ "type": 1, "size": size });
+            if raw {
+                entry["name_raw"] = json!(STANDARD.encode(name));
+            } else {
+                entry["name"] = json!(name);
+            }
+            tar_split.push(entry);
+            match contents {
+                Some(contents) => {
+                    fs::write(diff_path.join(name), contents).unwrap();
+                    layer.extend_from_slice(contents);
+                    segment(&mut layer, &mut tar_split, &vec![0; (512 - size % 512) % 512]);
+                },
+                None => fs::create_dir_all(diff_path.join(name)).unwrap(),
+            }
+        }
+        segment(&mut layer, &mut tar_split, &[0; 1024]);
+
+        let tar_split_path = root.join("overlay-layers").join(format!("{layer_id}.tar-split.gz"));
+        fs::create_dir_all(tar_split_path.parent().unwrap()).unwrap();
+        let mut encoder = GzEncoder::new(File::create(tar_split_path).unwrap(), Compression::default());
+        for line in tar_split {
+            writeln!(encoder, "{line}").unwrap();
+        }
+        encoder.finish().unwrap();
+        sha256_bytes(&layer)
+    }
+
+    /// A containers/storage root with a two layer image named `docker.io/library/app:latest`.
+    /// Returns the root, the config digest and the layer digests from the bottom up
+    fn storage_root() -> (TempDir, String, Vec<String>) {
+        let root = tempfile::tempdir().unwrap();
+        let base = write_layer(root.path(), "base", &[
+            ("etc", None),
+            ("etc/hostname", Some(b"app\n")),
+            ("empty", Some(b"")),
+        ]);
+        let top = write_layer(root.path(), "top", &[
+            ("raw:bin", None),
+            ("raw:bin/run me", Some(&[b'x'; 700])),
+        ]);
+        let config = config_bytes(&[base.clone(), top.clone()]);
+        let config_digest = sha256_bytes(&config);
+        let id = config_digest.trim_start_matches("sha256:");
+        let images_path = root.path().join("overlay-images");
+        fs::create_dir_all(images_path.join(id)).unwrap();
+        fs::write(images_path.join(id).join(format!("={}", STANDARD.encode(&config_digest))), &config).unwrap();
+        fs::write(images_path.join("images.json"), serde_json::to_vec(&json!([
+            { "id": "0".repeat(64), "names": ["docker.io/library/other:latest"], "layer": "base" },
+            { "id": id, "names": ["docker.io/library/app:latest"], "layer": "top" },
+        ])).unwrap()).unwrap();
+        fs::write(root.path().join("overlay-layers/layers.json"), serde_json::to_vec(&json!([
+            { "id": "top", "parent": "base", "diff-digest": top },
+            { "id": "base", "diff-digest": base },
+        ])).unwrap()).unwrap();
+        (root, config_digest, vec![base, top])
+    }
+
+    fn layer_path(base: &Path, digest: &str) -> PathBuf {
+        base.join("layers_compressed").join(format!("{digest}.tgz"))
+    }
+
+    #[test]
+    fn candidate_names_add_the_docker_hub_and_localhost_prefixes() {
+        assert_eq!(candidate_names("alpine:3"), ["alpine:3", "docker.io/library/alpine:3", "localhost/alpine:3"]);
+        assert_eq!(candidate_names("user/app"), ["user/app", "docker.io/user/app", "localhost/user/app"]);
+        assert_eq!(candidate_names("quay.io/user/app"), ["quay.io/user/app"]);
+        assert_eq!(candidate_names("localhost:5000/app"), ["localhost:5000/app"]);
+        assert_eq!(candidate_names("localhost/app"), ["localhost/app"]);
+    }
+
+    #[test]
+    fn reassembles_layers_from_their_tar_split() {
+        let (root, _, layers) = storage_root();
+        let dir = tempfile::tempdir().unwrap();
+        let expected: [&[&str]; 2] = [&["etc", "etc/hostname", "empty"], &["bin", "bin/run me"]];
+        for ((layer_id, diff_digest), expected) in ["base", "top"].iter().zip(&layers).zip(expected) {
+            let dest = dir.path().join(layer_id);
+            assemble_layer(root.path(), layer_id, &dest).unwrap();
+            assert_eq!(sha256_bytes(&fs::read(&dest).unwrap()), *diff_digest);
+            let names = tar::Archive::new(File::open(&dest).unwrap()).entries().unwrap()
+                .map(|entry| entry.unwrap().path().unwrap().display().to_string())
+                .collect::<Vec<String>>();
+            assert_eq!(names, expected);
+        }
+    }
+
+    #[test]
+    fn refuses_a_layer_whose_file_was_truncated() {
+        let (root, _, _) = storage_root();
+        fs::write(root.path().join("overlay/top/diff/bin/run me"), b"short").unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        assert!(assemble_layer(root.path(), "top", &dir.path().join("top")).is_err());
+    }
+
+    #[test]
+    fn imports_podman_images_by_their_short_name() {
+        let (root, config_digest, layers) = storage_root();
+        with_base_path(|base| {
+            let image = import_podman_image(root.path(), "app:latest").unwrap();
+            assert_eq!(image.digest, config_digest);
+            assert_eq!(image.layers, layers);
+            assert_eq!(image.config.rootfs.diff_ids, layers);
+            for digest in &layers {
+                assert_eq!(sha256_bytes(&fs::read(layer_path(base, digest)).unwrap()), *digest);
+            }
+            assert!(get_blobs_path().unwrap().join(&image.manifest_digest).exists());
+            assert!(import_podman_image(root.path(), "missing").is_err());
+        });
+    }
+
+    #[test]
+    fn refuses_podman_layers_that_dont_match_their_diff_digest() {
+        let (root, _, _) = storage_root();
+        fs::write(root.path().join("overlay/base/diff/etc/hostname"), b"bad\n").unwrap();
+        with_base_path(|base| {
+            assert!(import_podman_image(root.path(), "app:latest").is_err());
+            assert!(!base.join("layers_compressed").read_dir().unwrap().any(|entry| entry.unwrap().path().extension().is_some_and(|e| e == "tgz")));
+        });
+    }
+
+    #[test]
+    fn imports_containerd_images_through_the_index() {
+        let (root, config_digest, layer_digest) = containerd_root();
+        with_base_path(|base| {
+            let image = import_containerd_image(root.path(), "default", "alpine:latest", &platform()).unwrap();
+            assert_eq!(image.digest, config_digest);
+            assert_eq!(image.layers, std::slice::from_ref(&layer_digest));
+            assert_eq!(fs::read(layer_path(base, &layer_digest)).unwrap(), b"\x1f\x8bnot really a layer");
+            assert!(get_blobs_path().unwrap().join(&image.manifest_digest).exists());
+            assert!(import_containerd_image(root.path(), "default", "missing", &platform()).is_err());
+            assert!(import_containerd_image(root.path(), "other", "alpine:latest", &platform()).is_err());
+            let arm = Platform { architecture: "riscv64".to_string(), os: "linux".to_string() };
+            assert!(import_containerd_image(root.path(), "default", "alpine:latest", &arm).is_err());
+        });
+    }
+}
@@ -3,9 +3,8 @@ use std::fs;
 use camino::Utf8PathBuf;
 
 use serde_json::json;
-use whaledrive::{cli_commands::check_required_commands_exist, models::input_models::{BuildImageArgs, ImageInfoArgs, RemoveImageArgs}, paths::BASE_PATH, utils::UnwrapOrPanicJson};
+use whaledrive::{cli_commands::check_required_commands_exist, models::input_models::{BuildImageArgs, ExportImageArgs, ExportRootfsArgs, ImageInfoArgs, ImportImagesArgs, ListImagesArgs, OutputFormat, PruneArgs, PurgeArgs, RemoveImageArgs, TagImageArgs, UntagImageArgs}, paths::BASE_PATH, utils::UnwrapOrPanicJson};
 use anyhow::Result;
-use tokio;
 use clap::{Args, Parser, Subcommand};
 
 #[derive(Debug, Parser)]
@@ -24,12 +23,26 @@ pub enum Command {
     Info(ImageInfoArgs),
     /// Create image from a registry
     Build(BuildImageArgs),
-    /// List all images that are currently stored
-    Images,
+    /// List the images that are currently stored
+    Images(ListImagesArgs),
     /// Remove an image
     Rm(RemoveImageArgs),
-    /// Remove all images not refered to by a tag and all layers nor associated with an image
-    Prune,
+    /// Point another name:tag at a stored image
+    Tag(TagImageArgs),
+    /// Remove a name:tag without removing the image it points at
+    Untag(UntagImageArgs),
+    /// Remove all images not refered to by a tag, their drive images, all layers not associated with an image and partial downloads
+    Prune(PruneArgs),
+    /// Show the disk space used by layers, drive images and each image
+    Df,
+    /// Remove all images, layers and state after asking for confirmation
+    Purge(PurgeArgs),
+    /// Write a stored image to an OCI image layout
+    Export(ExportImageArgs),
+    /// Seed the cache with the images in an OCI image layout
+    Import(ImportImagesArgs),
+    /// Write the flattened root filesystem of an image to a tarball or directory
+    ExportRootfs(ExportRootfsArgs),
 }
 
 #[derive(Debug, Args)]
@@ -47,32 +60,41 @@ async fn main() -> Result<()> {
         Ok(result) => println!("{result}"),
         Err(e) => eprintln!("{}", json!({
             "error": e.to_string()
-        }).to_string())
+        }))
     }
     Ok(())
 }
 
 
 async fn wrapped_main() -> Result<String> {
-    // Check if all required commands exist
-    check_required_commands_exist()?;
-
     let command = App::parse();
 
-    // Update global base path based on CLI arguments
-    let mut base_path_lock = BASE_PATH.write().map_err(|_|{anyhow::anyhow!("Failed to get state path lock")})?;
-    *base_path_lock = command.global_opts.base_path.clone();
+    // Check if all required commands exist, only building a drive image needs them
+    if matches!(&command.command, Command::Build(args) if args.output_format != OutputFormat::Initramfs) {
+        check_required_commands_exist()?;
+    }
+
+    // Update global base path based on CLI arguments. The lock lives in its own
+    // scope so it is released before any command runs and we don't get deadlocked
+    {
+        let mut base_path_lock = BASE_PATH.write().map_err(|_|{anyhow::anyhow!("Failed to get state path lock")})?;
+        *base_path_lock = command.global_opts.base_path.clone();
+    }
     // Create the base path if it doesn't exist
     fs::create_dir_all(command.global_opts.base_path.as_path())?;
 
-    // Needs to be dropped early so we don't get deadlocked
-    drop(base_path_lock);
-
     match command.command {
         Command::Info(args) => whaledrive::commands::image_info(args).await,
         Command::Build(args) => whaledrive::commands::build_image(args).await,
-        Command::Prune => whaledrive::commands::prune(),
-        Command::Images => whaledrive::commands::list_images(),
+        Command::Prune(args) => whaledrive::commands::prune(args),
+        Command::Df => whaledrive::commands::disk_usage(),
+        Command::Purge(args) => whaledrive::commands::purge(args),
+        Command::Images(args) => whaledrive::commands::list_images(args),
         Command::Rm(args) => whaledrive::commands::remove_image(args),
+        Command::Tag(args) => whaledrive::commands::tag_image(args),
+        Command::Untag(args) => whaledrive::commands::untag_image(args),
+        Command::Export(args) => whaledrive::commands::export_image(args),
+        Command::Import(args) => whaledrive::commands::import_images(args),
+        Command::ExportRootfs(args) => whaledrive::commands::export_rootfs(args).await,
     }
 }
\ No newline at end of file
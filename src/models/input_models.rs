@@ -1,5 +1,23 @@
-use camino::{Utf8Path, Utf8PathBuf};
-use clap::Args;
+use std::{fmt::Display, fs, str::FromStr};
+
+use anyhow::{bail, Context, Result};
+use camino::Utf8PathBuf;
+use clap::{Args, ValueEnum};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::paths::get_config_path;
+
+/// An image stored on the local filesystem rather than in a registry
+#[derive(Debug, Clone)]
+pub enum ImageTransport {
+    /// `docker-archive:<path>`, a tarball created by `docker save`
+    DockerArchive(Utf8PathBuf),
+    /// `oci:<path>[:tag]`, an OCI image layout directory
+    OciLayout {
+        path: Utf8PathBuf,
+        tag: Option<String>,
+    },
+}
 
 #[derive(Debug, Clone)]
 pub struct ImageArg {
@@ -7,28 +25,342 @@ pub struct ImageArg {
     pub name: String,
     /// The tag of the image(eg: latest)
     pub tag: String,
+    /// The manifest or index digest the image is pinned to with `name@sha256:...`
+    pub digest: Option<String>,
+    /// Set when the image is read from a local archive or layout
+    pub transport: Option<ImageTransport>,
 }
 
 impl From<String> for ImageArg {
     fn from(name: String) -> Self {
-        let parts = name.split(":").collect::<Vec<&str>>();
-        if parts.len() == 2 {
-            ImageArg {
-                name: parts[0].to_string(),
-                tag: parts[1].to_string()
-            }
-        } else {
-            ImageArg {
-                name,
-                tag: String::from("latest")
+        if let Some(path) = name.strip_prefix("docker-archive:") {
+            return ImageArg {
+                name: name.clone(),
+                tag: String::from("latest"),
+                digest: None,
+                transport: Some(ImageTransport::DockerArchive(Utf8PathBuf::from(path))),
+            };
+        }
+        if let Some(reference) = name.strip_prefix("oci:") {
+            // Only the last component of the path can carry a tag
+            let (path, tag) = match reference.rsplit_once(':') {
+                Some((path, tag)) if !tag.contains('/') => (path, Some(tag.to_string())),
+                _ => (reference, None),
+            };
+            return ImageArg {
+                name: format!("oci:{path}"),
+                tag: tag.clone().unwrap_or(String::from("latest")),
+                digest: None,
+                transport: Some(ImageTransport::OciLayout { path: Utf8PathBuf::from(path), tag }),
+            };
+        }
+        let (reference, digest) = match name.split_once('@') {
+            Some((reference, digest)) => (reference, Some(digest.to_string())),
+            None => (name.as_str(), None),
+        };
+        // Only a colon after the last slash starts the tag, an earlier one is a registry port
+        let (name, tag) = match reference.rsplit_once(':') {
+            Some((name, tag)) if !tag.contains('/') => (name, tag),
+            _ => (reference, "latest"),
+        };
+        ImageArg {
+            name: name.to_string(),
+            tag: tag.to_string(),
+            digest,
+            transport: None,
+        }
+    }
+}
+
+impl ImageArg {
+    /// What the image is stored under in the tagged images, name:tag or name@digest when it's pinned
+    pub fn tagged_reference(&self) -> String {
+        match &self.digest {
+            Some(digest) => format!("{}@{}", self.name, digest),
+            None => format!("{}:{}", self.name, self.tag),
+        }
+    }
+
+    /// The tag or digest to ask the registry for
+    pub fn manifest_reference(&self) -> &str {
+        self.digest.as_deref().unwrap_or(&self.tag)
+    }
+}
+
+impl Display for ImageArg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.transport {
+            // The archive path can't carry a tag
+            Some(ImageTransport::DockerArchive(_)) => write!(f, "{}", self.name),
+            _ => write!(f, "{}", self.tagged_reference()),
+        }
+    }
+}
+
+/// Where the image to build is read from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ImageSourceKind {
+    /// Pull the image from a registry
+    Registry,
+    /// Export the image from the local docker daemon
+    DockerDaemon,
+    /// Read the image from containerd's content store
+    Containerd,
+    /// Read the image from containers/storage, as used by Podman
+    Podman,
+}
+
+/// When a registry image is pulled rather than built from the cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum PullPolicy {
+    /// Always fetch the manifests, downloading the layers that aren't cached
+    Always,
+    /// Build from the cache when the reference is stored along with everything it needs, otherwise pull it
+    Missing,
+    /// Only build from the cache, failing without network access when anything is missing
+    Never,
+}
+
+/// The file format the drive image is written in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// A plain disk image
+    Raw,
+    /// A QEMU copy-on-write (version 3) image
+    Qcow2,
+    /// A fixed size VHD, as Azure expects
+    Vhd,
+    /// A dynamically sized VHDX for Hyper-V
+    Vhdx,
+    /// A stream optimized VMDK for VMware and OVA packages
+    Vmdk,
+    /// A newc cpio archive of the root filesystem to boot from RAM, instead of a disk
+    Initramfs,
+}
+
+impl OutputFormat {
+    /// The file extension images in this format are stored with
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Raw => "img",
+            OutputFormat::Qcow2 => "qcow2",
+            OutputFormat::Vhd => "vhd",
+            OutputFormat::Vhdx => "vhdx",
+            OutputFormat::Vmdk => "vmdk",
+            OutputFormat::Initramfs => "cpio",
+        }
+    }
+}
+
+/// Compression algorithms for outputs that offer a choice
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum CompressionAlgorithm {
+    #[default]
+    Gzip,
+    Zstd,
+}
+
+impl CompressionAlgorithm {
+    /// The suffix added to the file extension of compressed outputs
+    pub fn extension(&self) -> &'static str {
+        match self {
+            CompressionAlgorithm::Gzip => "gz",
+            CompressionAlgorithm::Zstd => "zst",
+        }
+    }
+}
+
+/// Filesystems the root partition can be created with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Filesystem {
+    #[default]
+    Ext4,
+    /// A compressed read only filesystem
+    Squashfs,
+    /// A compressed read only filesystem with faster random access than squashfs
+    Erofs,
+    Xfs,
+    Btrfs,
+}
+
+impl Filesystem {
+    /// The name mount and fstab know the filesystem by
+    pub fn name(&self) -> &'static str {
+        match self {
+            Filesystem::Ext4 => "ext4",
+            Filesystem::Squashfs => "squashfs",
+            Filesystem::Erofs => "erofs",
+            Filesystem::Xfs => "xfs",
+            Filesystem::Btrfs => "btrfs",
+        }
+    }
+
+    /// The command that creates the filesystem
+    pub fn command(&self) -> &'static str {
+        match self {
+            Filesystem::Ext4 => "mkfs.ext4",
+            Filesystem::Squashfs => "mksquashfs",
+            Filesystem::Erofs => "mkfs.erofs",
+            Filesystem::Xfs => "mkfs.xfs",
+            Filesystem::Btrfs => "mkfs.btrfs",
+        }
+    }
+
+    /// Read only filesystems are built as a file sized to their contents
+    pub fn is_read_only(&self) -> bool {
+        matches!(self, Filesystem::Squashfs | Filesystem::Erofs)
+    }
+}
+
+/// The kind of partition table written to the drive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum PartitionTableType {
+    /// An MBR partition table, which BIOS boot loaders read
+    #[default]
+    #[value(alias = "mbr")]
+    Dos,
+    Gpt,
+}
+
+/// What a partition of a custom layout is for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PartitionKind {
+    /// Mounted at /boot unless another mountpoint is given, and marked as the one to boot
+    Boot,
+    /// The partition mounted at /, which holds everything not on another partition
+    Root,
+    Swap,
+    /// Holds the directory of the image at its mountpoint, like /var or /home
+    #[default]
+    Data,
+    /// Keeps the changes to the directories overlaid with --overlay, mounted at /overlay
+    /// unless another mountpoint is given
+    Overlay,
+}
+
+impl PartitionKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PartitionKind::Boot => "boot",
+            PartitionKind::Root => "root",
+            PartitionKind::Swap => "swap",
+            PartitionKind::Data => "data",
+            PartitionKind::Overlay => "overlay",
+        }
+    }
+}
+
+/// A partition of a custom layout, from `--partition` or a layout file
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PartitionSpec {
+    #[serde(rename = "type", default)]
+    pub kind: PartitionKind,
+    /// Where the partition is mounted, and so which directory of the image goes on it
+    pub mountpoint: Option<String>,
+    /// Size in bytes, which defaults to fitting the files that go on the partition
+    #[serde(default, deserialize_with = "deserialize_size")]
+    pub size: Option<u64>,
+    /// Defaults to the filesystem from --fs for root and ext4 for the others
+    pub fs: Option<Filesystem>,
+    pub label: Option<String>,
+}
+
+/// Parses a partition from comma separated `key=value` pairs of type, mountpoint, size, fs and label.
+/// The type can also be given on its own, as in `swap,size=1G`
+impl FromStr for PartitionSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let mut spec = PartitionSpec::default();
+        for (index, field) in value.split(',').enumerate() {
+            let (key, value) = match field.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None if index == 0 => ("type", field.trim()),
+                None => bail!("Expected key=value but got {}", field),
+            };
+            match key {
+                "type" => spec.kind = PartitionKind::from_str(value, true).map_err(|e| anyhow::anyhow!(e))?,
+                "mount" | "mountpoint" => spec.mountpoint = Some(value.to_string()),
+                "size" => spec.size = Some(parse_size(value)?),
+                "fs" => spec.fs = Some(Filesystem::from_str(value, true).map_err(|e| anyhow::anyhow!(e))?),
+                "label" => spec.label = Some(value.to_string()),
+                _ => bail!("Unknown partition option {}", key),
             }
         }
+        Ok(spec)
     }
 }
 
-impl ToString for ImageArg {
-    fn to_string(&self) -> String {
-        format!("{}:{}", self.name, self.tag)
+/// A layout file, a TOML document with a `[[partition]]` table for each partition
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PartitionLayout {
+    #[serde(rename = "partition", default)]
+    pub partitions: Vec<PartitionSpec>,
+}
+
+impl PartitionLayout {
+    pub fn from_file(path: &Utf8PathBuf) -> Result<PartitionLayout> {
+        let contents = fs::read_to_string(path).context(format!("Failed to read layout file {}", path))?;
+        toml::from_str(&contents).context(format!("Invalid layout file {}", path))
+    }
+}
+
+/// The optional config.toml in the base path
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The most space layers and drive images may take up, in bytes or with a suffix like "20G"
+    #[serde(default, deserialize_with = "deserialize_size")]
+    pub cache_max: Option<u64>,
+}
+
+impl Config {
+    /// Reads the config file, all the defaults apply when there is none
+    pub fn load() -> Result<Config> {
+        let path = get_config_path()?;
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+        let contents = fs::read_to_string(&path).context(format!("Failed to read config file {}", path))?;
+        toml::from_str(&contents).context(format!("Invalid config file {}", path))
+    }
+}
+
+/// Partitions start on a megabyte boundary unless told otherwise
+pub const DEFAULT_ALIGNMENT: u64 = 1024 * 1024;
+
+/// Parses a size in bytes with an optional K, M, G or T suffix, which are powers of 1024
+pub fn parse_size(value: &str) -> Result<u64> {
+    let value = value.trim();
+    let digits = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let number = value[..digits].parse::<u64>().context(format!("Invalid size {}", value))?;
+    let multiplier: u64 = match value[digits..].trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        "T" | "TB" | "TIB" => 1 << 40,
+        _ => bail!("Invalid size {}", value),
+    };
+    number.checked_mul(multiplier).context(format!("Size {} is too large", value))
+}
+
+/// Sizes in layout files can be a number of bytes or a string with a suffix
+fn deserialize_size<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Bytes(u64),
+        Text(String),
+    }
+    match Option::<Size>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(Size::Bytes(bytes)) => Ok(Some(bytes)),
+        Some(Size::Text(text)) => parse_size(&text).map(Some).map_err(serde::de::Error::custom),
     }
 }
 
@@ -48,8 +380,23 @@ pub struct ImageInfoArgs {
 
 #[derive(Debug, Args)]
 pub struct BuildImageArgs {
-    /// The image to pull
+    /// The image to pull. `docker-archive:<path>` and `oci:<path>[:tag]` read a local archive or layout
     pub image: ImageArg,
+    /// Where to get the image from, ignored for docker-archive and oci references
+    #[clap(long, value_enum, default_value_t = ImageSourceKind::Registry)]
+    pub source: ImageSourceKind,
+    /// Root directory of the containerd or containers/storage store to read from
+    #[clap(long)]
+    pub store_root: Option<Utf8PathBuf>,
+    /// The containerd namespace the image is in
+    #[clap(long, default_value_t = String::from("default"))]
+    pub containerd_namespace: String,
+    /// When to pull a registry image rather than build it from the cache
+    #[clap(long, value_enum, default_value_t = PullPolicy::Always)]
+    pub pull: PullPolicy,
+    /// Build from the cache without network access, the same as `--pull never`
+    #[clap(long, conflicts_with = "pull")]
+    pub offline: bool,
     /// The output path of the image
     #[clap(long)]
     pub outfile: Option<Utf8PathBuf>,
@@ -59,6 +406,83 @@ pub struct BuildImageArgs {
     /// The architecture the image is for
     #[clap(long, default_value_t = String::from("amd64"))]
     pub architecture: String,
+    /// Build a bit-for-bit reproducible image, taking timestamps from SOURCE_DATE_EPOCH
+    #[clap(long)]
+    pub reproducible: bool,
+    /// The file format of the drive image
+    #[clap(long, alias = "format", value_enum, default_value_t = OutputFormat::Raw)]
+    pub output_format: OutputFormat,
+    /// Compress the drive image when the output format supports it
+    #[clap(long)]
+    pub compress: bool,
+    /// The algorithm to compress with when the output format offers a choice, which initramfs does
+    #[clap(long, value_enum, default_value_t = CompressionAlgorithm::Gzip)]
+    pub compression: CompressionAlgorithm,
+    /// The filesystem of the root partition
+    #[clap(long, value_enum, default_value_t = Filesystem::Ext4)]
+    pub fs: Filesystem,
+    /// Add a dm-verity hash tree of the root partition in a partition after it
+    #[clap(long, conflicts_with = "encrypt")]
+    pub verity: bool,
+    /// Encrypt the root partition with LUKS2
+    #[clap(long, requires = "key_file")]
+    pub encrypt: bool,
+    /// The file holding the key for the encrypted root partition
+    #[clap(long, requires = "encrypt")]
+    pub key_file: Option<Utf8PathBuf>,
+    /// Add a partition, as comma separated type, mountpoint, size, fs and label, e.g. `mountpoint=/var,size=2G,fs=xfs`
+    #[clap(long = "partition", conflicts_with = "layout")]
+    pub partitions: Vec<PartitionSpec>,
+    /// A TOML file describing the partitions of the drive
+    #[clap(long)]
+    pub layout: Option<Utf8PathBuf>,
+    /// Make a directory of a read only root writable with an overlay, keeping its changes on an
+    /// overlay partition that's added to the layout unless it already has one
+    #[clap(long = "overlay")]
+    pub overlays: Vec<String>,
+    /// The kind of partition table to write
+    #[clap(long, value_enum, default_value_t = PartitionTableType::Dos)]
+    pub partition_table: PartitionTableType,
+    /// Partitions start and end on multiples of this size, which has to be a multiple of 4K
+    #[clap(long, value_parser = parse_size, default_value = "1M")]
+    pub alignment: u64,
+    /// Once built, the least recently used layers and drive images are removed until the cache
+    /// fits in this size, like 20G. Overrides cache_max in the config file
+    #[clap(long, value_parser = parse_size)]
+    pub cache_max: Option<u64>,
+}
+
+impl BuildImageArgs {
+    /// The pull policy, taking --offline into account
+    pub fn pull_policy(&self) -> PullPolicy {
+        if self.offline {
+            PullPolicy::Never
+        } else {
+            self.pull
+        }
+    }
+
+    /// Whether the partitions were given rather than the default single root partition
+    pub fn has_layout(&self) -> bool {
+        self.layout.is_some() || !self.partitions.is_empty() || !self.overlays.is_empty()
+    }
+
+    /// Whether the drive has the default MBR table with one 1MB aligned root partition
+    pub fn has_default_partitions(&self) -> bool {
+        !self.has_layout() && self.partition_table == PartitionTableType::Dos && self.alignment == DEFAULT_ALIGNMENT
+    }
+
+    /// The partitions from the layout file or the command line, empty for the default single root partition
+    pub fn partition_layout(&self) -> Result<Vec<PartitionSpec>> {
+        let mut partitions = match &self.layout {
+            Some(path) => PartitionLayout::from_file(path)?.partitions,
+            None => self.partitions.clone(),
+        };
+        if !self.overlays.is_empty() && !partitions.iter().any(|spec| spec.kind == PartitionKind::Overlay) {
+            partitions.push(PartitionSpec { kind: PartitionKind::Overlay, ..Default::default() });
+        }
+        Ok(partitions)
+    }
 }
 
 #[derive(Debug, Args)]
@@ -76,12 +500,136 @@ pub struct RemoveImageArgs {
     pub architecture: Option<String>
 }
 
+#[derive(Debug, Args)]
+pub struct TagImageArgs {
+    /// The stored image to tag, as name:tag or digest
+    pub source: String,
+    /// The name:tag to point at it, which is moved if it already points at another image
+    pub target: ImageArg,
+    /// The operating system the source image is for, when it's given as name:tag
+    #[clap(long, default_value_t = String::from("linux"))]
+    pub os: String,
+    /// The architecture the source image is for, when it's given as name:tag
+    #[clap(long, default_value_t = String::from("amd64"))]
+    pub architecture: String
+}
+
+#[derive(Debug, Args)]
+pub struct UntagImageArgs {
+    /// The name:tag to remove, the image it points at is kept until it's pruned
+    pub image: ImageArg,
+    /// The operating system the image is for
+    #[clap(long, default_value_t = String::from("linux"))]
+    pub os: String,
+    /// The architecture the image is for
+    #[clap(long, default_value_t = String::from("amd64"))]
+    pub architecture: String
+}
+
+#[derive(Debug, Args)]
+pub struct PurgeArgs {
+    /// Don't ask for confirmation, which is required when not run from a terminal
+    #[clap(long, short)]
+    pub force: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct PruneArgs {
+    /// Only report what would be removed and how much space it would free
+    #[clap(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct ExportImageArgs {
+    /// The stored image to export
+    pub image: ImageArg,
+    /// The OCI image layout directory to write, it is created if it doesn't exist
+    #[clap(long)]
+    pub oci_dir: Utf8PathBuf,
+    /// The operating system the image is for
+    #[clap(long, default_value_t = String::from("linux"))]
+    pub os: String,
+    /// The architecture the image is for
+    #[clap(long, default_value_t = String::from("amd64"))]
+    pub architecture: String,
+}
+
+#[derive(Debug, Args)]
+pub struct ExportRootfsArgs {
+    /// The image to flatten. `docker-archive:<path>` and `oci:<path>[:tag]` read a local archive or layout
+    pub image: ImageArg,
+    /// Where to get the image from, ignored for docker-archive and oci references
+    #[clap(long, value_enum, default_value_t = ImageSourceKind::Registry)]
+    pub source: ImageSourceKind,
+    /// Root of the containerd or Podman store, defaults to the store's usual location
+    #[clap(long)]
+    pub store_root: Option<Utf8PathBuf>,
+    /// The containerd namespace the image is in
+    #[clap(long, default_value_t = String::from("default"))]
+    pub containerd_namespace: String,
+    /// Write the root filesystem as a tarball, compressed when the path ends in .gz or .zst
+    #[clap(long, required_unless_present = "dir", conflicts_with = "dir")]
+    pub tar: Option<Utf8PathBuf>,
+    /// Write the root filesystem to a directory, which has to be empty if it exists
+    #[clap(long)]
+    pub dir: Option<Utf8PathBuf>,
+    /// The operating system the image is for
+    #[clap(long, default_value_t = String::from("linux"))]
+    pub os: String,
+    /// The architecture the image is for
+    #[clap(long, default_value_t = String::from("amd64"))]
+    pub architecture: String,
+}
+
+#[derive(Debug, Args)]
+pub struct ImportImagesArgs {
+    /// The OCI image layout directory to seed the cache from
+    pub oci_dir: Utf8PathBuf,
+}
+
 #[derive(Debug, Args)]
 pub struct ListImagesArgs {
+    /// Only images with a name matching this pattern, where * matches anything and ? any one character
+    #[clap(long)]
+    pub name: Option<String>,
+    /// Only images with this tag
+    #[clap(long)]
+    pub tag: Option<String>,
     /// The operating system the image is for
     #[clap(long)]
-    os: Option<String>,
-    /// The platform the image is for
+    pub os: Option<String>,
+    /// The architecture the image is for
+    #[clap(long)]
+    pub architecture: Option<String>,
+    /// Only images no tag points at
+    #[clap(long)]
+    pub dangling: bool,
+    /// Only images created before this one, given as name:tag or digest
     #[clap(long)]
-    platform: Option<String>
+    pub before: Option<String>,
+    /// Only images created after this one, given as name:tag or digest
+    #[clap(long)]
+    pub since: Option<String>,
+    /// What the images are ordered by
+    #[clap(long, value_enum, default_value_t = ImageSortKey::Name)]
+    pub sort: ImageSortKey,
+    /// List the images in the opposite order
+    #[clap(long)]
+    pub reverse: bool,
+    /// Only print the digests, one per line
+    #[clap(long, short)]
+    pub quiet: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ImageSortKey {
+    /// The first name:tag of the image
+    Name,
+    /// When the image was created, according to its config
+    Created,
+    /// The size of its drive image
+    Size,
+    /// When it was last built, exported or imported
+    LastUsed,
 }
\ No newline at end of file
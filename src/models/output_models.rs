@@ -1,14 +1,18 @@
 use std::collections::HashMap;
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-use crate::application_state::Image;
+use crate::models::registry_models::Platform;
 
 
 #[derive(Serialize)]
 pub struct ImageInfoResult {
     /// Digest of the resultant image
     pub digest: String,
+    /// Digest of the manifest for the platform
+    pub manifest_digest: String,
+    /// Digest of what the reference points at, the index or the manifest itself
+    pub index_digest: String,
     /// Is this already downloaded
     pub downloaded: bool,
     /// Is the version that is downloaded the same sha as the remote one?
@@ -19,18 +23,103 @@ pub struct ImageInfoResult {
 pub struct MakeImageResult {
     /// Digest of the resultant image
     pub digest: String,
-    /// Size of the resultant image in bytes
+    /// Apparent size of the resultant image in bytes
     pub size: u64,
+    /// Disk space the resultant image actually uses in bytes, smaller than the size for sparse images
+    pub allocated_size: u64,
     /// Is this already downloaded
     pub downloaded: bool,
     /// File path of the image generated
     pub file_path: String,
+    /// The dm-verity parameters when the root partition has a hash tree
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verity: Option<VerityResult>,
+    /// How the root partition is encrypted, when it is
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<EncryptionResult>,
+    /// The partitions of a custom layout
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub partitions: Option<Vec<PartitionResult>>,
+    /// Layers and drive images removed to keep the cache under its maximum size
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub evicted: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PartitionResult {
+    pub number: u32,
+    /// boot, root, swap or data
+    pub kind: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mountpoint: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filesystem: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// Where the partition starts in the drive image, in bytes
+    pub offset: u64,
+    pub size: u64,
+    pub device: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EncryptionResult {
+    pub format: String,
+    /// UUID of the LUKS header, which crypttab finds the partition by
+    pub luks_uuid: String,
+    /// The encrypted root partition
+    pub device: String,
+    /// The device root is mounted from once it's unlocked
+    pub mapped_device: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VerityResult {
+    /// Hash of the top of the tree, which the kernel checks everything against
+    pub root_hash: String,
+    /// Salt hashed in front of every block, as hex
+    pub salt: String,
+    pub hash_algorithm: String,
+    pub data_block_size: u64,
+    pub hash_block_size: u64,
+    /// Number of blocks of the root partition the tree covers
+    pub data_blocks: u64,
+    /// Block of the hash partition the tree starts at, after the superblock
+    pub hash_start_block: u64,
+    /// The root partition
+    pub data_device: String,
+    /// The partition holding the hash tree
+    pub hash_device: String,
+    /// Kernel parameters that set up the verity device and boot from it
+    pub kernel_cmdline: String,
 }
 
 #[derive(Serialize)]
 pub struct ListImagesResult {
     /// List of images
-    pub images: HashMap<String, Image>
+    pub images: Vec<ImageEntry>
+}
+
+#[derive(Serialize)]
+pub struct ImageEntry {
+    pub digest: String,
+    /// Every name:tag pointing at the image, none when it's dangling
+    pub tags: Vec<String>,
+    /// The name the image was first stored under
+    pub name: String,
+    pub platform: Platform,
+    /// When the image was created, according to its config
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created: Option<String>,
+    /// Size of the drive image, zero if it hasn't been built
+    pub size: u64,
+    pub layers: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub manifest_digest: Option<String>,
+    /// Unix time the image was last built, exported or imported
+    pub last_used_at: u64,
+    /// Paths of the drive images built from it
+    pub drive_images: Vec<String>,
 }
 
 #[derive(Serialize)]
@@ -41,8 +130,107 @@ pub struct RemoveImageResult {
     pub removed_layers: Vec<String>,
 }
 
+#[derive(Serialize)]
+pub struct DiskUsageResult {
+    /// The compressed layers
+    pub layers: FolderUsage,
+    /// The drive images and their details
+    pub images: FolderUsage,
+    /// The stored manifests and configs
+    pub blobs: FolderUsage,
+    /// Temporary folders and files left behind by runs that were interrupted
+    pub temp: FolderUsage,
+    /// Bytes all of the folders take up
+    pub total_size: u64,
+    /// Bytes prune would free
+    pub reclaimable_bytes: u64,
+    pub image_usage: Vec<ImageUsage>,
+}
+
+#[derive(Serialize)]
+pub struct FolderUsage {
+    pub path: String,
+    /// Disk space the folder takes up in bytes
+    pub size: u64,
+    /// Number of files and folders directly in it
+    pub entries: usize,
+}
+
+#[derive(Serialize)]
+pub struct ImageUsage {
+    pub digest: String,
+    /// Every name:tag pointing at the image
+    pub tags: Vec<String>,
+    /// Disk space of the drive images built from it
+    pub drive_images_size: u64,
+    /// Size of its stored layers
+    pub layers_size: u64,
+    /// Size of its stored layers that other images use as well
+    pub shared_layers_size: u64,
+    /// Size of its stored layers only it uses
+    pub unique_layers_size: u64,
+}
+
+#[derive(Serialize)]
+pub struct PurgeResult {
+    /// The folders and files that were deleted
+    pub removed: Vec<String>,
+    /// Bytes freed on disk
+    pub reclaimed_bytes: u64,
+}
+
 #[derive(Serialize)]
 pub struct PruneResult {
+    /// Images no tag pointed at
+    pub images: Vec<String>,
     /// All the layers that were pruned
-    pub layers: Vec<String>
+    pub layers: Vec<String>,
+    /// Every file that was deleted
+    pub files: Vec<String>,
+    /// Bytes freed on disk, or that would be freed on a dry run
+    pub reclaimed_bytes: u64,
+    pub dry_run: bool,
+}
+
+#[derive(Serialize)]
+pub struct ExportImageResult {
+    /// Digest of the exported image config
+    pub digest: String,
+    /// Digest of the exported image manifest
+    pub manifest_digest: String,
+    /// The OCI image layout the image was written to
+    pub path: String,
+}
+
+#[derive(Serialize)]
+pub struct ExportRootfsResult {
+    /// Digest of the image config
+    pub digest: String,
+    /// The tarball or directory the root filesystem was written to
+    pub path: String,
+}
+
+#[derive(Serialize)]
+pub struct TagImageResult {
+    /// Digest of the image the tag points at
+    pub digest: String,
+    /// The image:tag-os:arch that was set
+    pub tag: String,
+    /// Digest of the image the tag pointed at before, if it was moved
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous_digest: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct UntagImageResult {
+    /// Digest of the image the tag pointed at
+    pub digest: String,
+    /// The image:tag-os:arch that was removed
+    pub tag: String,
+}
+
+#[derive(Serialize)]
+pub struct ImportImagesResult {
+    /// Mapping of image:tag-os:arch to digest for every imported image
+    pub images: HashMap<String, String>,
 }
\ No newline at end of file
@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 
+use anyhow::{bail, Context, Result};
 use serde::{Deserialize, Serialize};
 
 
@@ -18,6 +19,15 @@ pub struct Manifests {
 }
 
 impl Manifests {
+    /// Parses what the registry returned for a reference, failing on the errors it reports
+    pub fn parse(bytes: &[u8]) -> Result<Manifests> {
+        let manifests = serde_json::from_slice::<Manifests>(bytes)?;
+        if let Some(errors) = &manifests.errors {
+            bail!("Error getting manifests: {:?}", errors.first().context("Errors present but empty")?.message);
+        }
+        Ok(manifests)
+    }
+
     pub fn get_manifest_for_platform(&self, platform: &Platform) -> Option<Manifest> {
         if let Some(manifests) = &self.manifests {
             manifests.iter().find(|m| {
@@ -54,30 +64,41 @@ pub struct ErrorDetail {
 pub struct ImageConfig {
     pub architecture: String,
     pub config: Config,
-    pub created: String,
+    pub created: Option<String>,
+    #[serde(default)]
     pub history: Vec<History>,
     pub os: String,
     pub rootfs: RootFs,
 }
 
+impl ImageConfig {
+    /// Gets a label from the image config if it is set
+    pub fn get_label(&self, key: &str) -> Option<&String> {
+        self.config.labels.as_ref().and_then(|labels| labels.get(key))
+    }
+}
+
+/// Fields are optional because locally built images often leave them null
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Config {
     #[serde(rename = "Env")]
-    pub env: Vec<String>,
+    pub env: Option<Vec<String>>,
     #[serde(rename = "Entrypoint")]
-    pub entrypoint: Vec<String>,
+    pub entrypoint: Option<Vec<String>>,
     #[serde(rename = "Cmd")]
-    pub cmd: Vec<String>,
+    pub cmd: Option<Vec<String>>,
+    #[serde(rename = "WorkingDir")]
+    pub working_dir: Option<String>,
     #[serde(rename = "Labels")]
-    pub labels: HashMap<String, String>,
+    pub labels: Option<HashMap<String, String>>,
     #[serde(rename = "ArgsEscaped")]
-    pub args_escaped: bool,
+    pub args_escaped: Option<bool>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct History {
-    pub created: String,
-    pub created_by: String,
+    pub created: Option<String>,
+    pub created_by: Option<String>,
     pub comment: Option<String>,
     pub empty_layer: Option<bool>,
 }
@@ -101,7 +122,7 @@ pub struct Manifest {
     pub platform: Platform
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Layer {
     pub media_type: String,
@@ -109,17 +130,20 @@ pub struct Layer {
     pub size: u64
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct OCIManifest {
     pub schema_version: u64,
+    /// Optional in manifests read from OCI layouts
+    #[serde(default)]
     pub media_type: String,
     pub config: OCIManifestConfig,
     pub layers: Vec<Layer>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub annotations: Option<HashMap<String, String>>
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct OCIManifestConfig {
     pub media_type: String,
@@ -127,14 +151,128 @@ pub struct OCIManifestConfig {
     pub size: u64
 }
 
+pub const OCI_INDEX_MEDIA_TYPE: &str = "application/vnd.oci.image.index.v1+json";
+pub const OCI_MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+pub const OCI_CONFIG_MEDIA_TYPE: &str = "application/vnd.oci.image.config.v1+json";
+pub const OCI_LAYER_MEDIA_TYPE: &str = "application/vnd.oci.image.layer.v1.tar";
+pub const OCI_LAYER_GZIP_MEDIA_TYPE: &str = "application/vnd.oci.image.layer.v1.tar+gzip";
+pub const REF_NAME_ANNOTATION: &str = "org.opencontainers.image.ref.name";
+pub const IMAGE_NAME_ANNOTATION: &str = "io.containerd.image.name";
+/// Set by buildkit on the attestation manifests it adds next to each image in an index
+pub const REFERENCE_TYPE_ANNOTATION: &str = "vnd.docker.reference.type";
+
+/// The index.json of an OCI image layout, or an image index blob inside one
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct OCIIndex {
+    pub schema_version: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
+    pub manifests: Vec<OCIDescriptor>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct OCIDescriptor {
+    pub media_type: String,
+    pub digest: String,
+    pub size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub platform: Option<Platform>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<HashMap<String, String>>,
+}
+
+impl OCIDescriptor {
+    /// Whether this descriptor points to another index rather than an image manifest
+    pub fn is_index(&self) -> bool {
+        self.media_type == OCI_INDEX_MEDIA_TYPE
+            || self.media_type == "application/vnd.docker.distribution.manifest.list.v2+json"
+    }
+
+    /// The tag the descriptor is stored under in an OCI layout
+    pub fn ref_name(&self) -> Option<&String> {
+        self.annotations.as_ref().and_then(|a| a.get(REF_NAME_ANNOTATION))
+    }
+
+    /// Whether this is an attestation manifest, which describes another image rather than
+    /// being one and has a config that isn't an image config
+    pub fn is_attestation(&self) -> bool {
+        self.annotations.as_ref().is_some_and(|a| a.contains_key(REFERENCE_TYPE_ANNOTATION))
+            || self.platform.as_ref().is_some_and(|p| p.os == "unknown" && p.architecture == "unknown")
+    }
+
+    /// The full image name containerd and whaledrive record next to the tag
+    pub fn image_name(&self) -> Option<&String> {
+        self.annotations.as_ref().and_then(|a| a.get(IMAGE_NAME_ANNOTATION))
+    }
+}
+
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "PascalCase")]
 pub struct LocalImageManifest {
     pub config: String,
-    pub repo_tags: Vec<String>,
+    pub repo_tags: Option<Vec<String>>,
     pub layers: Vec<String>
 }
 
+/// An image entry in containers/storage `overlay-images/images.json`
+#[derive(Deserialize, Debug)]
+pub struct StorageImage {
+    /// Hex of the config digest
+    pub id: String,
+    pub names: Option<Vec<String>>,
+    /// Id of the top layer
+    pub layer: Option<String>,
+}
+
+/// A layer entry in containers/storage `overlay-layers/layers.json`
+#[derive(Deserialize, Debug)]
+pub struct StorageLayer {
+    pub id: String,
+    pub parent: Option<String>,
+    /// Digest of the uncompressed layer tar
+    #[serde(rename = "diff-digest")]
+    pub diff_digest: Option<String>,
+}
+
+/// A line of a tar-split file, which records everything in a layer tar except file contents
+#[derive(Deserialize, Debug)]
+pub struct TarSplitEntry {
+    /// 1 for a file whose contents come from disk, 2 for raw tar bytes
+    #[serde(rename = "type")]
+    pub entry_type: u8,
+    pub name: Option<String>,
+    pub name_raw: Option<String>,
+    pub size: Option<u64>,
+    /// Base64 encoded raw bytes for segments
+    pub payload: Option<String>,
+}
+
+/// The parts of the docker engine image inspect response we care about
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct LocalImageInspect {
+    pub id: String,
+    pub os: String,
+    pub architecture: String,
+}
+
+/// An image whose layers have all been placed in the compressed layers folder
+/// and whose manifest and config are in the blobs folder
+#[derive(Debug)]
+pub struct PulledImage {
+    /// Digest of the image config
+    pub digest: String,
+    /// Digest of the image manifest
+    pub manifest_digest: String,
+    /// Digest of the index or manifest the registry resolved the reference to
+    pub index_digest: Option<String>,
+    pub config: ImageConfig,
+    /// Digests of the layers, from the bottom layer up
+    pub layers: Vec<String>,
+}
+
 impl PartialEq for Platform {
     fn eq(&self, other: &Self) -> bool {
         self.architecture == other.architecture && self.os == other.os
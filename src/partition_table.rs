@@ -0,0 +1,506 @@
+use std::{fs::{File, OpenOptions}, io::{Read, Seek, SeekFrom, Write}, path::Path};
+
+use anyhow::{bail, Context, Result};
+use flate2::Crc;
+use sha2::{Digest, Sha256};
+
+use crate::{disk_formats::{guid_to_bytes, to_guid}, models::input_models::PartitionTableType};
+
+const SECTOR_SIZE: u64 = 512;
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const MBR_PARTITION_COUNT: usize = 4;
+const MBR_PROTECTIVE_TYPE: u8 = 0xee;
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_HEADER_SIZE: usize = 92;
+const GPT_ENTRY_COUNT: u64 = 128;
+const GPT_ENTRY_SIZE: u64 = 128;
+/// Sectors taken by the partition entries, which come after the header at both ends of the disk
+const GPT_ENTRY_SECTORS: u64 = GPT_ENTRY_COUNT * GPT_ENTRY_SIZE / SECTOR_SIZE;
+/// The attribute firmware and MBR boot code look for to find the partition to boot
+const GPT_LEGACY_BIOS_BOOTABLE: u64 = 1 << 2;
+
+/// What a partition holds, which decides its MBR type and GPT type guid
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionType {
+    Linux,
+    Luks,
+    Swap,
+    /// A dm-verity hash tree
+    VerityHash,
+}
+
+impl PartitionType {
+    fn mbr_type(&self) -> u8 {
+        match self {
+            PartitionType::Linux => 0x83,
+            PartitionType::Luks => 0xe8,
+            PartitionType::Swap => 0x82,
+            // Data without a filesystem
+            PartitionType::VerityHash => 0xda,
+        }
+    }
+
+    fn gpt_type(&self) -> &'static str {
+        match self {
+            PartitionType::Linux => "0fc63daf-8483-4772-8e79-3d69d8477de4",
+            PartitionType::Luks => "ca7d7ccb-63ed-4c53-861c-1742536059cc",
+            PartitionType::Swap => "0657fd6d-a4ab-43c4-84e5-0933c84b4f4f",
+            // The verity types are for a particular architecture, so the hash tree is plain Linux data
+            PartitionType::VerityHash => "0fc63daf-8483-4772-8e79-3d69d8477de4",
+        }
+    }
+}
+
+/// A partition to write, with its start and size in bytes
+#[derive(Debug, Clone)]
+pub struct TablePartition {
+    pub start: u64,
+    pub size: u64,
+    pub partition_type: PartitionType,
+    pub bootable: bool,
+    /// Only GPT stores a name
+    pub name: String,
+}
+
+/// A partition read back from a drive image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableEntry {
+    pub number: u32,
+    pub start: u64,
+    pub size: u64,
+    pub bootable: bool,
+    /// What the partition is found by in /dev/disk/by-partuuid and fstab
+    pub partuuid: String,
+}
+
+/// An MBR or GPT partition table, with each partition placed after the last on the alignment
+#[derive(Debug, Clone)]
+pub struct PartitionTable {
+    pub table_type: PartitionTableType,
+    /// The disk identifier in the `0x<hex>` form, which GPT ids are derived from
+    pub disk_id: String,
+    pub alignment: u64,
+    pub partitions: Vec<TablePartition>,
+}
+
+impl PartitionTable {
+    /// Starts a table without partitions. Partitions are aligned to a multiple of 4K so
+    /// filesystem and verity blocks line up with the disk
+    pub fn new(table_type: PartitionTableType, disk_id: &str, alignment: u64) -> Result<PartitionTable> {
+        if alignment == 0 || !alignment.is_multiple_of(4096) {
+            bail!("Partition alignment has to be a multiple of 4096 bytes, not {}", alignment);
+        }
+        u32::from_str_radix(disk_id.trim_start_matches("0x"), 16).context(format!("Invalid disk identifier {}", disk_id))?;
+        Ok(PartitionTable { table_type, disk_id: disk_id.to_string(), alignment, partitions: Vec::new() })
+    }
+
+    /// Adds a partition after the last one, rounding its size up to the alignment
+    pub fn add(&mut self, size: u64, partition_type: PartitionType, bootable: bool, name: &str) -> Result<()> {
+        if self.table_type == PartitionTableType::Dos && self.partitions.len() == MBR_PARTITION_COUNT {
+            bail!("A DOS partition table can only hold {} partitions", MBR_PARTITION_COUNT);
+        }
+        let start = match self.partitions.last() {
+            Some(last) => last.start + last.size,
+            None => self.first_usable_sector() * SECTOR_SIZE,
+        };
+        self.partitions.push(TablePartition {
+            start: start.div_ceil(self.alignment) * self.alignment,
+            size: size.div_ceil(self.alignment) * self.alignment,
+            partition_type,
+            bootable,
+            name: name.to_string(),
+        });
+        Ok(())
+    }
+
+    /// The size of the smallest disk that holds every partition, and the backup GPT at its end
+    pub fn disk_size(&self) -> u64 {
+        let end = match self.partitions.last() {
+            Some(last) => last.start + last.size,
+            None => self.first_usable_sector() * SECTOR_SIZE,
+        };
+        match self.table_type {
+            PartitionTableType::Dos => end.div_ceil(self.alignment) * self.alignment,
+            PartitionTableType::Gpt => (end + (GPT_ENTRY_SECTORS + 1) * SECTOR_SIZE).div_ceil(self.alignment) * self.alignment,
+        }
+    }
+
+    /// The PARTUUID the partition with this number will have
+    pub fn partuuid(&self, number: u32) -> String {
+        match self.table_type {
+            PartitionTableType::Dos => format!("{}-{:02}", self.disk_id.trim_start_matches("0x").to_lowercase(), number),
+            PartitionTableType::Gpt => guid_to_string(&self.partition_guid(number)),
+        }
+    }
+
+    /// Writes the table to the start of the image, and for GPT the backup to its end.
+    /// The image has to be at least as large as the disk size
+    pub fn write(&self, image_path: &Path) -> Result<()> {
+        let disk_sectors = std::fs::metadata(image_path)?.len() / SECTOR_SIZE;
+        if disk_sectors * SECTOR_SIZE < self.disk_size() {
+            bail!("The drive image is too small for its partitions");
+        }
+        let mut file = OpenOptions::new().write(true).open(image_path)?;
+        match self.table_type {
+            PartitionTableType::Dos => file.write_all(&self.create_mbr()?)?,
+            PartitionTableType::Gpt => self.write_gpt(&mut file, disk_sectors)?,
+        }
+        Ok(())
+    }
+
+    fn first_usable_sector(&self) -> u64 {
+        match self.table_type {
+            PartitionTableType::Dos => 1,
+            // The protective MBR, the header and the partition entries
+            PartitionTableType::Gpt => 2 + GPT_ENTRY_SECTORS,
+        }
+    }
+
+    fn disk_guid(&self) -> [u8; 16] {
+        to_guid(&Sha256::digest(format!("{}gpt", self.disk_id).as_bytes()))
+    }
+
+    fn partition_guid(&self, number: u32) -> [u8; 16] {
+        to_guid(&Sha256::digest(format!("{}gpt{}", self.disk_id, number).as_bytes()))
+    }
+
+    fn create_mbr(&self) -> Result<[u8; 512]> {
+        let mut mbr = [0u8; 512];
+        // The first 440 bytes are left for the boot code
+        let signature = u32::from_str_radix(self.disk_id.trim_start_matches("0x"), 16)?;
+        mbr[440..444].copy_from_slice(&signature.to_le_bytes());
+        for (index, partition) in self.partitions.iter().enumerate() {
+            let start = partition.start / SECTOR_SIZE;
+            let sectors = partition.size / SECTOR_SIZE;
+            if start + sectors > u32::MAX as u64 {
+                bail!("A DOS partition table can't address more than 2TB, use GPT");
+            }
+            let entry = mbr_entry(partition.bootable, partition.partition_type.mbr_type(), start, sectors);
+            mbr[446 + index * 16..446 + (index + 1) * 16].copy_from_slice(&entry);
+        }
+        mbr[510..512].copy_from_slice(&MBR_SIGNATURE);
+        Ok(mbr)
+    }
+
+    fn write_gpt(&self, file: &mut File, disk_sectors: u64) -> Result<()> {
+        let last_sector = disk_sectors - 1;
+        // A protective MBR covering the whole disk keeps tools that only know MBR away from it
+        let mut mbr = [0u8; 512];
+        let entry = mbr_entry(false, MBR_PROTECTIVE_TYPE, 1, last_sector.min(u32::MAX as u64));
+        mbr[446..462].copy_from_slice(&entry);
+        mbr[510..512].copy_from_slice(&MBR_SIGNATURE);
+
+        let mut entries = vec![0u8; (GPT_ENTRY_COUNT * GPT_ENTRY_SIZE) as usize];
+        for (index, partition) in self.partitions.iter().enumerate() {
+            let entry = &mut entries[index * GPT_ENTRY_SIZE as usize..(index + 1) * GPT_ENTRY_SIZE as usize];
+            entry[0..16].copy_from_slice(&guid_to_bytes(partition.partition_type.gpt_type()));
+            entry[16..32].copy_from_slice(&self.partition_guid(index as u32 + 1));
+            entry[32..40].copy_from_slice(&(partition.start / SECTOR_SIZE).to_le_bytes());
+            // The last sector is inclusive
+            entry[40..48].copy_from_slice(&((partition.start + partition.size) / SECTOR_SIZE - 1).to_le_bytes());
+            let attributes = if partition.bootable { GPT_LEGACY_BIOS_BOOTABLE } else { 0 };
+            entry[48..56].copy_from_slice(&attributes.to_le_bytes());
+            for (unit, character) in partition.name.encode_utf16().take(36).enumerate() {
+                entry[56 + unit * 2..58 + unit * 2].copy_from_slice(&character.to_le_bytes());
+            }
+        }
+        let entries_crc = crc32(&entries);
+        let backup_entries_sector = last_sector - GPT_ENTRY_SECTORS;
+
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&mbr)?;
+        file.write_all(&self.create_gpt_header(1, last_sector, 2, last_sector, entries_crc))?;
+        file.write_all(&entries)?;
+        file.seek(SeekFrom::Start(backup_entries_sector * SECTOR_SIZE))?;
+        file.write_all(&entries)?;
+        file.write_all(&self.create_gpt_header(last_sector, 1, backup_entries_sector, last_sector, entries_crc))?;
+        Ok(())
+    }
+
+    fn create_gpt_header(&self, current: u64, backup: u64, entries_sector: u64, last_sector: u64, entries_crc: u32) -> [u8; 512] {
+        let mut header = [0u8; 512];
+        header[0..8].copy_from_slice(GPT_SIGNATURE);
+        header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes()); // revision 1.0
+        header[12..16].copy_from_slice(&(GPT_HEADER_SIZE as u32).to_le_bytes());
+        header[24..32].copy_from_slice(&current.to_le_bytes());
+        header[32..40].copy_from_slice(&backup.to_le_bytes());
+        header[40..48].copy_from_slice(&self.first_usable_sector().to_le_bytes());
+        header[48..56].copy_from_slice(&(last_sector - GPT_ENTRY_SECTORS - 1).to_le_bytes());
+        header[56..72].copy_from_slice(&self.disk_guid());
+        header[72..80].copy_from_slice(&entries_sector.to_le_bytes());
+        header[80..84].copy_from_slice(&(GPT_ENTRY_COUNT as u32).to_le_bytes());
+        header[84..88].copy_from_slice(&(GPT_ENTRY_SIZE as u32).to_le_bytes());
+        header[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+        // The checksum is of the header with the checksum field zeroed
+        let header_crc = crc32(&header[0..GPT_HEADER_SIZE]);
+        header[16..20].copy_from_slice(&header_crc.to_le_bytes());
+        header
+    }
+}
+
+/// Reads the partitions of an MBR or GPT table back from a drive image, checking the GPT checksums
+pub fn read_partition_table(image_path: &Path) -> Result<Vec<TableEntry>> {
+    let mut file = File::open(image_path)?;
+    let mut mbr = [0u8; 512];
+    file.read_exact(&mut mbr)?;
+    if mbr[510..512] != MBR_SIGNATURE {
+        bail!("{} has no partition table", image_path.display());
+    }
+    if mbr[446 + 4] == MBR_PROTECTIVE_TYPE {
+        return read_gpt(&mut file);
+    }
+    let signature = u32::from_le_bytes(mbr[440..444].try_into()?);
+    let mut entries = Vec::new();
+    for index in 0..MBR_PARTITION_COUNT {
+        let entry = &mbr[446 + index * 16..446 + (index + 1) * 16];
+        if entry[4] == 0 {
+            continue;
+        }
+        entries.push(TableEntry {
+            number: index as u32 + 1,
+            start: u32::from_le_bytes(entry[8..12].try_into()?) as u64 * SECTOR_SIZE,
+            size: u32::from_le_bytes(entry[12..16].try_into()?) as u64 * SECTOR_SIZE,
+            bootable: entry[0] == 0x80,
+            partuuid: format!("{:08x}-{:02}", signature, index + 1),
+        });
+    }
+    Ok(entries)
+}
+
+fn read_gpt(file: &mut File) -> Result<Vec<TableEntry>> {
+    let mut header = [0u8; 512];
+    file.read_exact(&mut header)?;
+    if &header[0..8] != GPT_SIGNATURE {
+        bail!("The protective MBR isn't followed by a GPT header");
+    }
+    let header_size = u32::from_le_bytes(header[12..16].try_into()?) as usize;
+    if !(GPT_HEADER_SIZE..=512).contains(&header_size) {
+        bail!("Invalid GPT header size {}", header_size);
+    }
+    let stored_crc = u32::from_le_bytes(header[16..20].try_into()?);
+    let mut zeroed = header;
+    zeroed[16..20].fill(0);
+    if crc32(&zeroed[0..header_size]) != stored_crc {
+        bail!("The GPT header checksum doesn't match");
+    }
+    let entries_sector = u64::from_le_bytes(header[72..80].try_into()?);
+    let entry_count = u32::from_le_bytes(header[80..84].try_into()?) as usize;
+    let entry_size = u32::from_le_bytes(header[84..88].try_into()?) as usize;
+    if entry_size < 128 || entry_count * entry_size > 1024 * 1024 {
+        bail!("Invalid GPT partition entries");
+    }
+    let mut entries = vec![0u8; entry_count * entry_size];
+    file.seek(SeekFrom::Start(entries_sector * SECTOR_SIZE))?;
+    file.read_exact(&mut entries)?;
+    if crc32(&entries) != u32::from_le_bytes(header[88..92].try_into()?) {
+        bail!("The GPT partition entries checksum doesn't match");
+    }
+    let mut partitions = Vec::new();
+    for (index, entry) in entries.chunks(entry_size).enumerate() {
+        // Unused entries have a zero type guid
+        if entry[0..16].iter().all(|byte| *byte == 0) {
+            continue;
+        }
+        let first = u64::from_le_bytes(entry[32..40].try_into()?);
+        let last = u64::from_le_bytes(entry[40..48].try_into()?);
+        if last < first {
+            bail!("GPT partition {} ends before it starts", index + 1);
+        }
+        partitions.push(TableEntry {
+            number: index as u32 + 1,
+            start: first * SECTOR_SIZE,
+            size: (last + 1 - first) * SECTOR_SIZE,
+            bootable: u64::from_le_bytes(entry[48..56].try_into()?) & GPT_LEGACY_BIOS_BOOTABLE != 0,
+            partuuid: guid_to_string(entry[16..32].try_into()?),
+        });
+    }
+    Ok(partitions)
+}
+
+/// An MBR partition entry, with the CHS addresses tools still check
+fn mbr_entry(bootable: bool, partition_type: u8, start: u64, sectors: u64) -> [u8; 16] {
+    let mut entry = [0u8; 16];
+    entry[0] = if bootable { 0x80 } else { 0 };
+    entry[1..4].copy_from_slice(&lba_to_chs(start));
+    entry[4] = partition_type;
+    entry[5..8].copy_from_slice(&lba_to_chs(start + sectors - 1));
+    entry[8..12].copy_from_slice(&(start as u32).to_le_bytes());
+    entry[12..16].copy_from_slice(&(sectors as u32).to_le_bytes());
+    entry
+}
+
+/// Converts a sector to a cylinder, head and sector address with 255 heads and 63 sectors a track,
+/// or the largest address when it's past what CHS can reach
+fn lba_to_chs(lba: u64) -> [u8; 3] {
+    const HEADS: u64 = 255;
+    const SECTORS: u64 = 63;
+    let cylinder = lba / (HEADS * SECTORS);
+    if cylinder > 1023 {
+        return [0xfe, 0xff, 0xff];
+    }
+    let head = (lba / SECTORS) % HEADS;
+    let sector = lba % SECTORS + 1;
+    [head as u8, sector as u8 | ((cylinder >> 2) as u8 & 0xc0), cylinder as u8]
+}
+
+/// The text form of a guid stored in the mixed endian layout
+fn guid_to_string(bytes: &[u8; 16]) -> String {
+    format!(
+        "{:08x}-{:04x}-{:04x}-{}-{}",
+        u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+        u16::from_le_bytes([bytes[4], bytes[5]]),
+        u16::from_le_bytes([bytes[6], bytes[7]]),
+        bytes[8..10].iter().map(|byte| format!("{byte:02x}")).collect::<String>(),
+        bytes[10..16].iter().map(|byte| format!("{byte:02x}")).collect::<String>(),
+    )
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(data);
+    crc.sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    const MB: u64 = 1024 * 1024;
+
+    /// Writes the table to an image of its disk size in a temp dir
+    fn write_table(table: &PartitionTable) -> (TempDir, std::path::PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("disk.img");
+        File::create(&path).unwrap().set_len(table.disk_size()).unwrap();
+        table.write(&path).unwrap();
+        (dir, path)
+    }
+
+    fn sample_table(table_type: PartitionTableType) -> PartitionTable {
+        let mut table = PartitionTable::new(table_type, "0x1234abcd", MB).unwrap();
+        table.add(3 * MB, PartitionType::Linux, false, "boot").unwrap();
+        table.add(10 * MB, PartitionType::Linux, true, "root").unwrap();
+        table.add(2 * MB, PartitionType::Swap, false, "swap").unwrap();
+        table.add(MB, PartitionType::VerityHash, false, "verity").unwrap();
+        table
+    }
+
+    fn assert_read_back(table: &PartitionTable, path: &Path) {
+        let entries = read_partition_table(path).unwrap();
+        assert_eq!(entries.len(), table.partitions.len());
+        for (index, (entry, partition)) in entries.iter().zip(&table.partitions).enumerate() {
+            assert_eq!(entry.number, index as u32 + 1);
+            assert_eq!(entry.start, partition.start);
+            assert_eq!(entry.size, partition.size);
+            assert_eq!(entry.bootable, partition.bootable);
+            assert_eq!(entry.partuuid, table.partuuid(entry.number));
+        }
+    }
+
+    fn read_sector(path: &Path, sector: u64) -> Vec<u8> {
+        let data = fs::read(path).unwrap();
+        data[(sector * SECTOR_SIZE) as usize..((sector + 1) * SECTOR_SIZE) as usize].to_vec()
+    }
+
+    /// Checks a GPT header's checksum and returns its current, backup and entries sectors
+    /// with the entries checksum
+    fn check_gpt_header(header: &[u8]) -> (u64, u64, u64, u32) {
+        assert_eq!(&header[0..8], GPT_SIGNATURE);
+        let mut zeroed = header[0..GPT_HEADER_SIZE].to_vec();
+        zeroed[16..20].fill(0);
+        assert_eq!(crc32(&zeroed), u32::from_le_bytes(header[16..20].try_into().unwrap()));
+        let field = |offset: usize| u64::from_le_bytes(header[offset..offset + 8].try_into().unwrap());
+        (field(24), field(32), field(72), u32::from_le_bytes(header[88..92].try_into().unwrap()))
+    }
+
+    #[test]
+    fn dos_table_reads_back() {
+        let table = sample_table(PartitionTableType::Dos);
+        let (_dir, path) = write_table(&table);
+        assert_read_back(&table, &path);
+        assert_eq!(table.partuuid(2), "1234abcd-02");
+        let mbr = read_sector(&path, 0);
+        assert_eq!(mbr[510..512], MBR_SIGNATURE);
+        assert_eq!(mbr[440..444], 0x1234abcdu32.to_le_bytes());
+    }
+
+    #[test]
+    fn gpt_table_reads_back() {
+        let table = sample_table(PartitionTableType::Gpt);
+        let (_dir, path) = write_table(&table);
+        assert_read_back(&table, &path);
+        let mbr = read_sector(&path, 0);
+        assert_eq!(mbr[446 + 4], MBR_PROTECTIVE_TYPE);
+    }
+
+    #[test]
+    fn gpt_headers_and_entries_have_valid_checksums() {
+        let table = sample_table(PartitionTableType::Gpt);
+        let (_dir, path) = write_table(&table);
+        let data = fs::read(&path).unwrap();
+        let last_sector = data.len() as u64 / SECTOR_SIZE - 1;
+        let entries_len = (GPT_ENTRY_COUNT * GPT_ENTRY_SIZE) as usize;
+
+        let (current, backup, entries_sector, entries_crc) = check_gpt_header(&read_sector(&path, 1));
+        assert_eq!((current, backup, entries_sector), (1, last_sector, 2));
+        let primary_entries = &data[(entries_sector * SECTOR_SIZE) as usize..][..entries_len];
+        assert_eq!(crc32(primary_entries), entries_crc);
+
+        // The backup header is in the last sector with its entries just before it
+        let (current, backup, entries_sector, backup_crc) = check_gpt_header(&read_sector(&path, last_sector));
+        assert_eq!((current, backup, entries_sector), (last_sector, 1, last_sector - GPT_ENTRY_SECTORS));
+        let backup_entries = &data[(entries_sector * SECTOR_SIZE) as usize..][..entries_len];
+        assert_eq!(backup_crc, entries_crc);
+        assert_eq!(backup_entries, primary_entries);
+    }
+
+    #[test]
+    fn partitions_are_rounded_to_the_alignment() {
+        let mut table = PartitionTable::new(PartitionTableType::Gpt, "0x1", 4 * MB).unwrap();
+        table.add(1, PartitionType::Linux, false, "a").unwrap();
+        table.add(4 * MB + 1, PartitionType::Linux, false, "b").unwrap();
+        assert_eq!((table.partitions[0].start, table.partitions[0].size), (4 * MB, 4 * MB));
+        assert_eq!((table.partitions[1].start, table.partitions[1].size), (8 * MB, 8 * MB));
+        // The backup GPT needs room after the last partition
+        assert_eq!(table.disk_size(), 20 * MB);
+        assert!(PartitionTable::new(PartitionTableType::Dos, "0x1", 1000).is_err());
+    }
+
+    #[test]
+    fn dos_table_holds_four_partitions() {
+        let mut table = PartitionTable::new(PartitionTableType::Dos, "0x1", MB).unwrap();
+        for _ in 0..MBR_PARTITION_COUNT {
+            table.add(MB, PartitionType::Linux, false, "data").unwrap();
+        }
+        assert!(table.add(MB, PartitionType::Linux, false, "data").is_err());
+        let mut table = PartitionTable::new(PartitionTableType::Gpt, "0x1", MB).unwrap();
+        for _ in 0..=MBR_PARTITION_COUNT {
+            table.add(MB, PartitionType::Linux, false, "data").unwrap();
+        }
+    }
+
+    #[test]
+    fn gpt_partition_ending_before_its_start_is_refused() {
+        let table = sample_table(PartitionTableType::Gpt);
+        let (_dir, path) = write_table(&table);
+        let mut data = fs::read(&path).unwrap();
+        let entries_start = (2 * SECTOR_SIZE) as usize;
+        let entries_len = (GPT_ENTRY_COUNT * GPT_ENTRY_SIZE) as usize;
+        // End the first partition a sector before it starts, with the checksums fixed up
+        let first = u64::from_le_bytes(data[entries_start + 32..entries_start + 40].try_into().unwrap());
+        data[entries_start + 40..entries_start + 48].copy_from_slice(&(first - 1).to_le_bytes());
+        let entries_crc = crc32(&data[entries_start..entries_start + entries_len]);
+        let header = &mut data[SECTOR_SIZE as usize..SECTOR_SIZE as usize + GPT_HEADER_SIZE];
+        header[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+        header[16..20].fill(0);
+        let header_crc = crc32(header);
+        header[16..20].copy_from_slice(&header_crc.to_le_bytes());
+        fs::write(&path, &data).unwrap();
+        let error = read_partition_table(&path).unwrap_err();
+        assert!(error.to_string().contains("ends before it starts"), "{error}");
+    }
+}
@@ -0,0 +1,268 @@
+use std::{cmp::Reverse, fs, os::unix::fs::{chown, MetadataExt, PermissionsExt}, path::{Component, Path, PathBuf}};
+
+use anyhow::{bail, Context, Result};
+
+use crate::models::input_models::{Filesystem, PartitionKind, PartitionSpec};
+
+/// The size of an overlay partition when none is given, since its files are only written at runtime
+const DEFAULT_OVERLAY_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// A partition of the drive, with the defaults of its spec filled in
+#[derive(Debug, Clone)]
+pub struct Partition {
+    pub kind: PartitionKind,
+    /// Where the partition is mounted, None for swap
+    pub mountpoint: Option<String>,
+    /// None for swap
+    pub filesystem: Option<Filesystem>,
+    pub label: Option<String>,
+    /// The requested size, otherwise it's sized to fit its files
+    pub size: Option<u64>,
+}
+
+/// A line of /etc/fstab
+#[derive(Debug, Clone)]
+pub struct FstabEntry {
+    pub device: String,
+    pub mountpoint: String,
+    pub fs_type: String,
+    pub options: String,
+    pub pass: u32,
+}
+
+/// A directory of a read only root made writable by an overlay, with the directories on the
+/// overlay partition that its changes and overlayfs' own work go in
+#[derive(Debug, Clone)]
+pub struct Overlay {
+    pub dir: String,
+    pub upper: String,
+    pub work: String,
+}
+
+/// Checks a layout and fills in its defaults. Layouts without a root partition get one first,
+/// with the filesystem from --fs, so an empty layout is the usual single root partition
+pub fn resolve_layout(specs: &[PartitionSpec], root_filesystem: Filesystem) -> Result<Vec<Partition>> {
+    let mut partitions = Vec::new();
+    if !specs.iter().any(|spec| spec.kind == PartitionKind::Root) {
+        partitions.push(Partition {
+            kind: PartitionKind::Root,
+            mountpoint: Some("/".to_string()),
+            filesystem: Some(root_filesystem),
+            label: None,
+            size: None,
+        });
+    }
+    for spec in specs {
+        let (mountpoint, filesystem) = match spec.kind {
+            PartitionKind::Swap => {
+                if spec.mountpoint.is_some() || spec.fs.is_some() {
+                    bail!("A swap partition has no mountpoint or filesystem");
+                }
+                if spec.size.is_none() {
+                    bail!("A swap partition needs a size");
+                }
+                (None, None)
+            },
+            PartitionKind::Root => {
+                if matches!(spec.mountpoint.as_deref(), Some(mountpoint) if mountpoint != "/") {
+                    bail!("The root partition can only be mounted at /");
+                }
+                (Some("/".to_string()), Some(spec.fs.unwrap_or(root_filesystem)))
+            },
+            PartitionKind::Boot => {
+                let mountpoint = normalize_mountpoint(spec.mountpoint.as_deref().unwrap_or("/boot"))?;
+                (Some(mountpoint), Some(spec.fs.unwrap_or_default()))
+            },
+            PartitionKind::Data => {
+                let mountpoint = spec.mountpoint.as_deref().context("A data partition needs a mountpoint")?;
+                (Some(normalize_mountpoint(mountpoint)?), Some(spec.fs.unwrap_or_default()))
+            },
+            PartitionKind::Overlay => {
+                let mountpoint = normalize_mountpoint(spec.mountpoint.as_deref().unwrap_or("/overlay"))?;
+                let filesystem = spec.fs.unwrap_or_default();
+                if filesystem.is_read_only() {
+                    bail!("The overlay partition has to be writable, not {}", filesystem.name());
+                }
+                (Some(mountpoint), Some(filesystem))
+            },
+        };
+        if filesystem == Some(Filesystem::Squashfs) && spec.label.is_some() {
+            bail!("squashfs filesystems have no label");
+        }
+        let size = match spec.kind {
+            PartitionKind::Overlay => Some(spec.size.unwrap_or(DEFAULT_OVERLAY_SIZE)),
+            _ => spec.size,
+        };
+        partitions.push(Partition { kind: spec.kind, mountpoint, filesystem, label: spec.label.clone(), size });
+    }
+    for kind in [PartitionKind::Root, PartitionKind::Boot, PartitionKind::Overlay] {
+        if partitions.iter().filter(|partition| partition.kind == kind).count() > 1 {
+            bail!("A layout can only have one {} partition", kind.name());
+        }
+    }
+    let mut mountpoints = partitions.iter().filter_map(|partition| partition.mountpoint.as_deref()).collect::<Vec<&str>>();
+    if mountpoints.contains(&"/etc") {
+        bail!("/etc holds the fstab that mounts the other partitions, so it has to be on the root partition");
+    }
+    mountpoints.sort();
+    if let Some(duplicate) = mountpoints.windows(2).find(|pair| pair[0] == pair[1]) {
+        bail!("More than one partition is mounted at {}", duplicate[0]);
+    }
+    Ok(partitions)
+}
+
+/// Mountpoints are absolute and kept in a single form so they can be compared
+fn normalize_mountpoint(mountpoint: &str) -> Result<String> {
+    let path = Path::new(mountpoint);
+    if !path.is_absolute() {
+        bail!("Mountpoint {} isn't an absolute path", mountpoint);
+    }
+    let mut parts = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_str().context("Mountpoints have to be valid utf8")?),
+            Component::RootDir | Component::CurDir => {},
+            _ => bail!("Mountpoint {} can't contain ..", mountpoint),
+        }
+    }
+    if parts.is_empty() {
+        bail!("Only the root partition can be mounted at /");
+    }
+    Ok(format!("/{}", parts.join("/")))
+}
+
+/// Checks the directories to put an overlay on, which have to be on a read only root, and
+/// places their upper and work directories under the overlay partition's mountpoint
+pub fn resolve_overlays(dirs: &[String], partitions: &[Partition]) -> Result<Vec<Overlay>> {
+    if dirs.is_empty() {
+        return Ok(Vec::new());
+    }
+    let root = partitions.iter().find(|partition| partition.kind == PartitionKind::Root).context("The layout has no root partition")?;
+    if !root.filesystem.is_some_and(|filesystem| filesystem.is_read_only()) {
+        bail!("Overlays are for read only roots, use --fs squashfs or --fs erofs");
+    }
+    let overlay_mountpoint = partitions.iter()
+        .find(|partition| partition.kind == PartitionKind::Overlay)
+        .and_then(|partition| partition.mountpoint.clone())
+        .context("Overlays need an overlay partition to keep their changes")?;
+    let mut overlays: Vec<Overlay> = Vec::new();
+    for dir in dirs {
+        let dir = normalize_mountpoint(dir)?;
+        // overlayfs doesn't look through mounts, so the lower directory can't hold or be under a partition
+        let mut mountpoints = partitions.iter().filter_map(|partition| partition.mountpoint.as_deref()).filter(|&mountpoint| mountpoint != "/");
+        if let Some(mountpoint) = mountpoints.find(|&mountpoint| is_within(mountpoint, &dir) || is_within(&dir, mountpoint)) {
+            bail!("Can't put an overlay on {} since the partition at {} is mounted there", dir, mountpoint);
+        }
+        if let Some(other) = overlays.iter().find(|other| is_within(&other.dir, &dir) || is_within(&dir, &other.dir)) {
+            bail!("The overlays on {} and {} can't be nested", other.dir, dir);
+        }
+        overlays.push(Overlay {
+            upper: format!("{overlay_mountpoint}{dir}/upper"),
+            work: format!("{overlay_mountpoint}{dir}/work"),
+            dir,
+        });
+    }
+    Ok(overlays)
+}
+
+/// Whether a path is the directory or anything under it
+fn is_within(path: &str, dir: &str) -> bool {
+    path == dir || path.starts_with(&format!("{dir}/"))
+}
+
+/// Creates the directory of each overlay and its upper and work directories in the unpacked
+/// image, before those are moved to the overlay partition. The upper directory is the root of
+/// the overlay, so it gets the owner and permissions of the directory it's on
+pub fn create_overlay_dirs(root: &Path, overlays: &[Overlay]) -> Result<()> {
+    for overlay in overlays {
+        let dir = create_mountpoint(root, &overlay.dir)?;
+        let metadata = fs::metadata(&dir)?;
+        let upper = create_mountpoint(root, &overlay.upper)?;
+        fs::set_permissions(&upper, metadata.permissions())?;
+        chown(&upper, Some(metadata.uid()), Some(metadata.gid()))?;
+        create_mountpoint(root, &overlay.work)?;
+    }
+    Ok(())
+}
+
+/// Moves the directory each partition is mounted at out of the unpacked image into the work
+/// directory, leaving an empty mountpoint with the same owner and permissions behind.
+/// Returns the directory with the files of each partition, None for swap
+pub fn split_partition_dirs(root: &Path, work_dir: &Path, partitions: &[Partition]) -> Result<Vec<Option<PathBuf>>> {
+    let mut sources = vec![None; partitions.len()];
+    // Deeper mountpoints go first so /var/log is moved out of /var before /var is
+    let mut order = (0..partitions.len()).collect::<Vec<usize>>();
+    order.sort_by_key(|&index| Reverse(partitions[index].mountpoint.as_deref().map(|mountpoint| mountpoint.matches('/').count())));
+    for index in order {
+        let partition = &partitions[index];
+        match (partition.kind, &partition.mountpoint) {
+            (PartitionKind::Root, _) => sources[index] = Some(root.to_path_buf()),
+            (_, Some(mountpoint)) => {
+                let mountpoint_path = create_mountpoint(root, mountpoint)?;
+                let source = work_dir.join(format!("partition-{}", index + 1));
+                fs::rename(&mountpoint_path, &source)?;
+                fs::create_dir(&mountpoint_path)?;
+                let metadata = fs::metadata(&source)?;
+                fs::set_permissions(&mountpoint_path, metadata.permissions())?;
+                chown(&mountpoint_path, Some(metadata.uid()), Some(metadata.gid()))?;
+                sources[index] = Some(source);
+            },
+            _ => {},
+        }
+    }
+    Ok(sources)
+}
+
+/// Finds the directory of a mountpoint in the unpacked image, creating whatever is missing.
+/// Symlinks are refused since they could point outside of the image
+fn create_mountpoint(root: &Path, mountpoint: &str) -> Result<PathBuf> {
+    let mut path = root.to_path_buf();
+    for part in mountpoint.trim_start_matches('/').split('/') {
+        path.push(part);
+        match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.is_dir() => {},
+            Ok(_) => bail!("{} in the image isn't a directory, so it can't be a mountpoint", mountpoint),
+            Err(_) => {
+                fs::create_dir(&path)?;
+                fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+            },
+        }
+    }
+    Ok(path)
+}
+
+/// The fstab entries that mount every partition. Root is mounted from root_device,
+/// which is a device mapper device when it's encrypted or verified
+pub fn fstab_entries(partitions: &[Partition], root_device: &str, partuuid: impl Fn(u32) -> String) -> Vec<FstabEntry> {
+    partitions.iter().enumerate().map(|(index, partition)| {
+        let device = match partition.kind {
+            PartitionKind::Root => root_device.to_string(),
+            _ => partuuid(index as u32 + 1),
+        };
+        match (&partition.mountpoint, partition.filesystem) {
+            (Some(mountpoint), Some(filesystem)) => {
+                let (options, pass) = match filesystem {
+                    // Root is checked first, then the rest
+                    Filesystem::Ext4 if partition.kind == PartitionKind::Root => ("defaults", 1),
+                    Filesystem::Ext4 => ("defaults", 2),
+                    _ if filesystem.is_read_only() => ("ro", 0),
+                    // fsck does nothing for xfs and btrfs
+                    _ => ("defaults", 0),
+                };
+                FstabEntry { device, mountpoint: mountpoint.clone(), fs_type: filesystem.name().to_string(), options: options.to_string(), pass }
+            },
+            _ => FstabEntry { device, mountpoint: "none".to_string(), fs_type: "swap".to_string(), options: "sw".to_string(), pass: 0 },
+        }
+    }).collect()
+}
+
+/// The fstab entries that mount the overlays, which come after the overlay partition they're kept on
+pub fn overlay_fstab_entries(overlays: &[Overlay], overlay_mountpoint: &str) -> Vec<FstabEntry> {
+    overlays.iter().map(|overlay| FstabEntry {
+        device: "overlay".to_string(),
+        mountpoint: overlay.dir.clone(),
+        fs_type: "overlay".to_string(),
+        options: format!("lowerdir={},upperdir={},workdir={},x-systemd.requires-mounts-for={}", overlay.dir, overlay.upper, overlay.work, overlay_mountpoint),
+        pass: 0,
+    }).collect()
+}
@@ -23,6 +23,18 @@ pub fn get_app_state_path() -> Result<Utf8PathBuf> {
     Ok(BASE_PATH.read().map_err(|_|{anyhow::anyhow!("Failed to get state path")})?.as_path().join("state.json"))
 }
 
+pub fn get_state_lock_path() -> Result<Utf8PathBuf> {
+    Ok(BASE_PATH.read().map_err(|_|{anyhow::anyhow!("Failed to get state lock path")})?.as_path().join("state.lock"))
+}
+
+pub fn get_base_path() -> Result<Utf8PathBuf> {
+    Ok(BASE_PATH.read().map_err(|_|{anyhow::anyhow!("Failed to get base path")})?.clone())
+}
+
+pub fn get_config_path() -> Result<Utf8PathBuf> {
+    Ok(BASE_PATH.read().map_err(|_|{anyhow::anyhow!("Failed to get config path")})?.as_path().join("config.toml"))
+}
+
 pub fn get_layers_path() -> Result<Utf8PathBuf> {
     Ok(BASE_PATH.read().map_err(|_|{anyhow::anyhow!("Failed to get layers path")})?.as_path().join("layers"))
 }
@@ -31,10 +43,18 @@ pub fn get_layers_compressed_path() -> Result<Utf8PathBuf> {
     Ok(BASE_PATH.read().map_err(|_|{anyhow::anyhow!("Failed to get layers compressed path")})?.as_path().join("layers_compressed"))
 }
 
+pub fn get_temp_path() -> Result<Utf8PathBuf> {
+    Ok(BASE_PATH.read().map_err(|_|{anyhow::anyhow!("Failed to get temp path")})?.as_path().join("tmp"))
+}
+
 pub fn get_docker_layers_path() -> Utf8PathBuf {
     Utf8PathBuf::from("/var/lib/docker/overlay2")
 }
 
 pub fn get_images_path() -> Result<Utf8PathBuf> {
     Ok(BASE_PATH.read().map_err(|_|{anyhow::anyhow!("Failed to get images path")})?.as_path().join("images"))
+}
+
+pub fn get_blobs_path() -> Result<Utf8PathBuf> {
+    Ok(BASE_PATH.read().map_err(|_|{anyhow::anyhow!("Failed to get blobs path")})?.as_path().join("blobs"))
 }
\ No newline at end of file
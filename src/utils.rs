@@ -1,14 +1,167 @@
-use core::str;
-use std::{fmt::Display, fs::{self, File}, io, path::Path, process::{Command, Output, Stdio}, thread::sleep, time::Duration};
+use std::{fmt::Display, fs::{self, File}, io::{self, Read, Seek, SeekFrom, Write}, os::unix::fs::MetadataExt, path::{Component, Path}, process::{Command, Stdio}};
 use anyhow::{anyhow, bail, Context, Result};
 use camino::Utf8PathBuf;
-use flate2::read::GzDecoder;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
 use serde_json::json;
-use tar::Archive;
+use sha2::{Digest, Sha256};
+use tar::{Archive, Builder};
 use tempfile::TempDir;
 
 
-use crate::{cli_commands::{burn_bootloader, copy_recursive, create_disk_image, create_loop_device, create_partition_table, detach_loop_device, format_ext4_file, mount_file, mount_with_offset, unmount_file}, docker_client::DockerClient, models::registry_models::{Manifest, Platform}, paths::get_images_path};
+use crate::{cli_commands::{burn_bootloader, check_filesystem_command_exists, clamp_file_times, copy_recursive, create_erofs_image, create_loop_device, create_squashfs_image, detach_loop_device, dig_holes, format_btrfs_file, format_ext4_file, format_ext4_file_reproducible, format_swap_file, format_xfs_file, xfs_protofile, luks_close, luks_format, luks_open, mount_file, mount_with_offset, unmount_file}, models::{input_models::{Filesystem, PartitionKind, PartitionSpec, PartitionTableType}, output_models::{EncryptionResult, PartitionResult, VerityResult}, registry_models::{OCI_LAYER_GZIP_MEDIA_TYPE, OCI_LAYER_MEDIA_TYPE}}, partition_table::{read_partition_table, PartitionTable, PartitionType}, partitions::{create_overlay_dirs, fstab_entries, overlay_fstab_entries, resolve_layout, resolve_overlays, split_partition_dirs, FstabEntry}, paths::{get_blobs_path, get_temp_path}, verity::{hash_tree_size, write_hash_tree, VerityLayout}};
+
+/// Prefix of the files that mark a path in a lower layer as deleted
+const WHITEOUT_PREFIX: &str = ".wh.";
+/// Marks a directory as opaque, hiding everything the lower layers had in it
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+/// Fixed values used in place of the random and time based ones
+/// the disk and filesystem tools would otherwise pick. They are deterministic on purpose:
+/// two builds of the same image share their ids, so only use them with `--reproducible`
+#[derive(Debug, Clone)]
+pub struct Reproducibility {
+    /// Timestamp used for the filesystem and any file newer than it
+    pub epoch: u64,
+    /// UUID of the root filesystem
+    pub fs_uuid: String,
+    /// Seed for the ext4 directory hashes
+    pub hash_seed: String,
+    /// Identifier written to the partition table
+    pub disk_id: String,
+}
+
+impl Reproducibility {
+    /// Derives the fixed values from the image digest, taking the timestamp
+    /// from SOURCE_DATE_EPOCH or falling back to the unix epoch
+    pub fn from_digest(digest: &str) -> Result<Reproducibility> {
+        let epoch = match std::env::var("SOURCE_DATE_EPOCH") {
+            Ok(value) => value.trim().parse::<u64>().context("SOURCE_DATE_EPOCH must be a unix timestamp")?,
+            Err(_) => 0,
+        };
+        let hex = digest.rsplit(':').next().context("Digest is empty")?;
+        if hex.len() < 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            bail!("Expected a sha256 digest but got {}", digest);
+        }
+        Ok(Reproducibility {
+            epoch,
+            fs_uuid: hex_to_uuid(&hex[0..32]),
+            hash_seed: hex_to_uuid(&hex[32..64]),
+            disk_id: format!("0x{}", &hex[0..8]),
+        })
+    }
+
+    /// The values for another partition of the same image, so no two filesystems share a uuid
+    pub fn for_partition(&self, number: u32) -> Reproducibility {
+        let hex = format!("{:x}", Sha256::digest(format!("{}{}", self.fs_uuid, number).as_bytes()));
+        Reproducibility {
+            epoch: self.epoch,
+            fs_uuid: hex_to_uuid(&hex[0..32]),
+            hash_seed: hex_to_uuid(&hex[32..64]),
+            disk_id: self.disk_id.clone(),
+        }
+    }
+}
+
+/// Formats 32 hex characters in the 8-4-4-4-12 uuid layout
+fn hex_to_uuid(hex: &str) -> String {
+    format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32])
+}
+
+/// Name of the device mapper device root is mounted from when it's encrypted or verified
+const MAPPED_ROOT_NAME: &str = "root";
+/// Space for the LUKS2 header, which is 16MB by default
+const LUKS2_HEADER_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Options that change how a drive image is created
+#[derive(Debug, Clone, Default)]
+pub struct DriveOptions {
+    /// When set the drive image is built bit-for-bit reproducibly
+    pub reproducibility: Option<Reproducibility>,
+    /// The filesystem of the root partition
+    pub filesystem: Filesystem,
+    /// Adds a partition with a dm-verity hash tree of the root partition
+    pub verity: bool,
+    /// When set the root partition is encrypted with LUKS2, using this file as the key
+    pub encryption_key: Option<Utf8PathBuf>,
+    /// The partitions of a custom layout, empty for a single root partition
+    pub partitions: Vec<PartitionSpec>,
+    pub partition_table: PartitionTableType,
+    /// Partitions start and end on multiples of this many bytes
+    pub alignment: u64,
+    /// Directories of a read only root to mount an overlay on, kept on the overlay partition
+    pub overlays: Vec<String>,
+}
+
+/// A drive image that was created and what the partitions on it need to be used
+#[derive(Debug, Clone)]
+pub struct DriveImage {
+    pub size: u64,
+    pub verity: Option<VerityResult>,
+    pub encryption: Option<EncryptionResult>,
+    pub partitions: Vec<PartitionResult>,
+}
+
+/// Picks a random partition table identifier, in the `0x<hex>` form
+fn random_disk_id() -> Result<String> {
+    Ok(format!("0x{:08x}", u32::from_le_bytes(random_bytes()?)))
+}
+
+/// Picks a random version 4 uuid
+fn random_uuid() -> Result<String> {
+    let mut bytes: [u8; 16] = random_bytes()?;
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    Ok(hex_to_uuid(&bytes.iter().map(|byte| format!("{byte:02x}")).collect::<String>()))
+}
+
+/// Adds the encrypted root partition to /etc/crypttab so the initramfs unlocks it at boot,
+/// asking for the passphrase since the key file isn't in the image
+fn write_crypttab(root: &Path, luks_uuid: &str) -> Result<()> {
+    let crypttab_path = root.join("etc/crypttab");
+    let existing = match fs::symlink_metadata(&crypttab_path) {
+        Ok(metadata) if metadata.is_file() => fs::read_to_string(&crypttab_path)?,
+        Ok(_) => {
+            fs::remove_file(&crypttab_path)?;
+            String::new()
+        },
+        Err(_) => String::new(),
+    };
+    let mut crypttab = existing.lines()
+        .filter(|line| line.split_whitespace().next() != Some(MAPPED_ROOT_NAME))
+        .map(|line| format!("{line}\n"))
+        .collect::<String>();
+    crypttab.push_str(&format!("{MAPPED_ROOT_NAME} UUID={luks_uuid} none luks\n"));
+    fs::write(&crypttab_path, crypttab)?;
+    Ok(())
+}
+
+/// Writes the entries to /etc/fstab, keeping any other entries the image has
+fn write_fstab(root: &Path, entries: &[FstabEntry]) -> Result<()> {
+    let etc = root.join("etc");
+    fs::create_dir_all(&etc)?;
+    let fstab_path = etc.join("fstab");
+    // A symlink could point outside of the unpacked image
+    let existing = match fs::symlink_metadata(&fstab_path) {
+        Ok(metadata) if metadata.is_file() => fs::read_to_string(&fstab_path)?,
+        Ok(_) => {
+            fs::remove_file(&fstab_path)?;
+            String::new()
+        },
+        Err(_) => String::new(),
+    };
+    let mut fstab = existing.lines()
+        .filter(|line| {
+            let mountpoint = line.split_whitespace().nth(1);
+            !entries.iter().any(|entry| entry.mountpoint != "none" && Some(entry.mountpoint.as_str()) == mountpoint)
+        })
+        .map(|line| format!("{line}\n"))
+        .collect::<String>();
+    for entry in entries {
+        fstab.push_str(&format!("{} {} {} {} 0 {}\n", entry.device, entry.mountpoint, entry.fs_type, entry.options, entry.pass));
+    }
+    fs::write(&fstab_path, fstab)?;
+    Ok(())
+}
 
 
 pub fn unpack_tar_gz(tar_gz: &Path, dest: &Path) -> Result<()> {
@@ -18,21 +171,276 @@ pub fn unpack_tar_gz(tar_gz: &Path, dest: &Path) -> Result<()> {
     Ok(())
 }
 
+/// Creates a temporary directory in the base path, so whatever a run that was killed
+/// leaves behind shows up in `df` and is removed by `prune`
+pub fn create_temp_dir() -> Result<TempDir> {
+    let temp_path = get_temp_path()?;
+    fs::create_dir_all(&temp_path)?;
+    Ok(TempDir::new_in(temp_path)?)
+}
+
+/// Hashes a file, returning its digest in the `sha256:<hex>` form
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(format!("sha256:{:x}", hasher.finalize()))
+}
+
+/// Reads bytes from the kernel's random number generator, for ids and salts that
+/// have to be unique and can't be guessed
+pub fn random_bytes<const N: usize>() -> Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    File::open("/dev/urandom").context("Failed to open /dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Hashes bytes, returning the digest in the `sha256:<hex>` form
+pub fn sha256_bytes(bytes: &[u8]) -> String {
+    format!("sha256:{:x}", Sha256::digest(bytes))
+}
+
+/// Stores a manifest or config in the blobs folder, returning its digest.
+/// Commands that only read the state can run at the same time, so the blob is written
+/// under a name of its own and renamed into place
+pub fn store_blob(bytes: &[u8]) -> Result<String> {
+    let blobs_path = get_blobs_path()?;
+    fs::create_dir_all(&blobs_path)?;
+    let digest = sha256_bytes(bytes);
+    let path = blobs_path.join(&digest);
+    if !path.exists() {
+        let temp_path = blobs_path.join(format!("{digest}.{}.tmp", std::process::id()));
+        fs::write(&temp_path, bytes)?;
+        fs::rename(&temp_path, &path)?;
+    }
+    Ok(digest)
+}
+
+/// Reads a manifest or config from the blobs folder
+pub fn read_stored_blob(digest: &str) -> Result<Vec<u8>> {
+    let path = get_blobs_path()?.join(digest);
+    fs::read(&path).context(format!("Blob {} is not stored", digest))
+}
+
+/// Matches text against a shell style pattern where * is any run of characters and ? is any one
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<char>>();
+    let text = text.chars().collect::<Vec<char>>();
+    let (mut p, mut t) = (0, 0);
+    // Where the last * was and the text position it was tried at, to backtrack to
+    let mut star = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            },
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            },
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                },
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Parses an RFC 3339 timestamp like the created time in image configs into seconds
+/// and nanoseconds since the epoch, so timestamps with different precisions compare correctly
+pub fn parse_rfc3339(timestamp: &str) -> Option<(i64, u32)> {
+    let (date, time) = timestamp.split_once(['T', 't', ' '])?;
+    let mut date_parts = date.splitn(3, '-').map(|part| part.parse::<i64>().ok());
+    let (year, month, day) = (date_parts.next()??, date_parts.next()??, date_parts.next()??);
+    // The offset is either Z or +hh:mm / -hh:mm after the time
+    let (time, offset) = match time.find(['Z', 'z', '+', '-']) {
+        Some(index) => time.split_at(index),
+        None => return None,
+    };
+    let offset_seconds = match offset {
+        "Z" | "z" => 0,
+        _ => {
+            let sign = if offset.starts_with('-') { -1 } else { 1 };
+            let (hours, minutes) = offset[1..].split_once(':')?;
+            sign * (hours.parse::<i64>().ok()? * 3600 + minutes.parse::<i64>().ok()? * 60)
+        },
+    };
+    let (time, fraction) = time.split_once('.').unwrap_or((time, ""));
+    let mut time_parts = time.splitn(3, ':').map(|part| part.parse::<i64>().ok());
+    let (hour, minute, second) = (time_parts.next()??, time_parts.next()??, time_parts.next()??);
+    let nanos = if fraction.is_empty() {
+        0
+    } else {
+        format!("{:0<9}", fraction.get(..9).unwrap_or(fraction)).parse::<u32>().ok()?
+    };
+    // Days since the epoch from the civil date, counting years from March so leap days come last
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+    Some((days * 86400 + hour * 3600 + minute * 60 + second - offset_seconds, nanos))
+}
+
+/// Makes sure the contents of the file match the digest it is stored under
+pub fn verify_digest(path: &Path, digest: &str) -> Result<()> {
+    if !digest.starts_with("sha256:") {
+        bail!("Unsupported digest algorithm for {}", digest);
+    }
+    let actual = sha256_file(path)?;
+    if actual != digest {
+        bail!("Digest mismatch for {}: expected {} but got {}", path.display(), digest, actual);
+    }
+    Ok(())
+}
+
+/// Gets the OCI media type of a layer archive from its compression
+pub fn layer_media_type(path: &Path) -> Result<&'static str> {
+    let mut magic = [0u8; 2];
+    let read = File::open(path)?.read(&mut magic)?;
+    Ok(if read == 2 && magic == [0x1f, 0x8b] { OCI_LAYER_GZIP_MEDIA_TYPE } else { OCI_LAYER_MEDIA_TYPE })
+}
+
+/// Opens a layer archive, decompressing it if it is gzipped.
+/// Layers exported from a docker daemon are usually plain tars
+pub fn open_layer_archive(path: &Path) -> Result<Archive<Box<dyn Read>>> {
+    let mut file = File::open(path)?;
+    let mut magic = [0u8; 4];
+    let read = file.read(&mut magic)?;
+    if read == 4 && magic == [0x28, 0xb5, 0x2f, 0xfd] {
+        bail!("Layer archive {} is zstd compressed which is not supported", path.display());
+    }
+    let is_gzip = read >= 2 && magic[..2] == [0x1f, 0x8b];
+    file.seek(SeekFrom::Start(0))?;
+    let reader: Box<dyn Read> = if is_gzip {
+        Box::new(GzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+    Ok(Archive::new(reader))
+}
+
 /// Decompresses the layers and stores them in the output path
-pub fn decompress_layers(layers: &Vec<String>, layers_path: &Path, output_path: &Path) -> Result<()> {
+pub fn decompress_layers(layers: &[String], layers_path: &Path, output_path: &Path) -> Result<()> {
     for layer in layers {
         let layer_archive_path = layers_path.join(format!("{layer}.tgz"));
         if !layer_archive_path.exists() {
             bail!("Layer archive {} not found", layer_archive_path.display());
         }
-        let tar_gz = File::open(layer_archive_path)?;
-        let tar = GzDecoder::new(tar_gz);
-        let mut archive = Archive::new(tar);
-        archive.unpack(output_path)?;
+        // Whiteouts only hide files from the layers below, so they're applied before the layer is unpacked
+        apply_whiteouts(&mut open_layer_archive(&layer_archive_path)?, output_path)?;
+        let mut archive = open_layer_archive(&layer_archive_path)?;
+        archive.set_preserve_permissions(true);
+        archive.set_preserve_ownerships(true);
+        archive.set_unpack_xattrs(true);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            if is_whiteout(&entry.path()?) {
+                continue;
+            }
+            entry.unpack_in(output_path)?;
+        }
+    }
+    Ok(())
+}
+
+fn is_whiteout(path: &Path) -> bool {
+    path.file_name().and_then(|name| name.to_str()).is_some_and(|name| name.starts_with(WHITEOUT_PREFIX))
+}
+
+/// Removes the files a layer's whiteouts hide. An opaque whiteout hides everything
+/// that was in its directory, any other hides the file named after the prefix
+fn apply_whiteouts(archive: &mut Archive<Box<dyn Read>>, output_path: &Path) -> Result<()> {
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let path = entry.path()?.into_owned();
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) else {
+            continue;
+        };
+        let Some(directory) = path.parent().and_then(|parent| resolve_in_root(output_path, parent)) else {
+            continue;
+        };
+        if name == OPAQUE_WHITEOUT {
+            for child in fs::read_dir(&directory)? {
+                remove_path(&child?.path())?;
+            }
+        } else if !matches!(hidden, "" | "." | "..") && !hidden.contains('/') {
+            remove_path(&directory.join(hidden))?;
+        }
+    }
+    Ok(())
+}
+
+/// Joins a relative path onto the root as long as it stays inside it. Paths that don't exist
+/// or go through a symlink, which could point anywhere on the host, give None
+fn resolve_in_root(root: &Path, path: &Path) -> Option<std::path::PathBuf> {
+    let mut resolved = root.to_path_buf();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => resolved.push(part),
+            Component::CurDir => continue,
+            _ => return None,
+        }
+        if !fs::symlink_metadata(&resolved).ok()?.is_dir() {
+            return None;
+        }
+    }
+    Some(resolved)
+}
+
+/// Removes a file, symlink or directory tree, ignoring paths that don't exist
+fn remove_path(path: &Path) -> Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path)?,
+        Ok(_) => fs::remove_file(path)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {},
+        Err(e) => return Err(e.into()),
+    }
+    Ok(())
+}
+
+/// Flattens the layers into a directory, which has to be empty if it exists
+pub fn export_rootfs_dir(layers: &[String], layers_path: &Path, dir: &Path) -> Result<()> {
+    if dir.exists() && fs::read_dir(dir)?.next().is_some() {
+        bail!("{} is not empty", dir.display());
+    }
+    fs::create_dir_all(dir)?;
+    decompress_layers(layers, layers_path, dir)
+}
+
+/// Flattens the layers and writes them as a tarball, compressed
+/// with gzip or zstd when the file name ends in .gz, .tgz or .zst
+pub fn export_rootfs_tar(layers: &[String], layers_path: &Path, tar_path: &Path) -> Result<()> {
+    let temp_combined_dir = create_temp_dir()?;
+    decompress_layers(layers, layers_path, temp_combined_dir.path())?;
+    let file = File::create(tar_path)?;
+    let name = tar_path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+    if name.ends_with(".gz") || name.ends_with(".tgz") {
+        write_tar(temp_combined_dir.path(), GzEncoder::new(file, Compression::default()))?.finish()?;
+    } else if name.ends_with(".zst") || name.ends_with(".tzst") {
+        write_tar(temp_combined_dir.path(), zstd::Encoder::new(file, 0)?)?.finish()?;
+    } else {
+        write_tar(temp_combined_dir.path(), file)?;
     }
     Ok(())
 }
 
+fn write_tar<W: Write>(dir: &Path, writer: W) -> Result<W> {
+    let mut builder = Builder::new(writer);
+    builder.follow_symlinks(false);
+    builder.append_dir_all(".", dir)?;
+    Ok(builder.into_inner()?)
+}
+
 /// Dumps a local image contents to a folder
 pub fn save_local_image(image_name: String, target_path: &Path) -> Result<()> {
     // Spawn the `docker save` command
@@ -66,7 +474,7 @@ pub fn save_local_image(image_name: String, target_path: &Path) -> Result<()> {
     Ok(())
 }
 
-pub fn layer_digest_to_cache_id(digest: &String) -> Result<String> {
+pub fn layer_digest_to_cache_id(digest: &str) -> Result<String> {
     let layer_cache_id_path_str = format!("/var/lib/docker/image/overlay2/layerdb/sha256/{}/cache-id", digest);
     dbg!(layer_cache_id_path_str.as_str());
     let layer_cache_id_path = Path::new(layer_cache_id_path_str.as_str());
@@ -74,51 +482,362 @@ pub fn layer_digest_to_cache_id(digest: &String) -> Result<String> {
     Ok(cache_id)
 }
 
-/// Creates a drive image from layers
-/// returns the size of the newly created image
-pub fn create_drive_image(layers: &Vec<String>, layers_path: &Path, bootloader_path: &String, image_path: &Utf8PathBuf) -> Result<u64>{
-    // Create two temp dirs, one for the mount and one for the unpacking
-    let temp_combined_dir = TempDir::new()?;
-    let temp_bootloader_dir = TempDir::new()?;
-    let temp_mount_dir = TempDir::new()?;
+/// Creates a sparse file of the given size, no disk blocks are used until they are written
+pub fn create_disk_image(image_path: &Utf8PathBuf, size: u64) -> Result<()> {
+    File::create(image_path)?.set_len(size)?;
+    Ok(())
+}
+
+/// Copies a file, leaving holes wherever the source has blocks of zeros
+pub fn copy_sparse(source: &Path, target: &Path) -> Result<()> {
+    const BLOCK_SIZE: usize = 4096;
+    let mut input = File::open(source)?;
+    let mut output = File::create(target)?;
+    let mut buffer = vec![0u8; BLOCK_SIZE * 256];
+    let mut length = 0;
+    loop {
+        let read = input.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        for block in buffer[..read].chunks(BLOCK_SIZE) {
+            if block.iter().all(|b| *b == 0) {
+                output.seek(SeekFrom::Current(block.len() as i64))?;
+            } else {
+                output.write_all(block)?;
+            }
+        }
+        length += read as u64;
+    }
+    // Trailing holes need the length set explicitly
+    output.set_len(length)?;
+    Ok(())
+}
+
+/// Writes the contents of a file into another starting at the given offset
+fn write_at_offset(source: &Path, target: &Path, offset: u64) -> Result<()> {
+    let mut input = File::open(source)?;
+    let mut output = fs::OpenOptions::new().write(true).open(target)?;
+    output.seek(SeekFrom::Start(offset))?;
+    io::copy(&mut input, &mut output)?;
+    Ok(())
+}
+
+/// Gets the number of bytes of disk space a file actually uses
+pub fn get_allocated_size(path: &Path) -> Result<u64> {
+    Ok(fs::metadata(path)?.blocks() * 512)
+}
+
+/// Creates a drive image from layers, with a partition for each one in the layout
+/// returns the size of the newly created image and what's needed to use its partitions
+pub fn create_drive_image(layers: &[String], layers_path: &Path, bootloader_path: &str, image_path: &Utf8PathBuf, options: &DriveOptions) -> Result<DriveImage>{
+    let partitions = resolve_layout(&options.partitions, options.filesystem)?;
+    let root_index = partitions.iter().position(|partition| partition.kind == PartitionKind::Root).context("The layout has no root partition")?;
+    let filesystem = partitions[root_index].filesystem.context("The root partition has no filesystem")?;
+    let overlays = resolve_overlays(&options.overlays, &partitions)?;
+    for partition in &partitions {
+        match partition.filesystem {
+            Some(filesystem) => {
+                check_filesystem_command_exists(filesystem)?;
+                if options.reproducibility.is_some() && matches!(filesystem, Filesystem::Xfs | Filesystem::Btrfs) {
+                    bail!("Reproducible builds aren't supported for {}", filesystem.name());
+                }
+            },
+            None => {
+                which::which("mkswap").context("mkswap is needed to create swap partitions")?;
+            },
+        }
+    }
+    if options.verity && !filesystem.is_read_only() {
+        bail!("dm-verity needs a read only filesystem, use --fs squashfs or --fs erofs");
+    }
+    if options.encryption_key.is_some() {
+        if filesystem.is_read_only() {
+            bail!("Encryption needs a filesystem that's written through a device, not {}", filesystem.name());
+        }
+        if options.reproducibility.is_some() {
+            bail!("Encrypted images can't be reproducible, LUKS generates a random volume key");
+        }
+        which::which("cryptsetup").context("cryptsetup is needed to encrypt the root partition")?;
+    }
+    // The verity hash tree takes a partition of its own
+    if options.partition_table == PartitionTableType::Dos && partitions.len() + options.verity as usize > 4 {
+        bail!("A DOS partition table can only hold 4 partitions");
+    }
+    // Create temp dirs for the mount, the unpacking and the files built along the way
+    let temp_combined_dir = create_temp_dir()?;
+    let temp_work_dir = create_temp_dir()?;
+    let temp_mount_dir = create_temp_dir()?;
     
-    if image_path.exists() { fs::remove_file(&image_path)?;}
+    if image_path.exists() { fs::remove_file(image_path)?;}
     // Copy layers to the temporary directory
-    decompress_layers(&layers, layers_path, temp_combined_dir.path())?;
-    let mut image_size = fs_extra::dir::get_size(&temp_combined_dir.path())? * 2;
-    
-    if image_size == 0 {
-        bail!("Image size must be greater than 0");
-    }
-    image_size += 1024 * 1024 * 20; // Add 20MB to the image size for the partition table and bootloader
-    let blocks = image_size / 4096;
-
-    // Create file and mount it so we can copy the files into it
-    create_disk_image(&image_path, blocks)?;
-    create_partition_table(&image_path)?;
-    let loop_device = create_loop_device()?;
-    // Mount the loop device to the image with a 1MB offset
-    mount_with_offset(&image_path, &loop_device, 1024 * 1024)?;
-    format_ext4_file(&loop_device.to_string())?;
-    // Mount the loop device to the temp mount directory
-    mount_file(&loop_device.to_string(), &temp_mount_dir.path().to_str().context("Failed to convert temp mount dir to string")?.to_string())?;
-    println!("Mounted loop device to temp mount dir at {}", temp_mount_dir.path().display());
-    // sleep(Duration::from_secs(300));
-    copy_recursive(temp_combined_dir.path(), temp_mount_dir.path())?;
-    println!("Copied files to temp mount dir");
-    // Copy the bootloader locally so we can use it after unmounting the image
-    let target_bootloader_path = Utf8PathBuf::from_path_buf(temp_bootloader_dir.path().join("bootloader.img")).map_err(|e|{anyhow!("Failed to convert temp mount dir to utf8")})?;
-    let bootloader_source_path = temp_mount_dir.path().join(bootloader_path.strip_prefix("/").context("Failed to strip prefix")?);
-    fs::copy(bootloader_source_path.as_path(), target_bootloader_path.as_path())?;
-    println!("Waiting to allow inspection of loop device {} and bootloader {}", loop_device.to_string(), target_bootloader_path.to_string());
-    // sleep(Duration::from_secs(300));
-    // Unmount the image now that we're done
-    unmount_file(&loop_device)?;
-    // Detach the loop device
-    detach_loop_device(&loop_device.to_string())?;
+    decompress_layers(layers, layers_path, temp_combined_dir.path())?;
+    let disk_id = match &options.reproducibility {
+        Some(reproducibility) => reproducibility.disk_id.clone(),
+        None => random_disk_id()?,
+    };
+    let mut table = PartitionTable::new(options.partition_table, &disk_id, options.alignment)?;
+    // The partition ids only depend on the disk id, so fstab can have them before the table is written
+    let partuuids = (1..=partitions.len() as u32 + options.verity as u32)
+        .map(|number| format!("PARTUUID={}", table.partuuid(number)))
+        .collect::<Vec<String>>();
+    let partuuid = |number: u32| partuuids[number as usize - 1].clone();
+    let luks_uuid = match options.encryption_key {
+        Some(_) => Some(random_uuid()?),
+        None => None,
+    };
+    // The kernel sets up the verity device from its command line and the initramfs unlocks
+    // the LUKS device from crypttab, and those are what root is mounted from
+    let root_device = if options.verity || luks_uuid.is_some() { format!("/dev/mapper/{MAPPED_ROOT_NAME}") } else { partuuid(root_index as u32 + 1) };
+    let mut entries = fstab_entries(&partitions, &root_device, partuuid);
+    if let Some(overlay_mountpoint) = partitions.iter().find(|partition| partition.kind == PartitionKind::Overlay).and_then(|partition| partition.mountpoint.as_deref()) {
+        entries.extend(overlay_fstab_entries(&overlays, overlay_mountpoint));
+    }
+    write_fstab(temp_combined_dir.path(), &entries)?;
+    create_overlay_dirs(temp_combined_dir.path(), &overlays)?;
+    if let Some(luks_uuid) = &luks_uuid {
+        write_crypttab(temp_combined_dir.path(), luks_uuid)?;
+    }
+    // Copy the bootloader out so we can use it once the filesystem is built
+    let target_bootloader_path = Utf8PathBuf::from_path_buf(temp_work_dir.path().join("bootloader.img")).map_err(|_|{anyhow!("Failed to convert temp work dir to utf8")})?;
+    let bootloader_relative_path = bootloader_path.strip_prefix("/").context("Failed to strip prefix")?;
+    fs::copy(temp_combined_dir.path().join(bootloader_relative_path), target_bootloader_path.as_path())?;
+    let sources = split_partition_dirs(temp_combined_dir.path(), temp_work_dir.path(), &partitions)?;
+    if let Some(reproducibility) = &options.reproducibility {
+        // Directories created while unpacking get the current time
+        for source in sources.iter().flatten() {
+            clamp_file_times(source, reproducibility.epoch)?;
+        }
+    }
+    // Root keeps the values derived from the digest, the other partitions get their own
+    let reproducibility = |index: usize| options.reproducibility.as_ref().map(|reproducibility| {
+        if index == root_index { reproducibility.clone() } else { reproducibility.for_partition(index as u32 + 1) }
+    });
+
+    // Read only filesystems are built from their directory in one go and their partition is
+    // sized to fit them, the others are sized for their files unless a size was asked for
+    let mut built = vec![None; partitions.len()];
+    let mut sizes = Vec::with_capacity(partitions.len());
+    for (index, partition) in partitions.iter().enumerate() {
+        let size = match (partition.filesystem, &sources[index]) {
+            (Some(filesystem), Some(source)) if filesystem.is_read_only() => {
+                let filesystem_path = temp_work_dir.path().join(format!("partition-{}.img", index + 1));
+                match filesystem {
+                    Filesystem::Squashfs => create_squashfs_image(source, &filesystem_path, reproducibility(index).as_ref())?,
+                    _ => create_erofs_image(source, &filesystem_path, reproducibility(index).as_ref(), partition.label.as_deref())?,
+                }
+                let size = fs::metadata(&filesystem_path)?.len();
+                built[index] = Some(filesystem_path);
+                partition.size.unwrap_or_default().max(size)
+            },
+            (Some(filesystem), Some(source)) => match partition.size {
+                Some(size) => size,
+                None => {
+                    let mut size = fs_extra::dir::get_size(source)? * 2;
+                    if size == 0 && index == root_index {
+                        bail!("Image size must be greater than 0");
+                    }
+                    size += 1024 * 1024 * 20; // Add 20MB for the filesystem's own structures
+                    if index == root_index && luks_uuid.is_some() {
+                        size += LUKS2_HEADER_SIZE;
+                    }
+                    // mkfs refuses to create xfs and btrfs filesystems below these sizes
+                    match filesystem {
+                        Filesystem::Xfs => size.max(300 * 1024 * 1024),
+                        Filesystem::Btrfs => size.max(128 * 1024 * 1024),
+                        _ => size,
+                    }
+                },
+            },
+            _ => partition.size.context("A swap partition needs a size")?,
+        };
+        sizes.push(size);
+    }
+    // Partitions follow each other on the alignment after the partition table, which leaves
+    // room for the bootloader, with the verity hash tree of the root partition last
+    let bootable_index = partitions.iter().position(|partition| partition.kind == PartitionKind::Boot).unwrap_or(root_index);
+    for (index, partition) in partitions.iter().enumerate() {
+        let partition_type = match partition.kind {
+            PartitionKind::Swap => PartitionType::Swap,
+            PartitionKind::Root if luks_uuid.is_some() => PartitionType::Luks,
+            _ => PartitionType::Linux,
+        };
+        let name = partition.label.as_deref().unwrap_or(partition.kind.name());
+        table.add(sizes[index], partition_type, index == bootable_index, name)?;
+    }
+    if options.verity {
+        // The hash tree covers the whole root partition, padding included
+        table.add(hash_tree_size(table.partitions[root_index].size), PartitionType::VerityHash, false, "verity")?;
+    }
+    let image_size = table.disk_size();
+    create_disk_image(image_path, image_size)?;
+    table.write(image_path.as_std_path())?;
+    // Everything after this goes by the table read back from the image
+    let entries = read_partition_table(image_path.as_std_path())?;
+    let matches = entries.len() == table.partitions.len() && entries.iter().all(|entry| format!("PARTUUID={}", entry.partuuid) == partuuid(entry.number));
+    if !matches {
+        bail!("The partition table read back from the drive image doesn't match the one written");
+    }
+
+    for (index, partition) in partitions.iter().enumerate() {
+        if let Some(filesystem_path) = &built[index] {
+            write_at_offset(filesystem_path, image_path.as_std_path(), entries[index].start)?;
+            continue;
+        }
+        let loop_device = create_loop_device()?;
+        // Attach the loop device to just this partition of the image
+        mount_with_offset(image_path, &loop_device, entries[index].start, entries[index].size)?;
+        let mut devices = PartitionDevices { loop_device: Some(loop_device.clone()), mapped_name: None, mounted: None };
+        let label = partition.label.as_deref();
+        match (partition.filesystem, &sources[index]) {
+            (Some(filesystem), Some(source)) => {
+                // An encrypted partition is formatted as LUKS and the filesystem goes in the device it maps to
+                let encryption = match (&options.encryption_key, &luks_uuid) {
+                    (Some(key_file), Some(luks_uuid)) if index == root_index => Some((key_file, luks_uuid, format!("whaledrive-{luks_uuid}"))),
+                    _ => None,
+                };
+                let device = match &encryption {
+                    Some((key_file, luks_uuid, mapped_name)) => {
+                        luks_format(loop_device.as_str(), key_file, luks_uuid)?;
+                        let device = luks_open(loop_device.as_str(), key_file, mapped_name)?;
+                        devices.mapped_name = Some(mapped_name.clone());
+                        device
+                    },
+                    None => loop_device.clone(),
+                };
+                // mkfs fills the filesystem from the source directory where it can, otherwise it's filled through a mount
+                let populated = match (filesystem, reproducibility(index)) {
+                    (Filesystem::Ext4, Some(reproducibility)) => {
+                        // Mounting the filesystem would stamp the superblock and journal with the
+                        // current time, so mkfs populates it directly from the unpacked layers instead
+                        format_ext4_file_reproducible(device.as_str(), source, &reproducibility, label)?;
+                        true
+                    },
+                    (Filesystem::Btrfs, _) => {
+                        format_btrfs_file(device.as_str(), source, label)?;
+                        true
+                    },
+                    // A tree with names that can't go in a protofile is filled through a mount instead
+                    (Filesystem::Xfs, _) => {
+                        let protofile = xfs_protofile(source)?;
+                        format_xfs_file(device.as_str(), protofile.as_ref(), label)?;
+                        protofile.is_some()
+                    },
+                    _ => {
+                        format_ext4_file(device.as_str(), label)?;
+                        false
+                    },
+                };
+                if !populated {
+                    // Mount the loop device to the temp mount directory
+                    mount_file(device.as_str(), temp_mount_dir.path().to_str().context("Failed to convert temp mount dir to string")?)?;
+                    devices.mounted = Some(device.clone());
+                    eprintln!("Mounted loop device to temp mount dir at {}", temp_mount_dir.path().display());
+                    // sleep(Duration::from_secs(300));
+                    copy_recursive(source, temp_mount_dir.path())?;
+                    eprintln!("Copied files to temp mount dir");
+                    eprintln!("Waiting to allow inspection of loop device {} and bootloader {}", device, target_bootloader_path);
+                    // sleep(Duration::from_secs(300));
+                    // Unmount the image now that we're done
+                    devices.unmount()?;
+                }
+            },
+            _ => {
+                let uuid = reproducibility(index).map(|reproducibility| reproducibility.fs_uuid);
+                format_swap_file(loop_device.as_str(), label, uuid.as_deref())?;
+            },
+        }
+        // Close the LUKS device and detach the loop device
+        devices.close()?;
+    }
+    let mut verity = None;
+    if options.verity {
+        let layout = VerityLayout {
+            data_offset: entries[root_index].start,
+            data_size: entries[root_index].size,
+            hash_offset: entries[partitions.len()].start,
+            data_device: partuuid(root_index as u32 + 1),
+            hash_device: partuuid(partitions.len() as u32 + 1),
+        };
+        let (salt, uuid) = verity_salt_and_uuid(options.reproducibility.as_ref())?;
+        verity = Some(write_hash_tree(image_path.as_std_path(), &layout, &salt, &uuid)?);
+    }
     // And finally, burn the bootloader
-    burn_bootloader(&image_path, &target_bootloader_path)?;
-    Ok(image_size)
+    burn_bootloader(image_path, &target_bootloader_path)?;
+    // mkfs and the copy write out some blocks of zeros that don't need to take up space
+    dig_holes(image_path)?;
+    let encryption = luks_uuid.map(|luks_uuid| EncryptionResult {
+        format: "luks2".to_string(),
+        luks_uuid,
+        device: partuuid(root_index as u32 + 1),
+        mapped_device: format!("/dev/mapper/{MAPPED_ROOT_NAME}"),
+    });
+    let partitions = partitions.iter().enumerate().map(|(index, partition)| PartitionResult {
+        number: index as u32 + 1,
+        kind: partition.kind.name().to_string(),
+        mountpoint: partition.mountpoint.clone(),
+        filesystem: partition.filesystem.map(|filesystem| filesystem.name().to_string()),
+        label: partition.label.clone(),
+        offset: entries[index].start,
+        size: entries[index].size,
+        device: partuuid(index as u32 + 1),
+    }).collect();
+    Ok(DriveImage { size: image_size, verity, encryption, partitions })
+}
+
+/// What's set up while a partition is filled: the loop device, the LUKS device mapped on it and
+/// the mounted filesystem. Whatever is still set up is torn down when it's dropped, so a step
+/// that fails partway doesn't leave them behind
+struct PartitionDevices {
+    loop_device: Option<Utf8PathBuf>,
+    mapped_name: Option<String>,
+    mounted: Option<Utf8PathBuf>,
+}
+
+impl PartitionDevices {
+    fn unmount(&mut self) -> Result<()> {
+        if let Some(device) = self.mounted.take() {
+            unmount_file(&device)?;
+        }
+        Ok(())
+    }
+
+    /// Tears everything down in order, unmounting before closing and closing before detaching
+    fn close(mut self) -> Result<()> {
+        self.tear_down()
+    }
+
+    fn tear_down(&mut self) -> Result<()> {
+        self.unmount()?;
+        if let Some(mapped_name) = self.mapped_name.take() {
+            luks_close(&mapped_name)?;
+        }
+        if let Some(loop_device) = self.loop_device.take() {
+            detach_loop_device(loop_device.as_str())?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PartitionDevices {
+    fn drop(&mut self) {
+        if let Err(e) = self.tear_down() {
+            eprintln!("Failed to clean up after building the partition: {}", e);
+        }
+    }
+}
+
+/// The salt and hash device uuid of a verity tree. They're random, except for reproducible
+/// builds where they're derived from the image digest so every build gets the same root hash
+fn verity_salt_and_uuid(reproducibility: Option<&Reproducibility>) -> Result<([u8; 32], [u8; 16])> {
+    let Some(reproducibility) = reproducibility else {
+        return Ok((random_bytes()?, random_bytes()?));
+    };
+    let seed = &reproducibility.fs_uuid;
+    let salt: [u8; 32] = Sha256::digest(format!("{seed}verity-salt").as_bytes()).into();
+    let mut uuid = [0u8; 16];
+    uuid.copy_from_slice(&Sha256::digest(format!("{seed}verity-uuid").as_bytes())[0..16]);
+    Ok((salt, uuid))
 }
 
 pub trait UnwrapOrPanicJson<T> {
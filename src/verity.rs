@@ -0,0 +1,231 @@
+use std::{fs::{File, OpenOptions}, io::{Read, Seek, SeekFrom, Write}, path::Path};
+
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+
+use crate::models::output_models::VerityResult;
+
+/// Data and hash blocks are both 4 KiB, the veritysetup default
+pub const VERITY_BLOCK_SIZE: u64 = 4096;
+const DIGEST_SIZE: u64 = 32;
+const HASHES_PER_BLOCK: u64 = VERITY_BLOCK_SIZE / DIGEST_SIZE;
+const SUPERBLOCK_SIZE: usize = 512;
+
+/// Where the data the tree covers and the tree itself are in the image,
+/// and the devices the kernel will find them on
+pub struct VerityLayout {
+    pub data_offset: u64,
+    pub data_size: u64,
+    pub hash_offset: u64,
+    pub data_device: String,
+    pub hash_device: String,
+}
+
+/// Number of hash blocks in each level of the tree, starting from the one over the data blocks.
+/// Like veritysetup and the kernel, a single data block has no levels and its hash is the root hash
+fn level_sizes(data_blocks: u64) -> Vec<u64> {
+    let mut levels = Vec::new();
+    let mut blocks = data_blocks;
+    while blocks > 1 {
+        blocks = blocks.div_ceil(HASHES_PER_BLOCK);
+        levels.push(blocks);
+    }
+    levels
+}
+
+/// The size of the hash area, a superblock followed by the tree
+pub fn hash_tree_size(data_size: u64) -> u64 {
+    let data_blocks = data_size / VERITY_BLOCK_SIZE;
+    (1 + level_sizes(data_blocks).iter().sum::<u64>()) * VERITY_BLOCK_SIZE
+}
+
+/// Computes a dm-verity (format 1, sha256) hash tree over the data and writes it with a
+/// superblock veritysetup understands, returning the root hash and parameters
+pub fn write_hash_tree(image_path: &Path, layout: &VerityLayout, salt: &[u8; 32], uuid: &[u8; 16]) -> Result<VerityResult> {
+    let data_blocks = layout.data_size / VERITY_BLOCK_SIZE;
+    let levels = level_sizes(data_blocks);
+    let mut input = File::open(image_path)?;
+    input.seek(SeekFrom::Start(layout.data_offset))?;
+
+    // Each level hashes the blocks of the one below it, packing the digests into zero padded blocks
+    let mut block = vec![0u8; VERITY_BLOCK_SIZE as usize];
+    let mut tree = Vec::new();
+    if levels.is_empty() {
+        input.read_exact(&mut block)?;
+    } else {
+        let mut level = Vec::with_capacity((levels[0] * VERITY_BLOCK_SIZE) as usize);
+        for index in 0..data_blocks {
+            input.read_exact(&mut block)?;
+            level.extend_from_slice(&salted_hash(salt, &block));
+            if (index + 1) % HASHES_PER_BLOCK == 0 || index + 1 == data_blocks {
+                level.resize(level.len().div_ceil(VERITY_BLOCK_SIZE as usize) * VERITY_BLOCK_SIZE as usize, 0);
+            }
+        }
+        tree.push(level);
+    }
+    while tree.last().is_some_and(|level| level.len() as u64 > VERITY_BLOCK_SIZE) {
+        let mut next = Vec::new();
+        for chunk in tree[tree.len() - 1].chunks(VERITY_BLOCK_SIZE as usize * HASHES_PER_BLOCK as usize) {
+            for block in chunk.chunks(VERITY_BLOCK_SIZE as usize) {
+                next.extend_from_slice(&salted_hash(salt, block));
+            }
+            next.resize(next.len().div_ceil(VERITY_BLOCK_SIZE as usize) * VERITY_BLOCK_SIZE as usize, 0);
+        }
+        tree.push(next);
+    }
+    let root_hash = salted_hash(salt, tree.last().unwrap_or(&block));
+
+    // The tree is stored with the level nearest the root first
+    let mut output = OpenOptions::new().write(true).open(image_path)?;
+    output.seek(SeekFrom::Start(layout.hash_offset))?;
+    output.write_all(&create_superblock(data_blocks, salt, uuid))?;
+    output.seek(SeekFrom::Start(layout.hash_offset + VERITY_BLOCK_SIZE))?;
+    for level in tree.iter().rev() {
+        output.write_all(level)?;
+    }
+    let root_hash = to_hex(&root_hash);
+    let salt = to_hex(salt);
+    // dm-init sets the device up before the root filesystem is mounted, as dm-0
+    let kernel_cmdline = format!(
+        "dm-mod.create=\"root,,,ro,0 {} verity 1 {} {} {VERITY_BLOCK_SIZE} {VERITY_BLOCK_SIZE} {data_blocks} 1 sha256 {root_hash} {salt}\" root=/dev/dm-0",
+        data_blocks * VERITY_BLOCK_SIZE / 512, layout.data_device, layout.hash_device
+    );
+    Ok(VerityResult {
+        root_hash,
+        salt,
+        hash_algorithm: "sha256".to_string(),
+        data_block_size: VERITY_BLOCK_SIZE,
+        hash_block_size: VERITY_BLOCK_SIZE,
+        data_blocks,
+        // The superblock takes up the first hash block
+        hash_start_block: 1,
+        data_device: layout.data_device.clone(),
+        hash_device: layout.hash_device.clone(),
+        kernel_cmdline,
+    })
+}
+
+/// Format 1 hashes are of the salt followed by the block
+fn salted_hash(salt: &[u8], block: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(block);
+    hasher.finalize().into()
+}
+
+fn create_superblock(data_blocks: u64, salt: &[u8; 32], uuid: &[u8; 16]) -> [u8; SUPERBLOCK_SIZE] {
+    let mut superblock = [0u8; SUPERBLOCK_SIZE];
+    superblock[0..8].copy_from_slice(b"verity\0\0");
+    superblock[8..12].copy_from_slice(&1u32.to_le_bytes()); // superblock version
+    superblock[12..16].copy_from_slice(&1u32.to_le_bytes()); // hash format
+    superblock[16..32].copy_from_slice(uuid);
+    superblock[32..38].copy_from_slice(b"sha256");
+    superblock[64..68].copy_from_slice(&(VERITY_BLOCK_SIZE as u32).to_le_bytes());
+    superblock[68..72].copy_from_slice(&(VERITY_BLOCK_SIZE as u32).to_le_bytes());
+    superblock[72..80].copy_from_slice(&data_blocks.to_le_bytes());
+    superblock[80..82].copy_from_slice(&(salt.len() as u16).to_le_bytes());
+    superblock[88..88 + salt.len()].copy_from_slice(salt);
+    superblock
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    const SALT: [u8; 32] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31];
+    const UUID: [u8; 16] = [0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 0xf0, 0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 0xf0];
+
+    /// Writes the tree of data_blocks blocks, block i filled with the byte i * 7 + 1, into an
+    /// image that has the data first and the hash area after it. Returns the result and the hash area
+    fn hash_blocks(data_blocks: u64) -> (VerityResult, Vec<u8>) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("disk.img");
+        let data_size = data_blocks * VERITY_BLOCK_SIZE;
+        let mut data = (0..data_blocks).flat_map(|index| vec![(index * 7 + 1) as u8; VERITY_BLOCK_SIZE as usize]).collect::<Vec<u8>>();
+        data.resize((data_size + hash_tree_size(data_size)) as usize, 0);
+        fs::write(&path, &data).unwrap();
+        let layout = VerityLayout {
+            data_offset: 0,
+            data_size,
+            hash_offset: data_size,
+            data_device: "PARTUUID=data".to_string(),
+            hash_device: "PARTUUID=hash".to_string(),
+        };
+        let result = write_hash_tree(&path, &layout, &SALT, &UUID).unwrap();
+        let image = fs::read(&path).unwrap();
+        (result, image[data_size as usize..].to_vec())
+    }
+
+    fn sha256_hex(data: &[u8]) -> String {
+        to_hex(&Sha256::digest(data))
+    }
+
+    // The expected root hashes and hash areas are what libcryptsetup, which `veritysetup format`
+    // runs, writes for the same data with `--salt 000102..1f --uuid 12345678-9abc-def0-1234-56789abcdef0`
+
+    #[test]
+    fn single_block_is_its_own_root() {
+        assert!(level_sizes(1).is_empty());
+        let (result, hash_area) = hash_blocks(1);
+        // Just the superblock, with no hash blocks after it
+        assert_eq!(hash_area.len() as u64, VERITY_BLOCK_SIZE);
+        let mut block = SALT.to_vec();
+        block.extend_from_slice(&[1u8; VERITY_BLOCK_SIZE as usize]);
+        assert_eq!(result.root_hash, sha256_hex(&block));
+        assert_eq!(result.root_hash, "345c7d5833a1abdf7aca1d2330bdb6fb35f7fbec4f4154159460c83979f297f6");
+        assert_eq!(sha256_hex(&hash_area), "d3b6ab6a32c0257f403ef3f25574f730a3ef2fb6560dacd8b844c44b147a654c");
+    }
+
+    #[test]
+    fn known_answer_matches_veritysetup() {
+        let (result, hash_area) = hash_blocks(3);
+        assert_eq!(result.root_hash, "cac8076b40f432837a8857d9bec46fa6fbb2a4bd05f74ab779dce584fa437a1e");
+        assert_eq!(sha256_hex(&hash_area), "09e4229ce94604dfc5911b1e8007d93a00ce3abc7cd7223df96fd275c700d738");
+        assert_eq!(result.data_blocks, 3);
+        assert_eq!(result.salt, to_hex(&SALT));
+        assert!(result.kernel_cmdline.contains(&format!("verity 1 PARTUUID=data PARTUUID=hash 4096 4096 3 1 sha256 {} {}", result.root_hash, result.salt)));
+
+        let superblock = &hash_area[0..SUPERBLOCK_SIZE];
+        assert_eq!(&superblock[0..8], b"verity\0\0");
+        assert_eq!(superblock[8..16], [1, 0, 0, 0, 1, 0, 0, 0]);
+        assert_eq!(superblock[16..32], UUID);
+        assert_eq!(&superblock[32..40], b"sha256\0\0");
+        assert_eq!(superblock[64..72], [0, 0x10, 0, 0, 0, 0x10, 0, 0]);
+        assert_eq!(u64::from_le_bytes(superblock[72..80].try_into().unwrap()), 3);
+        assert_eq!(u16::from_le_bytes(superblock[80..82].try_into().unwrap()), 32);
+        assert_eq!(superblock[88..120], SALT);
+        assert!(superblock[120..].iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn multi_level_tree_matches_veritysetup() {
+        // 200 blocks need two hash blocks over the data and a root block over those
+        assert_eq!(level_sizes(200), [2, 1]);
+        assert_eq!(hash_tree_size(200 * VERITY_BLOCK_SIZE), 4 * VERITY_BLOCK_SIZE);
+        let (result, hash_area) = hash_blocks(200);
+        assert_eq!(result.root_hash, "8269bbf301290ab28253e90bf3f5f0022b7a3aae501f10339bb46d5783c1fb0b");
+        assert_eq!(sha256_hex(&hash_area), "d9e07f9051acbc76ac2b46e7b847985276ad96fbe3840b9980cf20141f905ad5");
+        // The root block comes right after the superblock and holds the hashes of the two level blocks
+        let block = VERITY_BLOCK_SIZE as usize;
+        let root_block = &hash_area[block..2 * block];
+        assert_eq!(root_block[0..32], salted_hash(&SALT, &hash_area[2 * block..3 * block]));
+        assert_eq!(root_block[32..64], salted_hash(&SALT, &hash_area[3 * block..4 * block]));
+        assert!(root_block[64..].iter().all(|byte| *byte == 0));
+        assert_eq!(result.root_hash, to_hex(&salted_hash(&SALT, root_block)));
+    }
+
+    #[test]
+    fn level_sizes_follow_the_fan_out() {
+        assert_eq!(level_sizes(2), [1]);
+        assert_eq!(level_sizes(128), [1]);
+        assert_eq!(level_sizes(129), [2, 1]);
+        assert_eq!(level_sizes(128 * 128), [128, 1]);
+        assert_eq!(level_sizes(128 * 128 + 1), [129, 2, 1]);
+    }
+}
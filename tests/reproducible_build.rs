@@ -0,0 +1,61 @@
+//! Builds the same tree twice with `--reproducible` settings and compares the images byte for byte.
+//! Needs mkfs.ext4 and debugfs, the test is skipped when they aren't installed
+use std::{fs::{self, File}, os::unix::fs::{symlink, PermissionsExt}, path::Path, thread, time::Duration};
+
+use camino::Utf8PathBuf;
+use whaledrive::{cli_commands::{clamp_file_times, format_ext4_file_reproducible}, paths::BASE_PATH, utils::Reproducibility};
+
+const NAMES: [&str; 4] = ["alpha", "beta", "gamma", "delta"];
+
+/// Creates the same tree in the given order, so the directory entries and inodes are created differently
+fn write_tree(root: &Path, names: &[&str]) {
+    fs::create_dir_all(root.join("etc/conf.d")).unwrap();
+    fs::create_dir(root.join("tmp")).unwrap();
+    fs::set_permissions(root.join("tmp"), fs::Permissions::from_mode(0o1777)).unwrap();
+    for name in names {
+        fs::write(root.join("etc/conf.d").join(name), name.repeat(100)).unwrap();
+        fs::write(root.join(name), name).unwrap();
+    }
+    symlink("etc/conf.d/alpha", root.join("link")).unwrap();
+    fs::hard_link(root.join("beta"), root.join("beta-again")).unwrap();
+}
+
+fn build(source: &Path, image: &Path, reproducibility: &Reproducibility) -> Vec<u8> {
+    clamp_file_times(source, reproducibility.epoch).unwrap();
+    File::create(image).unwrap().set_len(16 * 1024 * 1024).unwrap();
+    format_ext4_file_reproducible(image.to_str().unwrap(), source, reproducibility, Some("root")).unwrap();
+    fs::read(image).unwrap()
+}
+
+#[test]
+fn reproducible_ext4_images_are_identical() {
+    if which::which("mkfs.ext4").is_err() || which::which("debugfs").is_err() {
+        eprintln!("skipping, mkfs.ext4 and debugfs are needed");
+        return;
+    }
+    let dir = tempfile::tempdir().unwrap();
+    // Temporary files go in the base path
+    *BASE_PATH.write().unwrap() = Utf8PathBuf::from_path_buf(dir.path().join("base")).unwrap();
+    let first = dir.path().join("first");
+    let second = dir.path().join("second");
+    write_tree(&first, &NAMES);
+    // The change times of the two trees can't be set, so they're made to differ
+    thread::sleep(Duration::from_millis(1100));
+    let mut reversed = NAMES;
+    reversed.reverse();
+    write_tree(&second, &reversed);
+
+    // A later epoch first, as clamping only moves times back
+    for epoch in [1_700_000_000, 0] {
+        let reproducibility = Reproducibility {
+            epoch,
+            fs_uuid: "12345678-9abc-def0-1234-56789abcdef0".to_string(),
+            hash_seed: "0fedcba9-8765-4321-0fed-cba987654321".to_string(),
+            disk_id: "0x12345678".to_string(),
+        };
+        let first_image = build(&first, &dir.path().join("first.img"), &reproducibility);
+        thread::sleep(Duration::from_millis(1100));
+        let second_image = build(&second, &dir.path().join("second.img"), &reproducibility);
+        assert!(first_image == second_image, "the images built with epoch {epoch} differ");
+    }
+}